@@ -1,4 +1,5 @@
 mod helpers;
+pub mod overlay;
 pub mod wireguard;
 
 pub use helpers::ObjectReference;
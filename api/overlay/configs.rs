@@ -0,0 +1,76 @@
+use super::{
+    DEFAULT_GENEVE_PORT, DEFAULT_VXLAN_PORT,
+    peers::{OverlayPeer, OverlayPeerConfig},
+};
+use crate::wireguard::WireguardAddress;
+
+use std::net::Ipv4Addr;
+
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// An unencrypted tunnel for Pods which only need isolation, addressed from the
+// same pools as WireguardConfigs.
+#[derive(Clone, CustomResource, Debug, Deserialize, JsonSchema, Serialize)]
+#[kube(
+    group = "podtunnel.com",
+    version = "v1alpha1",
+    kind = "OverlayConfig",
+    namespaced
+)]
+#[serde(rename_all = "camelCase")]
+#[kube(status = "OverlayConfigStatus")]
+pub struct OverlayConfigSpec {
+    pub interface: OverlayInterface,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub peers: Vec<OverlayPeer>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
+pub struct OverlayInterface {
+    #[serde(default)]
+    pub encapsulation: Encapsulation,
+
+    pub vni: u32,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+
+    pub address: WireguardAddress,
+}
+
+impl OverlayInterface {
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(match self.encapsulation {
+            Encapsulation::Vxlan => DEFAULT_VXLAN_PORT,
+            Encapsulation::Geneve => DEFAULT_GENEVE_PORT,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq, Eq, Serialize)]
+pub enum Encapsulation {
+    #[default]
+    Vxlan,
+    Geneve,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema, Serialize)]
+pub struct OverlayConfigStatus {
+    #[serde(default)]
+    pub interface_ready: bool,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub peers: Vec<OverlayPeerConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pod_address: Option<Ipv4Addr>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tunnel_address: Option<Ipv4Addr>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tunnel_address_prefix: Option<u8>,
+}
@@ -0,0 +1,10 @@
+mod configs;
+mod peers;
+
+pub use configs::{
+    Encapsulation, OverlayConfig, OverlayConfigSpec, OverlayConfigStatus, OverlayInterface,
+};
+pub use peers::{OverlayPeer, OverlayPeerConfig};
+
+pub const DEFAULT_VXLAN_PORT: u16 = 4789;
+pub const DEFAULT_GENEVE_PORT: u16 = 6081;
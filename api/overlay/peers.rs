@@ -0,0 +1,20 @@
+use crate::helpers::ObjectReference;
+
+use std::net::Ipv4Addr;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
+pub enum OverlayPeer {
+    Config(OverlayPeerConfig),
+    Pod(ObjectReference),
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+pub struct OverlayPeerConfig {
+    pub endpoint_address: Ipv4Addr,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tunnel_address: Option<Ipv4Addr>,
+}
@@ -39,15 +39,19 @@ pub struct WireguardAddressPoolStatus {
 }
 
 impl WireguardAddressPool {
+    // Leases are keyed by the kind and reference of the config holding them,
+    // so a WireguardConfig and an OverlayConfig of the same name each get an
+    // address of their own.
     pub async fn assign_ipv4(
         &mut self,
+        kind: &str,
         object_ref: ObjectReference,
     ) -> anyhow::Result<(Ipv4Addr, u8)> {
         let (base, prefix) = self.spec.network.split()?;
 
         let status = self.status.get_or_insert_default();
 
-        let ref_key = object_ref.to_string();
+        let ref_key = allocation_key(kind, &object_ref);
 
         if let Some(&v) = status.allocation.get(&ref_key) {
             return Ok((v, prefix));
@@ -62,19 +66,28 @@ impl WireguardAddressPool {
 
         let address = addresses
             .iter()
-            .skip_while(|&x| {
-                x.is_broadcast()
+            .find(|&x| {
+                !(x.is_broadcast()
                     || x.is_unspecified()
                     || status
                         .allocation
                         .values()
-                        .any(|allocated_ip| allocated_ip == x)
+                        .any(|allocated_ip| allocated_ip == x))
             })
-            .next()
             .context("pool exhausted")?;
 
-        status.allocation.insert(ref_key, address.clone());
+        status.allocation.insert(ref_key, *address);
 
         Ok((*address, prefix))
     }
 }
+
+// WireguardConfigs leased by their reference alone before other kinds drew
+// from pools, their keys are kept so existing leases stay put.
+fn allocation_key(kind: &str, object_ref: &ObjectReference) -> String {
+    if kind == "WireguardConfig" {
+        object_ref.to_string()
+    } else {
+        format!("{}/{}", kind, object_ref)
+    }
+}
@@ -42,6 +42,29 @@ pub struct WireguardInterface {
 
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub private_key: Option<ObjectReference>,
+
+    #[serde(default)]
+    pub routing_mode: WireguardRoutingMode,
+
+    // derived from the MTU of the Pod's interface when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u32>,
+
+    // advertise an MSS which fits the tunnel on the routes through it
+    #[serde(default)]
+    pub mss_clamping: bool,
+}
+
+// Which traffic of the Pod is routed through the tunnel.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+pub enum WireguardRoutingMode {
+    // traffic to the endpoints of the peers, over a default route
+    #[default]
+    Full,
+    // traffic to the allowed ips of the peers
+    Split,
+    // none, routing is left to the application
+    None,
 }
 
 #[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
@@ -72,4 +95,18 @@ pub struct WireguardConfigStatus {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub public_key: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<WireguardMode>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u32>,
+}
+
+// Where the tunnel of the running Pod is implemented, the kernel module or
+// the userspace fallback for nodes without it.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+pub enum WireguardMode {
+    Kernel,
+    Userspace,
 }
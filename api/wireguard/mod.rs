@@ -7,11 +7,12 @@ pub use addresses::{
 };
 pub use configs::{
     WireguardAddress, WireguardConfig, WireguardConfigSpec, WireguardConfigStatus,
-    WireguardInterface,
+    WireguardInterface, WireguardMode, WireguardRoutingMode,
 };
-pub use peers::{WireguardPeer, WireguardPeerConfig};
+pub use peers::{WireguardPeer, WireguardPeerConfig, WireguardPodPeer};
 
 pub const DEFAULT_WIREGUARD_LISTEN_PORT: u16 = 51820;
+pub const DEFAULT_PERSISTENT_KEEPALIVE: i32 = 25;
 
 fn default_wireguard_listen_port() -> Option<u16> {
     Some(DEFAULT_WIREGUARD_LISTEN_PORT)
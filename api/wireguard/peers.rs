@@ -8,7 +8,19 @@ use serde::{Deserialize, Serialize};
 #[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 pub enum WireguardPeer {
     Config(WireguardPeerConfig),
-    Pod(ObjectReference),
+    Pod(WireguardPodPeer),
+}
+
+// Another Pod with a WireguardConfig, whose peer configuration is filled in
+// by the operator once it is ready.
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
+pub struct WireguardPodPeer {
+    #[serde(flatten)]
+    pub pod: ObjectReference,
+
+    // seconds between keepalives, 0 turns them off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persistent_keepalive: Option<i32>,
 }
 
 #[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
@@ -32,11 +44,18 @@ pub struct WireguardPeerConfig {
 
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub persistent_keepalive: Option<i32>,
+
+    // a Secret holding the key as preshared_key, so that the key itself never
+    // shows up in a WireguardConfig
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preshared_key: Option<ObjectReference>,
 }
 
 impl WireguardPeerConfig {
     pub fn endpoint(&self) -> String {
-        let endpoint_port = self.endpoint_port.unwrap_or_default();
+        let endpoint_port = self
+            .endpoint_port
+            .unwrap_or(crate::wireguard::DEFAULT_WIREGUARD_LISTEN_PORT);
         format!("{}:{}", &self.endpoint_address, endpoint_port)
     }
 }
@@ -0,0 +1,170 @@
+use crate::resync;
+use drivers::{settings::DEFAULT_KUBECONFIG, wireguard::userspace};
+
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde_json::{Value, json};
+use tracing::{error, info};
+
+// The projected service account token of the agent's Pod, see
+// config/agent/daemonset.yaml.
+const TOKEN_DIRECTORY: &str = "/var/run/secrets/podtunnel";
+// the kubelet refreshes the token well before it expires
+const CREDENTIALS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+// The node agent, run by the podtunnel-agent DaemonSet, hosts the userspace
+// wireguard devices of the Pods on its node. Devices are restored after the
+// agent restarts, so the plugin never has to start processes of its own.
+//
+// It also hands the plugin its credentials: the agent's bound token, which
+// the kubelet keeps fresh, is copied next to the plugin's kubeconfig. With
+// those it keeps the tunnels on its node up to date, see resync.
+pub fn run() -> anyhow::Result<()> {
+    if Path::new(TOKEN_DIRECTORY).exists() {
+        thread::Builder::new()
+            .name("credentials".to_string())
+            .spawn(refresh_credentials)?;
+    } else {
+        info!(
+            "no token at {}, not installing credentials",
+            TOKEN_DIRECTORY
+        );
+    }
+    thread::Builder::new()
+        .name("resync".to_string())
+        .spawn(resync::run)?;
+
+    let listener = userspace::listen()?;
+    let devices = userspace::devices();
+    userspace::restore(&devices);
+    info!("podtunnel agent listening on {}", userspace::AGENT_SOCKET);
+    userspace::serve(listener, devices)
+}
+
+fn refresh_credentials() {
+    let mut installed = None;
+    loop {
+        if let Err(err) = install_credentials(&mut installed) {
+            error!("failed to install the plugin's credentials: {:#}", err);
+        }
+        thread::sleep(CREDENTIALS_REFRESH_INTERVAL);
+    }
+}
+
+fn install_credentials(installed: &mut Option<Vec<u8>>) -> anyhow::Result<()> {
+    let token_directory = Path::new(TOKEN_DIRECTORY);
+    let token = fs::read(token_directory.join("token")).context("failed to read the token")?;
+    if installed.as_ref() == Some(&token) {
+        return Ok(());
+    }
+    let ca = fs::read(token_directory.join("ca.crt")).context("failed to read the CA")?;
+    let host = env::var("KUBERNETES_SERVICE_HOST").context("KUBERNETES_SERVICE_HOST is unset")?;
+    let port = env::var("KUBERNETES_SERVICE_PORT").context("KUBERNETES_SERVICE_PORT is unset")?;
+    let server = if host.contains(':') {
+        format!("https://[{}]:{}", host, port)
+    } else {
+        format!("https://{}:{}", host, port)
+    };
+
+    let kubeconfig_path = Path::new(DEFAULT_KUBECONFIG);
+    let directory = kubeconfig_path
+        .parent()
+        .context("the kubeconfig has no directory")?;
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(directory)?;
+    write_private(&directory.join("ca.crt"), &ca)?;
+    write_private(&directory.join("token"), &token)?;
+    write_private(
+        kubeconfig_path,
+        kubeconfig(&server, directory).to_string().as_bytes(),
+    )?;
+
+    info!("installed the plugin's credentials");
+    *installed = Some(token);
+    Ok(())
+}
+
+// The kubeconfig refers to the token and CA by path, so the plugin always
+// reads the latest token.
+fn kubeconfig(server: &str, directory: &Path) -> Value {
+    json!({
+        "apiVersion": "v1",
+        "kind": "Config",
+        "clusters": [{
+            "name": "podtunnel",
+            "cluster": {
+                "server": server,
+                "certificate-authority": directory.join("ca.crt"),
+            },
+        }],
+        "users": [{
+            "name": "podtunnel-cni",
+            "user": {
+                "tokenFile": directory.join("token"),
+            },
+        }],
+        "contexts": [{
+            "name": "podtunnel-cni",
+            "context": {
+                "cluster": "podtunnel",
+                "user": "podtunnel-cni",
+            },
+        }],
+        "current-context": "podtunnel-cni",
+    })
+}
+
+// written aside and moved in place, so the plugin never reads half of it
+fn write_private(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let staging_path = path.with_extension("new");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&staging_path)
+        .with_context(|| format!("failed to write {}", staging_path.display()))?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&staging_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use kube::config::Kubeconfig;
+
+    #[test]
+    fn writes_a_kubeconfig_reading_the_token_file() {
+        let directory = Path::new("/etc/cni/net.d/podtunnel.d");
+        let kubeconfig = kubeconfig("https://10.96.0.1:443", directory).to_string();
+
+        let kubeconfig = Kubeconfig::from_yaml(&kubeconfig).unwrap();
+
+        assert_eq!(kubeconfig.current_context.as_deref(), Some("podtunnel-cni"));
+        let cluster = kubeconfig.clusters[0].cluster.as_ref().unwrap();
+        assert_eq!(cluster.server.as_deref(), Some("https://10.96.0.1:443"));
+        assert_eq!(
+            cluster.certificate_authority.as_deref(),
+            Some("/etc/cni/net.d/podtunnel.d/ca.crt")
+        );
+        let user = kubeconfig.auth_infos[0].auth_info.as_ref().unwrap();
+        assert_eq!(
+            user.token_file.as_deref(),
+            Some("/etc/cni/net.d/podtunnel.d/token")
+        );
+    }
+}
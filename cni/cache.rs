@@ -0,0 +1,250 @@
+use crate::{
+    errors::{CniContext, ErrorCode, Result},
+    specification::PreviousResult,
+};
+use drivers::{driver::ConfiguredInterface, settings::Settings};
+
+use std::{
+    fs,
+    io::ErrorKind,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+pub const DEFAULT_CACHE_DIR: &str = "/var/lib/cni/podtunnel";
+
+// appended to the whole key, interface names may contain dots themselves
+const TEMPORARY_SUFFIX: &str = ".tmp";
+
+// An attachment records everything ADD configured for a container so that
+// DEL, CHECK and GC can act on it even when the WireguardConfig is gone.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Attachment {
+    #[serde(rename = "containerID")]
+    pub container_id: String,
+    pub ifname: String,
+    pub pod_name: String,
+    pub pod_namespace: String,
+    pub pod_address: Ipv4Addr,
+    pub netns: String,
+    pub settings: Settings,
+    pub interface: ConfiguredInterface,
+    pub result: PreviousResult,
+}
+
+impl Attachment {
+    pub fn key(&self) -> String {
+        attachment_key(&self.container_id, &self.ifname)
+    }
+}
+
+pub struct AttachmentCache {
+    dir: PathBuf,
+}
+
+impl AttachmentCache {
+    pub fn new(cache_dir: &Path, network_name: &str) -> Self {
+        AttachmentCache {
+            dir: cache_dir.join(network_name),
+        }
+    }
+
+    pub fn get(&self, container_id: &str, ifname: &str) -> Result<Option<Attachment>> {
+        self.read(&self.dir.join(attachment_key(container_id, ifname)))
+    }
+
+    pub fn list(&self) -> Result<Vec<Attachment>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => {
+                return Err(err).cni_context(ErrorCode::IoFailure, "failed to list cache dir");
+            }
+        };
+
+        let mut attachments = vec![];
+        for entry in entries {
+            let path = entry
+                .cni_context(ErrorCode::IoFailure, "failed to list cache dir")?
+                .path();
+            if path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().ends_with(TEMPORARY_SUFFIX))
+            {
+                continue;
+            }
+            if let Some(attachment) = self.read(&path)? {
+                attachments.push(attachment);
+            }
+        }
+
+        Ok(attachments)
+    }
+
+    pub fn put(&self, attachment: &Attachment) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .cni_context(ErrorCode::IoFailure, "failed to create cache dir")?;
+
+        let contents = serde_json::to_vec(attachment)
+            .cni_context(ErrorCode::IoFailure, "failed to serialize cache entry")?;
+
+        // write to a temporary file first and rename it into place so that a
+        // reader never observes a partially written entry.
+        let path = self.dir.join(attachment.key());
+        let temporary_path = self.dir.join(attachment.key() + TEMPORARY_SUFFIX);
+        fs::write(&temporary_path, contents)
+            .cni_context(ErrorCode::IoFailure, "failed to write cache entry")?;
+        fs::rename(&temporary_path, &path)
+            .cni_context(ErrorCode::IoFailure, "failed to write cache entry")?;
+
+        Ok(())
+    }
+
+    pub fn remove(&self, container_id: &str, ifname: &str) -> Result<()> {
+        match fs::remove_file(self.dir.join(attachment_key(container_id, ifname))) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err).cni_context(ErrorCode::IoFailure, "failed to remove cache entry"),
+        }
+    }
+
+    // An entry which can't be decoded, e.g. after a crash mid-write on a
+    // filesystem without atomic renames, is of no use to anyone and would
+    // otherwise fail every DEL and GC of the attachment, so it's dropped.
+    fn read(&self, path: &Path) -> Result<Option<Attachment>> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).cni_context(ErrorCode::IoFailure, "failed to read cache entry");
+            }
+        };
+
+        match serde_json::from_slice(&contents) {
+            Ok(attachment) => Ok(Some(attachment)),
+            Err(err) => {
+                warn!(
+                    "discarding cache entry {} which can't be decoded: {}",
+                    path.display(),
+                    err
+                );
+                match fs::remove_file(path) {
+                    Err(err) if err.kind() != ErrorKind::NotFound => {
+                        Err(err).cni_context(ErrorCode::IoFailure, "failed to remove cache entry")
+                    }
+                    _ => Ok(None),
+                }
+            }
+        }
+    }
+}
+
+fn attachment_key(container_id: &str, ifname: &str) -> String {
+    format!("{}-{}", container_id, ifname)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use drivers::{driver::Tunnel, settings::Settings, wireguard::workflows::WireguardTunnel};
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    pub(crate) fn attachment(container_id: &str, ifname: &str) -> Attachment {
+        Attachment {
+            container_id: container_id.to_string(),
+            ifname: ifname.to_string(),
+            pod_name: "pod".to_string(),
+            pod_namespace: "default".to_string(),
+            pod_address: Ipv4Addr::new(192, 168, 1, 1),
+            netns: "/var/run/netns/test".to_string(),
+            settings: Settings::default(),
+            interface: ConfiguredInterface {
+                name: "wg0".to_string(),
+                address: Ipv4Addr::new(10, 0, 0, 1),
+                prefix: 24,
+                mtu: Some(1420),
+                mac: None,
+                routes: vec![],
+                dns: vec![],
+                tunnel: Tunnel::Wireguard(WireguardTunnel {
+                    listen_port: 51820,
+                    public_key: None,
+                    peers: vec![],
+                    routing_mode: None,
+                    mss_clamping: false,
+                }),
+            },
+            result: serde_json::from_value(json!({"cniVersion": "1.1.0"})).unwrap(),
+        }
+    }
+
+    pub(crate) fn cache() -> (TempDir, AttachmentCache) {
+        let dir = TempDir::new().unwrap();
+        let cache = AttachmentCache::new(dir.path(), "podtunnel");
+        (dir, cache)
+    }
+
+    fn keys(cache: &AttachmentCache) -> Vec<String> {
+        let mut keys: Vec<String> = cache.list().unwrap().iter().map(Attachment::key).collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn stores_and_removes_attachments() {
+        let (_dir, cache) = cache();
+        assert!(cache.get("abc", "eth0").unwrap().is_none());
+        assert!(cache.list().unwrap().is_empty());
+
+        cache.put(&attachment("abc", "eth0")).unwrap();
+        cache.put(&attachment("abc", "eth0.100")).unwrap();
+        cache.put(&attachment("def", "eth0")).unwrap();
+
+        let cached = cache.get("abc", "eth0.100").unwrap().unwrap();
+        assert_eq!(cached.key(), "abc-eth0.100");
+        assert_eq!(keys(&cache), ["abc-eth0", "abc-eth0.100", "def-eth0"]);
+
+        cache.remove("abc", "eth0").unwrap();
+        cache.remove("abc", "eth0").unwrap();
+        assert!(cache.get("abc", "eth0").unwrap().is_none());
+        assert_eq!(keys(&cache), ["abc-eth0.100", "def-eth0"]);
+    }
+
+    #[test]
+    fn keeps_temporary_files_next_to_dotted_interface_names() {
+        let (_dir, cache) = cache();
+        cache.put(&attachment("abc", "eth0.100")).unwrap();
+        cache.put(&attachment("abc", "eth0.200")).unwrap();
+
+        // an interrupted write leaves its temporary file behind
+        fs::write(cache.dir.join("abc-eth0.300.tmp"), b"{").unwrap();
+
+        let mut files: Vec<String> = fs::read_dir(&cache.dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["abc-eth0.100", "abc-eth0.200", "abc-eth0.300.tmp"]);
+        assert_eq!(keys(&cache), ["abc-eth0.100", "abc-eth0.200"]);
+    }
+
+    #[test]
+    fn discards_entries_which_cannot_be_decoded() {
+        let (_dir, cache) = cache();
+        cache.put(&attachment("abc", "eth0")).unwrap();
+        cache.put(&attachment("def", "eth0")).unwrap();
+        fs::write(cache.dir.join("abc-eth0"), b"{\"containerID\":").unwrap();
+        fs::write(cache.dir.join("ghi-eth0"), b"not json").unwrap();
+
+        assert!(cache.get("abc", "eth0").unwrap().is_none());
+        assert!(!cache.dir.join("abc-eth0").exists());
+
+        assert_eq!(keys(&cache), ["def-eth0"]);
+        assert!(!cache.dir.join("ghi-eth0").exists());
+    }
+}
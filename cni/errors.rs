@@ -0,0 +1,235 @@
+use crate::specification::CniVersion;
+use drivers::kube::{MissingCredentials, NotReady};
+
+use std::fmt::{self, Display, Formatter};
+
+use serde::{Serialize, Serializer};
+
+pub type Result<T, E = CniError> = std::result::Result<T, E>;
+
+// Codes 0-99 are reserved by the CNI specification, codes from 100 upwards
+// are specific to this plugin.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorCode {
+    IncompatibleCniVersion = 1,
+    UnsupportedField = 2,
+    ContainerUnknown = 3,
+    InvalidEnvironmentVariables = 4,
+    IoFailure = 5,
+    DecodingFailure = 6,
+    InvalidNetworkConfig = 7,
+    TryAgainLater = 11,
+    PluginNotAvailable = 50,
+    TunnelDrift = 100,
+    TunnelSetupFailure = 101,
+    TunnelTeardownFailure = 102,
+    KubernetesApiFailure = 103,
+    MissingCredentials = 104,
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(*self as u32)
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CniError {
+    #[serde(rename = "cniVersion")]
+    pub cni_version: String,
+
+    pub code: ErrorCode,
+
+    pub msg: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+}
+
+impl CniError {
+    pub fn new(code: ErrorCode, msg: &str, details: Option<String>) -> Self {
+        CniError {
+            cni_version: CniVersion::latest().to_string(),
+            code,
+            msg: msg.to_string(),
+            details,
+        }
+    }
+
+    // errors are reported in the version of the request whenever that
+    // version is one we understand.
+    pub fn for_version(mut self, cni_version: &str) -> Self {
+        if cni_version
+            .parse::<CniVersion>()
+            .is_ok_and(|version| version.is_supported())
+        {
+            self.cni_version = cni_version.to_string();
+        }
+        self
+    }
+
+    pub fn print(&self) {
+        match serde_json::to_string_pretty(self) {
+            Ok(cni_error) => println!("{}", cni_error),
+            Err(_) => println!("{{\"code\":{},\"msg\":{:?}}}", self.code as u32, self.msg),
+        }
+    }
+}
+
+impl Display for CniError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.details {
+            Some(details) => write!(f, "{}: {}", self.msg, details),
+            None => write!(f, "{}", self.msg),
+        }
+    }
+}
+
+impl std::error::Error for CniError {}
+
+pub trait CniContext<T> {
+    fn cni_context(self, code: ErrorCode, msg: &str) -> Result<T>;
+}
+
+impl<T, E> CniContext<T> for std::result::Result<T, E>
+where
+    E: Into<anyhow::Error>,
+{
+    fn cni_context(self, code: ErrorCode, msg: &str) -> Result<T> {
+        self.map_err(|err| {
+            let err: anyhow::Error = err.into();
+            if let Some(cni_error) = err.downcast_ref::<CniError>() {
+                return cni_error.clone();
+            }
+            CniError::new(
+                classify(&err).unwrap_or(code),
+                msg,
+                Some(format!("{:#}", err)),
+            )
+        })
+    }
+}
+
+impl<T> CniContext<T> for Option<T> {
+    fn cni_context(self, code: ErrorCode, msg: &str) -> Result<T> {
+        self.ok_or_else(|| CniError::new(code, msg, None))
+    }
+}
+
+// Failures talking to the Kubernetes API are usually transient, so the
+// runtime is asked to retry them rather than tear down the sandbox.
+fn classify(err: &anyhow::Error) -> Option<ErrorCode> {
+    if err.chain().any(|cause| cause.is::<NotReady>()) {
+        return Some(ErrorCode::TryAgainLater);
+    }
+
+    if err.chain().any(|cause| cause.is::<MissingCredentials>()) {
+        return Some(ErrorCode::MissingCredentials);
+    }
+
+    err.chain()
+        .find_map(|cause| cause.downcast_ref::<kube::Error>())
+        .map(|kube_error| match kube_error {
+            kube::Error::Api(api_error) if api_error.code == 429 || api_error.code >= 500 => {
+                ErrorCode::TryAgainLater
+            }
+            kube::Error::Api(_) => ErrorCode::KubernetesApiFailure,
+            _ => ErrorCode::TryAgainLater,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{io, path::PathBuf, time::Duration};
+
+    use anyhow::anyhow;
+    use kube::core::ErrorResponse;
+
+    fn api_error(code: u16) -> anyhow::Error {
+        kube::Error::Api(ErrorResponse {
+            status: "Failure".to_string(),
+            message: "failed".to_string(),
+            reason: "Failed".to_string(),
+            code,
+        })
+        .into()
+    }
+
+    #[test]
+    fn classifies_errors() {
+        let not_ready = || NotReady {
+            kind: "WireguardConfig",
+            name: "pod-a".to_string(),
+            timeout: Duration::from_secs(60),
+        };
+
+        for (err, code) in [
+            (
+                anyhow::Error::from(not_ready()),
+                Some(ErrorCode::TryAgainLater),
+            ),
+            (
+                anyhow::Error::from(not_ready()).context("failed to wait"),
+                Some(ErrorCode::TryAgainLater),
+            ),
+            (
+                MissingCredentials {
+                    kubeconfig: PathBuf::from("/etc/cni/net.d/podtunnel.d/podtunnel.kubeconfig"),
+                }
+                .into(),
+                Some(ErrorCode::MissingCredentials),
+            ),
+            (api_error(429), Some(ErrorCode::TryAgainLater)),
+            (api_error(500), Some(ErrorCode::TryAgainLater)),
+            (api_error(503), Some(ErrorCode::TryAgainLater)),
+            (api_error(403), Some(ErrorCode::KubernetesApiFailure)),
+            (api_error(404), Some(ErrorCode::KubernetesApiFailure)),
+            (
+                api_error(409).context("failed to update the WireguardConfig"),
+                Some(ErrorCode::KubernetesApiFailure),
+            ),
+            (
+                kube::Error::Service("connection refused".into()).into(),
+                Some(ErrorCode::TryAgainLater),
+            ),
+            (
+                kube::Error::ReadEvents(io::Error::from(io::ErrorKind::ConnectionReset)).into(),
+                Some(ErrorCode::TryAgainLater),
+            ),
+            (anyhow!("netlink failed"), None),
+        ] {
+            assert_eq!(classify(&err), code, "{:#}", err);
+        }
+    }
+
+    #[test]
+    fn keeps_the_code_of_cni_errors() {
+        let err: anyhow::Error =
+            CniError::new(ErrorCode::InvalidNetworkConfig, "bad config", None).into();
+
+        let cni_error = Err::<(), _>(err)
+            .cni_context(ErrorCode::TunnelSetupFailure, "failed")
+            .unwrap_err();
+
+        assert_eq!(cni_error.code, ErrorCode::InvalidNetworkConfig);
+        assert_eq!(cni_error.msg, "bad config");
+    }
+
+    #[test]
+    fn falls_back_to_the_given_code() {
+        let cni_error = Err::<(), _>(anyhow!("netlink failed"))
+            .cni_context(ErrorCode::TunnelSetupFailure, "failed to set up the tunnel")
+            .unwrap_err();
+
+        assert_eq!(cni_error.code, ErrorCode::TunnelSetupFailure);
+        assert_eq!(cni_error.details.as_deref(), Some("netlink failed"));
+
+        let cni_error = Err::<(), _>(api_error(500))
+            .cni_context(ErrorCode::TunnelSetupFailure, "failed to set up the tunnel")
+            .unwrap_err();
+
+        assert_eq!(cni_error.code, ErrorCode::TryAgainLater);
+    }
+}
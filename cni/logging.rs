@@ -0,0 +1,407 @@
+use std::{
+    env,
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{Map, Value};
+use tracing::{
+    Event, Level, Subscriber,
+    field::{Field, Visit},
+};
+use tracing_subscriber::{
+    Layer, filter::LevelFilter, layer::Context, prelude::*, util::SubscriberInitExt,
+};
+
+pub const DEFAULT_LOG_FILE: &str = "/var/log/podtunnel/cni.log";
+pub const DEFAULT_LOG_LEVEL: Level = Level::INFO;
+
+// the log is rotated to <path>.1 once it reaches this size, so at most twice
+// this is kept on a node
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct LogSettings {
+    pub path: PathBuf,
+    pub level: Level,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            path: PathBuf::from(DEFAULT_LOG_FILE),
+            level: DEFAULT_LOG_LEVEL,
+        }
+    }
+}
+
+// Installs the logger for the rest of the process. Only the first call has
+// an effect, so errors before the network config is read can still be
+// logged with the defaults.
+pub fn init(settings: &LogSettings) {
+    init_with(
+        Box::new(LogFile::new(settings.path.clone())),
+        settings.level,
+    );
+}
+
+// The node agent logs to stderr, which its container runtime collects.
+pub fn init_stderr(level: Level) {
+    init_with(Box::new(io::stderr()), level);
+}
+
+fn init_with(sink: Box<dyn Write + Send>, level: Level) {
+    let layer = JsonLines {
+        context: attachment_context(),
+        sink: Mutex::new(sink),
+    };
+    let _ = tracing_subscriber::registry()
+        .with(layer.with_filter(LevelFilter::from_level(level)))
+        .try_init();
+}
+
+// Every invocation of the plugin acts on one attachment, which the runtime
+// passes in the environment.
+fn attachment_context() -> Map<String, Value> {
+    let mut context = Map::new();
+    let cni_args = env::var("CNI_ARGS").unwrap_or_default();
+    let variables = [
+        ("container_id", env::var("CNI_CONTAINERID").ok()),
+        ("command", env::var("CNI_COMMAND").ok()),
+        ("pod_name", cni_arg(&cni_args, "K8S_POD_NAME")),
+        ("pod_namespace", cni_arg(&cni_args, "K8S_POD_NAMESPACE")),
+    ];
+    for (key, value) in variables {
+        if let Some(value) = value.filter(|value| !value.is_empty()) {
+            context.insert(key.to_string(), Value::String(value));
+        }
+    }
+    context
+}
+
+fn cni_arg(cni_args: &str, key: &str) -> Option<String> {
+    cni_args
+        .split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| value.to_string())
+}
+
+// Writes every event as one JSON object per line.
+struct JsonLines {
+    context: Map<String, Value>,
+    sink: Mutex<Box<dyn Write + Send>>,
+}
+
+impl<S: Subscriber> Layer<S> for JsonLines {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        let mut line = Map::new();
+        line.insert("timestamp".to_string(), Value::from(timestamp));
+        line.insert(
+            "level".to_string(),
+            Value::from(metadata.level().as_str().to_lowercase()),
+        );
+        line.insert("target".to_string(), Value::from(metadata.target()));
+        line.extend(self.context.clone());
+        event.record(&mut JsonFields(&mut line));
+
+        let mut line = Value::Object(line).to_string();
+        line.push('\n');
+        // a poisoned lock only means another thread panicked mid-write
+        let mut sink = self.sink.lock().unwrap_or_else(|err| err.into_inner());
+        let _ = sink.write_all(line.as_bytes());
+    }
+}
+
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonFields<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.insert(
+            field.name().to_string(),
+            Value::from(format!("{:?}", value)),
+        );
+    }
+}
+
+// The log file is opened on first use and reopened after rotating. Logging
+// never fails the plugin: once the file can't be written the rest of the
+// lines go to stderr, which the runtime collects, instead.
+struct LogFile {
+    path: PathBuf,
+    max_size: u64,
+    file: Option<File>,
+    inode: u64,
+    size: u64,
+    failed: bool,
+    fallback: Box<dyn Write + Send>,
+}
+
+impl LogFile {
+    fn new(path: PathBuf) -> Self {
+        LogFile::with_fallback(path, MAX_LOG_SIZE, Box::new(io::stderr()))
+    }
+
+    fn with_fallback(path: PathBuf, max_size: u64, fallback: Box<dyn Write + Send>) -> Self {
+        LogFile {
+            path,
+            max_size,
+            file: None,
+            inode: 0,
+            size: 0,
+            failed: false,
+            fallback,
+        }
+    }
+
+    fn write_line(&mut self, line: &[u8]) {
+        if !self.failed {
+            match self.try_write(line) {
+                Ok(()) => return,
+                Err(err) => {
+                    self.failed = true;
+                    self.file = None;
+                    let _ = writeln!(
+                        self.fallback,
+                        "failed to write to log file {}, logging to stderr: {}",
+                        self.path.display(),
+                        err
+                    );
+                }
+            }
+        }
+        let _ = self.fallback.write_all(line);
+    }
+
+    // every invocation of the plugin appends to the same file, so its size
+    // is only known once it's open, and it's reopened once another
+    // invocation rotated it.
+    fn try_write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.file.is_some() && self.replaced() {
+            self.file = None;
+        }
+        if self.file.is_none() {
+            self.open()?;
+        }
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+            self.open()?;
+        }
+        if let Some(file) = &mut self.file {
+            file.write_all(line)?;
+            self.size += line.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let metadata = file.metadata()?;
+        self.inode = metadata.ino();
+        self.size = metadata.len();
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn replaced(&self) -> bool {
+        match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.ino() != self.inode,
+            Err(_) => true,
+        }
+    }
+
+    // a concurrent invocation may have rotated the file first, the rename
+    // then only replaces the older rotated file
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        match fs::rename(&self.path, rotated) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_line(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use tempfile::TempDir;
+
+    // collects what's written to the fallback
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Buffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn log_file(path: PathBuf, max_size: u64) -> (Buffer, LogFile) {
+        let fallback = Buffer::default();
+        let log = LogFile::with_fallback(path, max_size, Box::new(fallback.clone()));
+        (fallback, log)
+    }
+
+    fn rotated(path: &std::path::Path) -> PathBuf {
+        let mut rotated = path.to_path_buf().into_os_string();
+        rotated.push(".1");
+        PathBuf::from(rotated)
+    }
+
+    #[test]
+    fn creates_the_log_directory() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("podtunnel").join("cni.log");
+        let (fallback, mut log) = log_file(path.clone(), MAX_LOG_SIZE);
+
+        log.write_line(b"first\n");
+        log.write_line(b"second\n");
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first\nsecond\n");
+        assert_eq!(fallback.contents(), "");
+    }
+
+    #[test]
+    fn rotates_once_the_log_is_full() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("cni.log");
+        let (fallback, mut log) = log_file(path.clone(), 14);
+
+        log.write_line(b"first\n");
+        log.write_line(b"second\n");
+        log.write_line(b"third\n");
+
+        assert_eq!(
+            fs::read_to_string(rotated(&path)).unwrap(),
+            "first\nsecond\n"
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "third\n");
+        assert_eq!(fallback.contents(), "");
+    }
+
+    #[test]
+    fn appends_to_a_log_written_by_an_earlier_invocation() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("cni.log");
+        fs::write(&path, "earlier\n").unwrap();
+        let (_, mut log) = log_file(path.clone(), 12);
+
+        log.write_line(b"later\n");
+
+        assert_eq!(fs::read_to_string(rotated(&path)).unwrap(), "earlier\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "later\n");
+    }
+
+    #[test]
+    fn reopens_a_log_rotated_by_another_process() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("cni.log");
+        let (_, mut log) = log_file(path.clone(), MAX_LOG_SIZE);
+
+        log.write_line(b"before\n");
+        fs::rename(&path, rotated(&path)).unwrap();
+        log.write_line(b"after\n");
+
+        assert_eq!(fs::read_to_string(rotated(&path)).unwrap(), "before\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "after\n");
+    }
+
+    #[test]
+    fn falls_back_when_the_log_cannot_be_written() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        let (fallback, mut log) = log_file(file.join("cni.log"), MAX_LOG_SIZE);
+
+        log.write_line(b"first\n");
+        log.write_line(b"second\n");
+
+        let contents = fallback.contents();
+        let mut lines = contents.lines();
+        assert!(
+            lines
+                .next()
+                .unwrap()
+                .starts_with("failed to write to log file")
+        );
+        assert_eq!(lines.collect::<Vec<_>>(), ["first", "second"]);
+    }
+
+    #[test]
+    fn logging_never_panics() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("cni.log");
+        let (fallback, mut log) = log_file(path.clone(), 1);
+
+        // lines over the maximum size still get written
+        log.write_line(b"first\n");
+        log.write_line(b"second\n");
+        // and a log directory removed underneath the plugin only falls back
+        fs::remove_dir_all(dir.path()).unwrap();
+        fs::write(dir.path(), "").unwrap();
+        log.write_line(b"third\n");
+
+        assert!(fallback.contents().ends_with("third\n"));
+        fs::remove_file(dir.path()).unwrap();
+    }
+}
@@ -1,28 +1,89 @@
+mod agent;
+mod cache;
+mod errors;
+mod logging;
 mod operations;
+mod resync;
 mod specification;
 
-use drivers::info;
+use errors::{CniContext, CniError, ErrorCode, Result};
+use logging::{DEFAULT_LOG_LEVEL, LogSettings};
 use operations as cni;
 use specification::Config as CniConfig;
 
-use std::env;
+use std::{env, process::exit};
 
-use anyhow::{Context, anyhow};
+use tracing::{error, info};
+
+fn main() {
+    // the same binary runs as the node agent
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let [command] = args.as_slice()
+        && command == "agent"
+    {
+        // the DaemonSet may raise the level, e.g. to debug for tunnel stats
+        let level = env::var("PODTUNNEL_LOG_LEVEL")
+            .ok()
+            .and_then(|level| level.parse().ok())
+            .unwrap_or(DEFAULT_LOG_LEVEL);
+        logging::init_stderr(level);
+        if let Err(err) = agent::run() {
+            error!("podtunnel agent failed: {:#}", err);
+            exit(1);
+        }
+        return;
+    }
+
+    run_cni();
+}
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    info!("running podtunnel cni");
+async fn run_cni() {
+    if let Err(cni_error) = run().await {
+        // a no-op unless the network config couldn't be read
+        logging::init(&LogSettings::default());
+        error!("podtunnel cni failed: {}", cni_error);
+        cni_error.print();
+        exit(1);
+    }
+}
 
-    let cni_command = env::var("CNI_COMMAND").context("no CNI_COMMAND provided")?;
-    let mut cni_config = CniConfig::new()?;
-    match cni_command.as_str() {
-        "ADD" => cni::add(&mut cni_config).await?,
-        "DEL" => cni::del(&mut cni_config)?,
-        "CHECK" => cni::check(&mut cni_config)?,
-        _ => return Err(anyhow!("unsupported cni command {}", cni_command)),
+async fn run() -> Result<()> {
+    let cni_command = env::var("CNI_COMMAND").cni_context(
+        ErrorCode::InvalidEnvironmentVariables,
+        "no CNI_COMMAND provided",
+    )?;
+    if cni_command == "VERSION" {
+        return specification::print_version_response();
     }
 
-    cni_config.print_cni_response()?;
+    let mut cni_config = CniConfig::new()?;
+    logging::init(&cni_config.log_settings()?);
+    info!("running podtunnel cni");
+
+    dispatch(&cni_command, &mut cni_config)
+        .await
+        .map_err(|cni_error| cni_error.for_version(&cni_config.cni_version))
+}
+
+async fn dispatch(cni_command: &str, cni_config: &mut CniConfig) -> Result<()> {
+    match cni_command {
+        "ADD" => {
+            cni::add(cni_config).await?;
+            cni_config.print_cni_response()?;
+        }
+        "DEL" => cni::del(cni_config).await?,
+        "CHECK" => cni::check(cni_config).await?,
+        "GC" => cni::gc(cni_config).await?,
+        "STATUS" => cni::status(cni_config).await?,
+        _ => {
+            return Err(CniError::new(
+                ErrorCode::InvalidEnvironmentVariables,
+                "unsupported cni command",
+                Some(cni_command.to_string()),
+            ));
+        }
+    }
 
     Ok(())
 }
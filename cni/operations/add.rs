@@ -1,39 +1,84 @@
-use crate::specification::{Config as CniConfig, Interface, Ips};
-use drivers::wireguard::workflows::configure_wireguard_for_pod;
+use crate::{
+    cache::Attachment,
+    errors::{CniContext, ErrorCode, Result},
+    specification::{Config as CniConfig, Dns, Interface, Ips, Route},
+};
+use drivers::driver::{Driver, TunnelDriver};
 
-use anyhow::Context;
+pub async fn add(cni_config: &mut CniConfig) -> Result<()> {
+    let (pod_namespace, pod_name, pod_netns, pod_ip) = cni_config.extract_pod_info()?;
+    let (container_id, ifname) = cni_config.extract_attachment_id()?;
+    let settings = cni_config.settings()?;
 
-pub async fn add(cni_config: &mut CniConfig) -> anyhow::Result<()> {
-    let (pod_namespace, pod_name, pod_netns, pod_ip) = cni_config
-        .extract_pod_info()
-        .context("failed to extract pod information from CNI environment")?;
+    let driver = Driver::from(settings.driver);
 
-    let (interface_name, tunnel_address, tunnel_address_prefix) =
-        match configure_wireguard_for_pod(&pod_name, &pod_namespace, &pod_netns, &pod_ip).await {
-            Ok(Some(result)) => result,
-            Ok(None) => return Ok(()),
-            Err(err) => return Err(err),
-        };
+    let configured_interface = match driver
+        .create(&pod_name, &pod_namespace, &pod_netns, &pod_ip, &settings)
+        .await
+        .cni_context(ErrorCode::TunnelSetupFailure, "failed to configure tunnel")?
+    {
+        Some(configured_interface) => configured_interface,
+        None => return Ok(()),
+    };
 
     let previous_result = cni_config
         .previous_result
         .as_mut()
-        .context("prevresult is missing")?;
+        .cni_context(ErrorCode::InvalidNetworkConfig, "prevresult is missing")?;
 
     previous_result.interfaces.push(Interface {
-        name: interface_name,
-        sandbox: Some(pod_netns),
-        mac: None,
-        mtu: None,
+        name: configured_interface.name.clone(),
+        sandbox: Some(pod_netns.clone()),
+        mac: configured_interface.mac.clone(),
+        mtu: configured_interface.mtu,
         socket_path: None,
         pci_id: None,
     });
+    let interface_idx = previous_result.interfaces.len() - 1;
 
     previous_result.ips.push(Ips {
-        interface: None,
-        address: Some(format!("{}/{}", &tunnel_address, tunnel_address_prefix)),
+        version: None,
+        interface: Some(interface_idx),
+        address: Some(format!(
+            "{}/{}",
+            &configured_interface.address, configured_interface.prefix
+        )),
         gateway: None,
     });
 
+    for dst in configured_interface.routes.iter() {
+        previous_result.routes.push(Route {
+            dst: Some(dst.clone()),
+            gw: None,
+            mtu: None,
+            advmss: configured_interface.advmss(),
+            priority: None,
+            table: None,
+            scope: None,
+        });
+    }
+
+    if !configured_interface.dns.is_empty() {
+        let dns = previous_result.dns.get_or_insert_with(Dns::default);
+        for nameserver in configured_interface.dns.iter() {
+            if !dns.nameservers.contains(nameserver) {
+                dns.nameservers.push(nameserver.clone());
+            }
+        }
+    }
+
+    let attachment = Attachment {
+        container_id,
+        ifname,
+        pod_name,
+        pod_namespace,
+        pod_address: pod_ip,
+        netns: pod_netns,
+        settings,
+        interface: configured_interface,
+        result: previous_result.clone(),
+    };
+    cni_config.cache().put(&attachment)?;
+
     Ok(())
 }
@@ -1,6 +1,55 @@
-use crate::specification::Config as CniConfig;
+use crate::{
+    errors::{CniContext, CniError, ErrorCode, Result},
+    specification::{CniVersion, Config as CniConfig},
+};
+use drivers::driver::{Driver, TunnelDriver};
+
+pub async fn check(cni_config: &mut CniConfig) -> Result<()> {
+    if cni_config.version()? < CniVersion::CHECK_SUPPORTED {
+        return Err(CniError::new(
+            ErrorCode::IncompatibleCniVersion,
+            "incompatible CNI version",
+            Some(format!(
+                "CHECK requires cniVersion {} or later",
+                CniVersion::CHECK_SUPPORTED
+            )),
+        ));
+    }
+
+    let (container_id, ifname) = cni_config.extract_attachment_id()?;
+    let (pod_namespace, pod_name, pod_netns) = cni_config.extract_pod_identity()?;
+    let pod_netns =
+        pod_netns.cni_context(ErrorCode::InvalidEnvironmentVariables, "empty CNI_NETNS")?;
+    let settings = cni_config.settings()?;
+
+    // the tunnel is verified against what ADD applied whenever it was cached,
+    // and against the current WireguardConfig otherwise.
+    let drift = match cni_config.cache().get(&container_id, &ifname)? {
+        Some(attachment) => {
+            Driver::from(attachment.settings.driver)
+                .verify(
+                    &pod_name,
+                    &pod_namespace,
+                    &pod_netns,
+                    Some(&attachment.interface),
+                    &attachment.settings,
+                )
+                .await
+        }
+        None => {
+            Driver::from(settings.driver)
+                .verify(&pod_name, &pod_namespace, &pod_netns, None, &settings)
+                .await
+        }
+    }
+    .cni_context(ErrorCode::TunnelDrift, "failed to inspect tunnel")?;
+    if !drift.is_empty() {
+        return Err(CniError::new(
+            ErrorCode::TunnelDrift,
+            "tunnel does not match its configuration",
+            Some(drift.join("; ")),
+        ));
+    }
 
-pub fn check(cni_config: &mut CniConfig) -> anyhow::Result<()> {
-    println!("CNI CHECK UNIMPLEMENTED, CONFIG: {:?}", cni_config);
     Ok(())
 }
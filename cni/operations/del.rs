@@ -1,6 +1,63 @@
-use crate::specification::Config as CniConfig;
+use crate::{
+    errors::{CniContext, ErrorCode, Result},
+    specification::Config as CniConfig,
+};
+use drivers::{
+    driver::{Driver, TunnelDriver},
+    settings::Settings,
+};
 
-pub fn del(cni_config: &mut CniConfig) -> anyhow::Result<()> {
-    println!("CNI DELETE UNIMPLEMENTED, CONFIG: {:?}", cni_config);
-    Ok(())
+pub async fn del(cni_config: &mut CniConfig) -> Result<()> {
+    let (container_id, ifname) = cni_config.extract_attachment_id()?;
+    let cache = cni_config.cache();
+    let settings = cni_config.settings()?;
+
+    match cache.get(&container_id, &ifname)? {
+        Some(attachment) => {
+            // the interface is torn down with the settings it was created
+            // with, even if the network config changed in the meantime.
+            let netns = cni_config
+                .extract_pod_identity()
+                .ok()
+                .and_then(|(_, _, pod_netns)| pod_netns)
+                .unwrap_or(attachment.netns);
+            let settings = Settings {
+                kubeconfig: settings.kubeconfig,
+                ..attachment.settings
+            };
+
+            Driver::from(settings.driver)
+                .delete(
+                    &attachment.pod_name,
+                    &attachment.pod_namespace,
+                    Some(&netns),
+                    Some(attachment.pod_address),
+                    &settings,
+                )
+                .await
+                .cni_context(
+                    ErrorCode::TunnelTeardownFailure,
+                    "failed to tear down tunnel",
+                )?;
+        }
+        None => {
+            let (pod_namespace, pod_name, pod_netns) = cni_config.extract_pod_identity()?;
+
+            Driver::from(settings.driver)
+                .delete(
+                    &pod_name,
+                    &pod_namespace,
+                    pod_netns.as_deref(),
+                    None,
+                    &settings,
+                )
+                .await
+                .cni_context(
+                    ErrorCode::TunnelTeardownFailure,
+                    "failed to tear down tunnel",
+                )?;
+        }
+    }
+
+    cache.remove(&container_id, &ifname)
 }
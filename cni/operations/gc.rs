@@ -0,0 +1,131 @@
+use crate::{
+    cache::{Attachment, AttachmentCache},
+    errors::{CniContext, CniError, ErrorCode, Result},
+    specification::{CniVersion, Config as CniConfig, ValidAttachment},
+};
+use drivers::{
+    driver::{Driver, TunnelDriver},
+    settings::Settings,
+};
+
+use tracing::info;
+
+pub async fn gc(cni_config: &mut CniConfig) -> Result<()> {
+    if cni_config.version()? < CniVersion::GC_SUPPORTED {
+        return Err(CniError::new(
+            ErrorCode::IncompatibleCniVersion,
+            "incompatible CNI version",
+            Some(format!(
+                "GC requires cniVersion {} or later",
+                CniVersion::GC_SUPPORTED
+            )),
+        ));
+    }
+
+    let cache = cni_config.cache();
+    let settings = cni_config.settings()?;
+
+    let mut failures = vec![];
+    for attachment in stale_attachments(&cache, &cni_config.valid_attachments)? {
+        info!("collecting stale attachment {}", attachment.key());
+        let attachment_settings = Settings {
+            kubeconfig: settings.kubeconfig.clone(),
+            ..attachment.settings.clone()
+        };
+        let result = Driver::from(attachment_settings.driver)
+            .delete(
+                &attachment.pod_name,
+                &attachment.pod_namespace,
+                Some(&attachment.netns),
+                Some(attachment.pod_address),
+                &attachment_settings,
+            )
+            .await
+            .cni_context(
+                ErrorCode::TunnelTeardownFailure,
+                "failed to tear down tunnel",
+            )
+            .and_then(|_| cache.remove(&attachment.container_id, &attachment.ifname));
+
+        // keep collecting the remaining attachments, the failed ones are
+        // retried on the next GC.
+        if let Err(err) = result {
+            failures.push(format!("{}: {}", attachment.key(), err));
+        }
+    }
+
+    if !failures.is_empty() {
+        return Err(CniError::new(
+            ErrorCode::TunnelTeardownFailure,
+            "failed to collect stale attachments",
+            Some(failures.join("; ")),
+        ));
+    }
+
+    Ok(())
+}
+
+// every cached attachment the runtime didn't list as valid
+fn stale_attachments(
+    cache: &AttachmentCache,
+    valid_attachments: &[ValidAttachment],
+) -> Result<Vec<Attachment>> {
+    Ok(cache
+        .list()?
+        .into_iter()
+        .filter(|attachment| {
+            !valid_attachments.iter().any(|valid| {
+                valid.container_id == attachment.container_id && valid.ifname == attachment.ifname
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::tests::{attachment, cache};
+
+    use std::fs;
+
+    fn valid(container_id: &str, ifname: &str) -> ValidAttachment {
+        ValidAttachment {
+            container_id: container_id.to_string(),
+            ifname: ifname.to_string(),
+        }
+    }
+
+    #[test]
+    fn collects_attachments_which_are_not_valid() {
+        let (dir, cache) = cache();
+        for (container_id, ifname) in [("abc", "eth0"), ("abc", "eth1"), ("def", "eth0")] {
+            cache.put(&attachment(container_id, ifname)).unwrap();
+        }
+        fs::write(dir.path().join("podtunnel").join("ghi-eth0"), b"{").unwrap();
+
+        let mut stale: Vec<String> = stale_attachments(&cache, &[valid("abc", "eth0")])
+            .unwrap()
+            .iter()
+            .map(Attachment::key)
+            .collect();
+        stale.sort();
+        assert_eq!(stale, ["abc-eth1", "def-eth0"]);
+
+        let valid_attachments = [
+            valid("abc", "eth0"),
+            valid("abc", "eth1"),
+            valid("def", "eth0"),
+        ];
+        assert!(
+            stale_attachments(&cache, &valid_attachments)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn collects_nothing_without_a_cache() {
+        let (_dir, cache) = cache();
+        assert!(stale_attachments(&cache, &[]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,40 @@
+use crate::{
+    errors::{CniError, ErrorCode, Result},
+    specification::{CniVersion, Config as CniConfig},
+};
+use drivers::{driver::DriverKind, overlay, wireguard};
+
+pub async fn status(cni_config: &mut CniConfig) -> Result<()> {
+    if cni_config.version()? < CniVersion::STATUS_SUPPORTED {
+        return Err(CniError::new(
+            ErrorCode::IncompatibleCniVersion,
+            "incompatible CNI version",
+            Some(format!(
+                "STATUS requires cniVersion {} or later",
+                CniVersion::STATUS_SUPPORTED
+            )),
+        ));
+    }
+
+    let settings = cni_config.settings()?;
+
+    let not_available = match settings.driver {
+        DriverKind::Wireguard => wireguard::status::plugin_status(&settings)
+            .await
+            .err()
+            .map(|not_available| not_available.to_string()),
+        DriverKind::Overlay => overlay::status::plugin_status(&settings)
+            .await
+            .err()
+            .map(|not_available| not_available.to_string()),
+    };
+
+    match not_available {
+        Some(not_available) => Err(CniError::new(
+            ErrorCode::PluginNotAvailable,
+            "podtunnel is not ready",
+            Some(not_available),
+        )),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,145 @@
+use crate::cache::{Attachment, AttachmentCache, DEFAULT_CACHE_DIR};
+use drivers::driver::{Driver, TunnelDriver};
+
+use std::{fs, io::ErrorKind, path::Path, thread, time::Duration};
+
+use anyhow::anyhow;
+use tracing::{debug, error, info};
+
+// how often the agent brings the tunnels on its node in line with their
+// configs
+const RESYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+// Tunnels are only created at ADD, so peers added, removed or moved later
+// reach a running Pod through this loop. It updates every tunnel the plugin
+// cached an attachment for, which is a no-op for those already up to date,
+// and logs their stats.
+pub fn run() {
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            error!("failed to start the tunnel resync: {}", err);
+            return;
+        }
+    };
+
+    loop {
+        match cached_attachments(Path::new(DEFAULT_CACHE_DIR)) {
+            Ok(attachments) => {
+                for attachment in attachments {
+                    if let Err(err) = runtime.block_on(resync(&attachment)) {
+                        info!(
+                            "failed to update the tunnel of Pod {}/{}: {:#}",
+                            attachment.pod_namespace, attachment.pod_name, err
+                        );
+                    }
+                }
+            }
+            Err(err) => error!("failed to list the cached attachments: {:#}", err),
+        }
+        thread::sleep(RESYNC_INTERVAL);
+    }
+}
+
+async fn resync(attachment: &Attachment) -> anyhow::Result<()> {
+    // DEL may be tearing the attachment down right now
+    if !Path::new(&attachment.netns).exists() {
+        return Ok(());
+    }
+
+    let driver = Driver::from(attachment.settings.driver);
+    let changes = driver
+        .update(
+            &attachment.pod_name,
+            &attachment.pod_namespace,
+            &attachment.netns,
+            &attachment.settings,
+        )
+        .await?;
+    if !changes.is_empty() {
+        info!(
+            "updated the tunnel of Pod {}/{}: {}",
+            attachment.pod_namespace,
+            attachment.pod_name,
+            changes.join(", ")
+        );
+    }
+
+    let stats = driver
+        .stats(&attachment.netns, &attachment.settings)
+        .await?;
+    debug!(
+        "tunnel {} of Pod {}/{}: {} peers, {} bytes received, {} bytes sent",
+        stats.interface,
+        attachment.pod_namespace,
+        attachment.pod_name,
+        stats.peers.len(),
+        stats.rx_bytes,
+        stats.tx_bytes
+    );
+    Ok(())
+}
+
+// The plugin caches attachments per network, in a directory named after it.
+fn cached_attachments(cache_dir: &Path) -> anyhow::Result<Vec<Attachment>> {
+    let entries = match fs::read_dir(cache_dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut attachments = vec![];
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let cache = AttachmentCache::new(cache_dir, &entry.file_name().to_string_lossy());
+        attachments.extend(cache.list().map_err(|err| anyhow!("{}", err))?);
+    }
+    Ok(attachments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cache::tests::attachment;
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn finds_the_attachments_of_every_network() {
+        let dir = TempDir::new().unwrap();
+        AttachmentCache::new(dir.path(), "podtunnel")
+            .put(&attachment("a", "wg0"))
+            .unwrap();
+        AttachmentCache::new(dir.path(), "overlay")
+            .put(&attachment("b", "vx0"))
+            .unwrap();
+        fs::write(dir.path().join("stray"), "").unwrap();
+
+        let mut keys: Vec<String> = cached_attachments(dir.path())
+            .unwrap()
+            .iter()
+            .map(Attachment::key)
+            .collect();
+        keys.sort();
+
+        assert_eq!(keys, ["a-wg0", "b-vx0"]);
+    }
+
+    #[test]
+    fn finds_nothing_before_the_first_attachment() {
+        let dir = TempDir::new().unwrap();
+
+        assert!(
+            cached_attachments(&dir.path().join("podtunnel"))
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
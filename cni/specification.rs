@@ -1,8 +1,133 @@
-use std::{env, io::Read, net::Ipv4Addr, str::FromStr};
-
-use anyhow::Context;
+use crate::{
+    cache::{AttachmentCache, DEFAULT_CACHE_DIR},
+    errors::{CniContext, CniError, ErrorCode, Result},
+    logging::LogSettings,
+};
+use drivers::{driver::DriverKind, settings::Settings};
+
+use std::{
+    env,
+    fmt::{self, Display, Formatter},
+    io::Read,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 use serde_json::from_str as deserialize;
+use tracing::Level;
+
+pub const SUPPORTED_VERSIONS: [CniVersion; 5] = [
+    CniVersion(0, 3, 0),
+    CniVersion(0, 3, 1),
+    CniVersion(0, 4, 0),
+    CniVersion(1, 0, 0),
+    CniVersion(1, 1, 0),
+];
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct CniVersion(u8, u8, u8);
+
+impl CniVersion {
+    pub const CHECK_SUPPORTED: CniVersion = CniVersion(0, 4, 0);
+    pub const IP_VERSION_REMOVED: CniVersion = CniVersion(1, 0, 0);
+    pub const EXTENDED_FIELDS_ADDED: CniVersion = CniVersion(1, 1, 0);
+    pub const GC_SUPPORTED: CniVersion = CniVersion(1, 1, 0);
+    pub const STATUS_SUPPORTED: CniVersion = CniVersion(1, 1, 0);
+
+    pub fn latest() -> Self {
+        SUPPORTED_VERSIONS[SUPPORTED_VERSIONS.len() - 1]
+    }
+
+    pub fn is_supported(&self) -> bool {
+        SUPPORTED_VERSIONS.contains(self)
+    }
+}
+
+impl FromStr for CniVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(version: &str) -> anyhow::Result<Self> {
+        let parts = version
+            .split('.')
+            .map(str::parse::<u8>)
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| anyhow!("invalid cni version {}", version))?;
+
+        match parts[..] {
+            [major, minor, patch] => Ok(CniVersion(major, minor, patch)),
+            [major, minor] => Ok(CniVersion(major, minor, 0)),
+            _ => Err(anyhow!("invalid cni version {}", version)),
+        }
+    }
+}
+
+impl Display for CniVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct VersionRequest {
+    #[serde(rename = "cniVersion")]
+    #[serde(default)]
+    cni_version: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+struct VersionResult {
+    #[serde(rename = "cniVersion")]
+    cni_version: String,
+
+    #[serde(rename = "supportedVersions")]
+    supported_versions: Vec<String>,
+}
+
+pub fn print_version_response() -> Result<()> {
+    let mut version_request_json = String::new();
+
+    std::io::stdin()
+        .read_to_string(&mut version_request_json)
+        .cni_context(ErrorCode::IoFailure, "failed to read cni version request")?;
+
+    let version_result = negotiate_version(&version_request_json)?;
+    let version_response = serde_json::to_string_pretty(&version_result).cni_context(
+        ErrorCode::IoFailure,
+        "failed to serialize cni version result",
+    )?;
+    println!("{}", version_response);
+    Ok(())
+}
+
+// An empty request, or one for a version we don't support, is answered with
+// the latest version we support.
+fn negotiate_version(version_request_json: &str) -> Result<VersionResult> {
+    let requested_version = match version_request_json.trim() {
+        "" => None,
+        version_request_json => deserialize::<VersionRequest>(version_request_json)
+            .cni_context(
+                ErrorCode::DecodingFailure,
+                "failed to deserialize cni version request",
+            )?
+            .cni_version
+            .and_then(|version| CniVersion::from_str(&version).ok())
+            .filter(CniVersion::is_supported),
+    };
+
+    Ok(VersionResult {
+        cni_version: requested_version
+            .unwrap_or_else(CniVersion::latest)
+            .to_string(),
+        supported_versions: SUPPORTED_VERSIONS
+            .iter()
+            .map(CniVersion::to_string)
+            .collect(),
+    })
+}
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
@@ -14,29 +139,187 @@ pub struct Config {
     #[serde(rename = "prevResult")]
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub previous_result: Option<PreviousResult>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub driver: Option<DriverKind>,
+
+    #[serde(rename = "interfaceName")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface_name: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fwmark: Option<u32>,
+
+    #[serde(rename = "routingTable")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub routing_table: Option<u32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kubeconfig: Option<PathBuf>,
+
+    #[serde(rename = "readinessTimeoutSeconds")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub readiness_timeout_seconds: Option<u64>,
+
+    #[serde(rename = "userspaceFallback")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub userspace_fallback: Option<bool>,
+
+    #[serde(rename = "cacheDir")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_dir: Option<PathBuf>,
+
+    #[serde(rename = "logFile")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<PathBuf>,
+
+    #[serde(rename = "logLevel")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<String>,
+
+    #[serde(rename = "cni.dev/valid-attachments")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub valid_attachments: Vec<ValidAttachment>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ValidAttachment {
+    #[serde(rename = "containerID")]
+    pub container_id: String,
+
+    pub ifname: String,
 }
 
 impl Config {
-    pub fn new() -> anyhow::Result<Self> {
+    pub fn new() -> Result<Self> {
         let mut cni_config_json = String::new();
 
         std::io::stdin()
             .read_to_string(&mut cni_config_json)
-            .context("failed to read cni config")?;
+            .cni_context(ErrorCode::IoFailure, "failed to read cni config")?;
+
+        Self::parse(&cni_config_json)
+    }
+
+    fn parse(cni_config_json: &str) -> Result<Self> {
+        let cni_config = deserialize::<Self>(cni_config_json).cni_context(
+            ErrorCode::DecodingFailure,
+            "failed to deserialize cni config",
+        )?;
+
+        if !cni_config
+            .version()
+            .is_ok_and(|version| version.is_supported())
+        {
+            return Err(CniError::new(
+                ErrorCode::IncompatibleCniVersion,
+                "incompatible CNI version",
+                Some(format!(
+                    "cniVersion {} is not one of {}",
+                    cni_config.cni_version,
+                    SUPPORTED_VERSIONS
+                        .map(|version| version.to_string())
+                        .join(", ")
+                )),
+            ));
+        }
 
-        let cni_config =
-            deserialize::<Self>(&cni_config_json).context("failed to deserialize cni config")?;
+        cni_config.settings()?;
 
         Ok(cni_config)
     }
 
-    pub fn extract_pod_info(&self) -> anyhow::Result<(String, String, String, Ipv4Addr)> {
+    pub fn settings(&self) -> Result<Settings> {
+        let defaults = Settings::default();
+        let driver = self.driver.unwrap_or(defaults.driver);
+        let settings = Settings {
+            driver,
+            interface_name: self
+                .interface_name
+                .clone()
+                .unwrap_or_else(|| driver.default_interface_name().to_string()),
+            fwmark: self.fwmark.unwrap_or(defaults.fwmark),
+            routing_table: self.routing_table.unwrap_or(defaults.routing_table),
+            kubeconfig: self.kubeconfig.clone().unwrap_or(defaults.kubeconfig),
+            readiness_timeout: self
+                .readiness_timeout_seconds
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.readiness_timeout),
+            underlay_mtu: self.pod_interface_mtu(),
+            userspace_fallback: self
+                .userspace_fallback
+                .unwrap_or(defaults.userspace_fallback),
+        };
+
+        settings.validate().cni_context(
+            ErrorCode::InvalidNetworkConfig,
+            "invalid podtunnel settings",
+        )?;
+
+        Ok(settings)
+    }
+
+    pub fn log_settings(&self) -> Result<LogSettings> {
+        let defaults = LogSettings::default();
+        let path = self.log_file.clone().unwrap_or(defaults.path);
+        if !path.is_absolute() {
+            return Err(CniError::new(
+                ErrorCode::InvalidNetworkConfig,
+                "invalid podtunnel settings",
+                Some(format!("log file path {} must be absolute", path.display())),
+            ));
+        }
+
+        let level = match &self.log_level {
+            Some(level) => Level::from_str(level).map_err(|_| {
+                CniError::new(
+                    ErrorCode::InvalidNetworkConfig,
+                    "invalid podtunnel settings",
+                    Some(format!("unknown log level {:?}", level)),
+                )
+            })?,
+            None => defaults.level,
+        };
+
+        Ok(LogSettings { path, level })
+    }
+
+    // The interface the previous plugins created in the Pod's netns, later
+    // entries for the same netns are the tunnels added on top of it.
+    fn pod_interface_mtu(&self) -> Option<u32> {
+        let pod_netns = env::var("CNI_NETNS").ok()?;
+        let mtu = self
+            .previous_result
+            .as_ref()?
+            .interfaces
+            .iter()
+            .find(|interface| interface.sandbox.as_ref() == Some(&pod_netns))?
+            .mtu?;
+        u32::try_from(mtu).ok()
+    }
+
+    pub fn version(&self) -> Result<CniVersion> {
+        CniVersion::from_str(&self.cni_version).cni_context(
+            ErrorCode::IncompatibleCniVersion,
+            "incompatible CNI version",
+        )
+    }
+
+    pub fn extract_pod_info(&self) -> Result<(String, String, String, Ipv4Addr)> {
         let cni_previous_result = self
             .previous_result
             .as_ref()
-            .context("no cni prevresult found")?;
-
-        let pod_netns = env::var("CNI_NETNS").context("empty CNI_NETNS")?;
+            .cni_context(ErrorCode::InvalidNetworkConfig, "no cni prevresult found")?;
+
+        let pod_netns = env::var("CNI_NETNS")
+            .cni_context(ErrorCode::InvalidEnvironmentVariables, "empty CNI_NETNS")?;
+        if !Path::new(&pod_netns).exists() {
+            return Err(CniError::new(
+                ErrorCode::ContainerUnknown,
+                "pod netns does not exist",
+                Some(pod_netns),
+            ));
+        }
 
         let interface_idx = cni_previous_result
             .interfaces
@@ -46,42 +329,89 @@ impl Config {
                 Some(sandbox) if *sandbox == pod_netns => Some(idx),
                 _ => None,
             })
-            .context("no interface index found for cni prevresult")?;
+            .cni_context(
+                ErrorCode::InvalidNetworkConfig,
+                "no interface index found for cni prevresult",
+            )?;
 
         let cni_ip = cni_previous_result
             .ips
             .iter()
             .find(|&ip| ip.interface == Some(interface_idx))
-            .context("no cni ip found for pod")?;
-
-        let cni_ip_address = cni_ip
-            .address
-            .as_ref()
-            .context("no address found for cni ip")?;
+            .cni_context(ErrorCode::InvalidNetworkConfig, "no cni ip found for pod")?;
 
-        let pod_ip = Ipv4Addr::from_str(
-            &cni_ip_address
-                .split('/')
-                .next()
-                .context("malformed cni ip")?,
+        let cni_ip_address = cni_ip.address.as_ref().cni_context(
+            ErrorCode::InvalidNetworkConfig,
+            "no address found for cni ip",
         )?;
 
-        let pod_netns = env::var("CNI_NETNS").context("empty CNI_NETNS")?;
+        let pod_ip = match cni_ip_address.split('/').next().map(IpAddr::from_str) {
+            Some(Ok(IpAddr::V4(pod_ip))) => pod_ip,
+            Some(Ok(IpAddr::V6(_))) => {
+                return Err(CniError::new(
+                    ErrorCode::UnsupportedField,
+                    "IPv6 pod addresses are not supported",
+                    Some(cni_ip_address.clone()),
+                ));
+            }
+            _ => {
+                return Err(CniError::new(
+                    ErrorCode::InvalidNetworkConfig,
+                    "malformed cni ip",
+                    Some(cni_ip_address.clone()),
+                ));
+            }
+        };
+
+        let (pod_namespace, pod_name) = extract_pod_name()?;
 
-        let cni_args = env::var("CNI_ARGS").context("empty CNI_ARGS")?;
+        Ok((pod_namespace, pod_name, pod_netns, pod_ip))
+    }
 
-        let pod_namespace =
-            cni_args_extract("K8S_POD_NAMESPACE", &cni_args)?.context("empty K8S_POD_NAMESPACE")?;
+    pub fn cache(&self) -> AttachmentCache {
+        let cache_dir = self
+            .cache_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CACHE_DIR));
+        AttachmentCache::new(&cache_dir, &self.name)
+    }
 
-        let pod_name =
-            cni_args_extract("K8S_POD_NAME", &cni_args)?.context("empty K8S_POD_NAME")?;
+    pub fn extract_attachment_id(&self) -> Result<(String, String)> {
+        let container_id = env::var("CNI_CONTAINERID")
+            .ok()
+            .filter(|container_id| !container_id.is_empty())
+            .cni_context(
+                ErrorCode::InvalidEnvironmentVariables,
+                "empty CNI_CONTAINERID",
+            )?;
+
+        let ifname = env::var("CNI_IFNAME")
+            .ok()
+            .filter(|ifname| !ifname.is_empty())
+            .cni_context(ErrorCode::InvalidEnvironmentVariables, "empty CNI_IFNAME")?;
+
+        Ok((container_id, ifname))
+    }
 
-        Ok((pod_namespace, pod_name, pod_netns, pod_ip))
+    pub fn extract_pod_identity(&self) -> Result<(String, String, Option<String>)> {
+        let pod_netns = env::var("CNI_NETNS")
+            .ok()
+            .filter(|pod_netns| !pod_netns.is_empty());
+
+        let (pod_namespace, pod_name) = extract_pod_name()?;
+
+        Ok((pod_namespace, pod_name, pod_netns))
     }
 
-    pub fn print_cni_response(&self) -> anyhow::Result<()> {
-        let cni_add_response = serde_json::to_string_pretty(&self.previous_result)
-            .context("failed to serialize CNI prevresult")?;
+    pub fn print_cni_response(&self) -> Result<()> {
+        let version = self.version()?;
+        let result = self
+            .previous_result
+            .clone()
+            .map(|previous_result| previous_result.into_version(version));
+
+        let cni_add_response = serde_json::to_string_pretty(&result)
+            .cni_context(ErrorCode::IoFailure, "failed to serialize CNI prevresult")?;
         println!("{}", cni_add_response);
         Ok(())
     }
@@ -105,6 +435,35 @@ pub struct PreviousResult {
     pub dns: Option<Dns>,
 }
 
+impl PreviousResult {
+    pub fn into_version(mut self, version: CniVersion) -> Self {
+        self.cni_version = version.to_string();
+
+        for ip in self.ips.iter_mut() {
+            ip.version = match version < CniVersion::IP_VERSION_REMOVED {
+                true => ip.ip_version(),
+                false => None,
+            };
+        }
+
+        if version < CniVersion::EXTENDED_FIELDS_ADDED {
+            for interface in self.interfaces.iter_mut() {
+                interface.socket_path = None;
+                interface.pci_id = None;
+            }
+            for route in self.routes.iter_mut() {
+                route.mtu = None;
+                route.advmss = None;
+                route.priority = None;
+                route.table = None;
+                route.scope = None;
+            }
+        }
+
+        self
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Interface {
     pub name: String,
@@ -119,6 +478,7 @@ pub struct Interface {
     pub sandbox: Option<String>,
 
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "socketPath")]
     pub socket_path: Option<String>,
 
     #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -128,6 +488,9 @@ pub struct Interface {
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Ips {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub interface: Option<usize>,
 
@@ -138,6 +501,16 @@ pub struct Ips {
     pub gateway: Option<String>,
 }
 
+impl Ips {
+    fn ip_version(&self) -> Option<String> {
+        let address = self.address.as_ref()?.split('/').next()?;
+        match IpAddr::from_str(address).ok()? {
+            IpAddr::V4(_) => Some("4".to_string()),
+            IpAddr::V6(_) => Some("6".to_string()),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Route {
     #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -162,7 +535,7 @@ pub struct Route {
     pub scope: Option<usize>,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Dns {
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub nameservers: Vec<String>,
@@ -177,11 +550,30 @@ pub struct Dns {
     pub options: Vec<String>,
 }
 
-fn cni_args_extract(cni_args_key: &str, cni_args: &str) -> anyhow::Result<Option<String>> {
+fn extract_pod_name() -> Result<(String, String)> {
+    let cni_args = env::var("CNI_ARGS")
+        .cni_context(ErrorCode::InvalidEnvironmentVariables, "empty CNI_ARGS")?;
+
+    let pod_namespace = cni_args_extract("K8S_POD_NAMESPACE", &cni_args)?.cni_context(
+        ErrorCode::InvalidEnvironmentVariables,
+        "empty K8S_POD_NAMESPACE",
+    )?;
+
+    let pod_name = cni_args_extract("K8S_POD_NAME", &cni_args)?
+        .cni_context(ErrorCode::InvalidEnvironmentVariables, "empty K8S_POD_NAME")?;
+
+    Ok((pod_namespace, pod_name))
+}
+
+fn cni_args_extract(cni_args_key: &str, cni_args: &str) -> Result<Option<String>> {
     for pair in cni_args.split(';') {
         let mut parts = pair.splitn(2, '=');
-        let key = parts.next().context("malformed CNI_ARGS")?;
-        let value = parts.next().context("malformed CNI_ARGS")?;
+        let key = parts
+            .next()
+            .cni_context(ErrorCode::InvalidEnvironmentVariables, "malformed CNI_ARGS")?;
+        let value = parts
+            .next()
+            .cni_context(ErrorCode::InvalidEnvironmentVariables, "malformed CNI_ARGS")?;
 
         if key == cni_args_key {
             return Ok(Some(value.to_string()));
@@ -190,3 +582,171 @@ fn cni_args_extract(cni_args_key: &str, cni_args: &str) -> anyhow::Result<Option
 
     Ok(None)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::{Value, json};
+
+    const NETWORK_CONFIG: &str =
+        r#"{"name": "podtunnel", "cniVersion": "VERSION", "type": "podtunnel-cni"}"#;
+
+    fn previous_result() -> PreviousResult {
+        serde_json::from_value(json!({
+            "cniVersion": "1.1.0",
+            "interfaces": [
+                {
+                    "name": "wg0",
+                    "mac": "00:00:00:00:00:00",
+                    "mtu": 1420,
+                    "sandbox": "/var/run/netns/test",
+                    "socketPath": "/run/test.sock",
+                    "pciID": "0000:00:00.0",
+                },
+            ],
+            "ips": [
+                {"version": "4", "interface": 0, "address": "10.0.0.1/24"},
+                {"interface": 0, "address": "fd00::1/64"},
+            ],
+            "routes": [
+                {
+                    "dst": "0.0.0.0/0",
+                    "mtu": 1420,
+                    "advmss": 1380,
+                    "priority": 2,
+                    "table": 129518285,
+                    "scope": 0,
+                },
+            ],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_versions() {
+        let cases = [
+            ("0.3.0", Some(CniVersion(0, 3, 0))),
+            ("0.4", Some(CniVersion(0, 4, 0))),
+            ("1.1.0", Some(CniVersion(1, 1, 0))),
+            ("1", None),
+            ("1.0.0.0", None),
+            ("1.x.0", None),
+            ("", None),
+        ];
+        for (version, expected) in cases {
+            assert_eq!(CniVersion::from_str(version).ok(), expected, "{}", version);
+        }
+        assert_eq!(CniVersion::latest().to_string(), "1.1.0");
+    }
+
+    #[test]
+    fn negotiates_the_requested_version_when_supported() {
+        let supported_versions: Vec<String> = SUPPORTED_VERSIONS
+            .iter()
+            .map(CniVersion::to_string)
+            .collect();
+        let mut cases = vec![
+            ("".to_string(), "1.1.0"),
+            ("{}".to_string(), "1.1.0"),
+            (r#"{"cniVersion": "0.2.0"}"#.to_string(), "1.1.0"),
+            (r#"{"cniVersion": "2.0.0"}"#.to_string(), "1.1.0"),
+            (r#"{"cniVersion": "latest"}"#.to_string(), "1.1.0"),
+        ];
+        for version in &supported_versions {
+            cases.push((format!(r#"{{"cniVersion": "{}"}}"#, version), version));
+        }
+
+        for (request, expected) in cases {
+            assert_eq!(
+                negotiate_version(&request).unwrap(),
+                VersionResult {
+                    cni_version: expected.to_string(),
+                    supported_versions: supported_versions.clone(),
+                },
+                "{}",
+                request
+            );
+        }
+
+        let err = negotiate_version("not json").unwrap_err();
+        assert_eq!(err.code, ErrorCode::DecodingFailure);
+    }
+
+    #[test]
+    fn accepts_only_supported_config_versions() {
+        for version in SUPPORTED_VERSIONS {
+            let config = NETWORK_CONFIG.replace("VERSION", &version.to_string());
+            assert_eq!(Config::parse(&config).unwrap().version().unwrap(), version);
+        }
+
+        for version in ["0.1.0", "0.2.0", "1.2.0", "2.0.0", "latest"] {
+            let config = NETWORK_CONFIG.replace("VERSION", version);
+            let err = Config::parse(&config).unwrap_err();
+            assert_eq!(err.code, ErrorCode::IncompatibleCniVersion, "{}", version);
+        }
+    }
+
+    #[test]
+    fn converts_results_to_every_supported_version() {
+        // (version, ip versions, extended interface and route fields)
+        let cases = [
+            ("0.3.0", true, false),
+            ("0.3.1", true, false),
+            ("0.4.0", true, false),
+            ("1.0.0", false, false),
+            ("1.1.0", false, true),
+        ];
+        assert_eq!(cases.len(), SUPPORTED_VERSIONS.len());
+
+        for (version, ip_versions, extended_fields) in cases {
+            let result = previous_result().into_version(version.parse().unwrap());
+            let result = serde_json::to_value(result).unwrap();
+
+            assert_eq!(result["cniVersion"], version);
+            let versions: Vec<&Value> = result["ips"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|ip| &ip["version"])
+                .collect();
+            match ip_versions {
+                true => assert_eq!(versions, [&json!("4"), &json!("6")], "{}", version),
+                false => assert_eq!(versions, [&Value::Null, &Value::Null], "{}", version),
+            }
+
+            let interface = &result["interfaces"][0];
+            let route = &result["routes"][0];
+            assert_eq!(interface["mtu"], 1420);
+            assert_eq!(route["dst"], "0.0.0.0/0");
+            match extended_fields {
+                true => {
+                    assert_eq!(interface["socketPath"], "/run/test.sock");
+                    assert_eq!(interface["pciID"], "0000:00:00.0");
+                    assert_eq!(route["advmss"], 1380);
+                    assert_eq!(route["table"], 129518285);
+                }
+                false => {
+                    for field in ["socketPath", "pciID"] {
+                        assert!(interface.get(field).is_none(), "{} {}", version, field);
+                    }
+                    for field in ["mtu", "advmss", "priority", "table", "scope"] {
+                        assert!(route.get(field).is_none(), "{} {}", version, field);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reports_errors_in_the_requested_version() {
+        for version in SUPPORTED_VERSIONS {
+            let err = CniError::new(ErrorCode::TunnelDrift, "drift", None)
+                .for_version(&version.to_string());
+            assert_eq!(err.cni_version, version.to_string());
+        }
+
+        let err = CniError::new(ErrorCode::TunnelDrift, "drift", None).for_version("0.2.0");
+        assert_eq!(err.cni_version, "1.1.0");
+    }
+}
@@ -0,0 +1,328 @@
+use crate::{
+    overlay::{driver::OverlayDriver, workflows::OverlayTunnel},
+    settings::{DEFAULT_INTERFACE_NAME, Settings},
+    wireguard::{
+        driver::WireguardDriver,
+        workflows::{WireguardTunnel, advmss},
+    },
+};
+
+use std::{
+    fmt,
+    future::Future,
+    net::{Ipv4Addr, SocketAddr},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DriverKind {
+    #[default]
+    Wireguard,
+    Overlay,
+}
+
+impl DriverKind {
+    pub fn default_interface_name(&self) -> &'static str {
+        match self {
+            DriverKind::Wireguard => DEFAULT_INTERFACE_NAME,
+            DriverKind::Overlay => "ovl0",
+        }
+    }
+}
+
+impl FromStr for DriverKind {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        match value {
+            "wireguard" => Ok(DriverKind::Wireguard),
+            "overlay" => Ok(DriverKind::Overlay),
+            _ => Err(anyhow!("unknown tunnel driver {:?}", value)),
+        }
+    }
+}
+
+impl fmt::Display for DriverKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DriverKind::Wireguard => write!(f, "wireguard"),
+            DriverKind::Overlay => write!(f, "overlay"),
+        }
+    }
+}
+
+// What a driver configured in the Pod's netns. It's reported in the CNI
+// result and cached, so that CHECK can verify the tunnel against it later.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ConfiguredInterface {
+    pub name: String,
+    pub address: Ipv4Addr,
+    pub prefix: u8,
+    pub mtu: Option<usize>,
+    pub mac: Option<String>,
+    pub routes: Vec<String>,
+    pub dns: Vec<String>,
+    #[serde(flatten)]
+    pub tunnel: Tunnel,
+}
+
+impl ConfiguredInterface {
+    // the MSS advertised on the routes through the tunnel
+    pub fn advmss(&self) -> Option<usize> {
+        match &self.tunnel {
+            Tunnel::Wireguard(tunnel) => {
+                let mtu = self.mtu.and_then(|mtu| u32::try_from(mtu).ok());
+                advmss(tunnel.mss_clamping, mtu).map(|advmss| advmss as usize)
+            }
+            Tunnel::Overlay(_) => None,
+        }
+    }
+}
+
+// The driver specific part of a configured interface. Cached results carry
+// no tag, wireguard tunnels are told apart by their peers. Overlay results
+// cached before this existed read as wireguard ones, which the overlay
+// driver treats like a missing result.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Tunnel {
+    Wireguard(WireguardTunnel),
+    Overlay(OverlayTunnel),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TunnelStats {
+    pub interface: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub peers: Vec<PeerStats>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PeerStats {
+    pub peer: String,
+    pub endpoint: Option<SocketAddr>,
+    pub last_handshake: Option<SystemTime>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+// A tunnel inside the netns of a Pod. Drivers fetch whatever they need for
+// the Pod from the API themselves, so callers only deal with the Pod, its
+// netns and the settings from the network config.
+pub trait TunnelDriver {
+    // Returns None when the Pod has no tunnel configured.
+    fn create(
+        &self,
+        name: &str,
+        namespace: &str,
+        netns: &str,
+        pod_address: &Ipv4Addr,
+        settings: &Settings,
+    ) -> impl Future<Output = anyhow::Result<Option<ConfiguredInterface>>> + Send;
+
+    // Brings a running tunnel in line with the desired state and returns what
+    // had to change.
+    fn update(
+        &self,
+        name: &str,
+        namespace: &str,
+        netns: &str,
+        settings: &Settings,
+    ) -> impl Future<Output = anyhow::Result<Vec<String>>> + Send;
+
+    // Returns how the tunnel drifted from expected, or from the desired state
+    // when there is nothing to expect.
+    fn verify(
+        &self,
+        name: &str,
+        namespace: &str,
+        netns: &str,
+        expected: Option<&ConfiguredInterface>,
+        settings: &Settings,
+    ) -> impl Future<Output = anyhow::Result<Vec<String>>> + Send;
+
+    fn delete(
+        &self,
+        name: &str,
+        namespace: &str,
+        netns: Option<&str>,
+        pod_address: Option<Ipv4Addr>,
+        settings: &Settings,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    fn stats(
+        &self,
+        netns: &str,
+        settings: &Settings,
+    ) -> impl Future<Output = anyhow::Result<TunnelStats>> + Send;
+}
+
+// The driver picked by the network config. Dispatching through an enum keeps
+// the trait free of boxed futures.
+#[derive(Clone, Copy, Debug)]
+pub enum Driver {
+    Wireguard(WireguardDriver),
+    Overlay(OverlayDriver),
+}
+
+impl From<DriverKind> for Driver {
+    fn from(kind: DriverKind) -> Self {
+        match kind {
+            DriverKind::Wireguard => Driver::Wireguard(WireguardDriver),
+            DriverKind::Overlay => Driver::Overlay(OverlayDriver),
+        }
+    }
+}
+
+impl TunnelDriver for Driver {
+    async fn create(
+        &self,
+        name: &str,
+        namespace: &str,
+        netns: &str,
+        pod_address: &Ipv4Addr,
+        settings: &Settings,
+    ) -> anyhow::Result<Option<ConfiguredInterface>> {
+        match self {
+            Driver::Wireguard(driver) => {
+                driver
+                    .create(name, namespace, netns, pod_address, settings)
+                    .await
+            }
+            Driver::Overlay(driver) => {
+                driver
+                    .create(name, namespace, netns, pod_address, settings)
+                    .await
+            }
+        }
+    }
+
+    async fn update(
+        &self,
+        name: &str,
+        namespace: &str,
+        netns: &str,
+        settings: &Settings,
+    ) -> anyhow::Result<Vec<String>> {
+        match self {
+            Driver::Wireguard(driver) => driver.update(name, namespace, netns, settings).await,
+            Driver::Overlay(driver) => driver.update(name, namespace, netns, settings).await,
+        }
+    }
+
+    async fn verify(
+        &self,
+        name: &str,
+        namespace: &str,
+        netns: &str,
+        expected: Option<&ConfiguredInterface>,
+        settings: &Settings,
+    ) -> anyhow::Result<Vec<String>> {
+        match self {
+            Driver::Wireguard(driver) => {
+                driver
+                    .verify(name, namespace, netns, expected, settings)
+                    .await
+            }
+            Driver::Overlay(driver) => {
+                driver
+                    .verify(name, namespace, netns, expected, settings)
+                    .await
+            }
+        }
+    }
+
+    async fn delete(
+        &self,
+        name: &str,
+        namespace: &str,
+        netns: Option<&str>,
+        pod_address: Option<Ipv4Addr>,
+        settings: &Settings,
+    ) -> anyhow::Result<()> {
+        match self {
+            Driver::Wireguard(driver) => {
+                driver
+                    .delete(name, namespace, netns, pod_address, settings)
+                    .await
+            }
+            Driver::Overlay(driver) => {
+                driver
+                    .delete(name, namespace, netns, pod_address, settings)
+                    .await
+            }
+        }
+    }
+
+    async fn stats(&self, netns: &str, settings: &Settings) -> anyhow::Result<TunnelStats> {
+        match self {
+            Driver::Wireguard(driver) => driver.stats(netns, settings).await,
+            Driver::Overlay(driver) => driver.stats(netns, settings).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use k8s_openapi::serde_json::{self, json};
+
+    #[test]
+    fn reads_wireguard_results_cached_before_tunnels() {
+        let cached = json!({
+            "name": "wg0",
+            "address": "10.0.0.1",
+            "prefix": 24,
+            "listen_port": 51820,
+            "public_key": "key",
+            "peers": [],
+            "mtu": 1420,
+            "mac": null,
+            "routes": [],
+            "dns": [],
+            "mss_clamping": true,
+        });
+
+        let interface: ConfiguredInterface = serde_json::from_value(cached.clone()).unwrap();
+        let Tunnel::Wireguard(tunnel) = &interface.tunnel else {
+            panic!("expected a wireguard tunnel, got {:?}", interface.tunnel);
+        };
+        assert_eq!(tunnel.listen_port, 51820);
+        assert_eq!(tunnel.public_key.as_deref(), Some("key"));
+        assert_eq!(tunnel.routing_mode, None);
+        assert!(tunnel.mss_clamping);
+        assert_eq!(interface.advmss(), Some(1380));
+        assert_eq!(serde_json::to_value(&interface).unwrap(), cached);
+    }
+
+    #[test]
+    fn reads_overlay_results() {
+        let cached = json!({
+            "name": "ovl0",
+            "address": "10.0.0.1",
+            "prefix": 24,
+            "listen_port": 4789,
+            "remotes": ["192.168.1.2"],
+            "mtu": 1450,
+            "mac": "02:00:00:00:00:01",
+            "routes": [],
+            "dns": [],
+        });
+
+        let interface: ConfiguredInterface = serde_json::from_value(cached.clone()).unwrap();
+        let Tunnel::Overlay(tunnel) = &interface.tunnel else {
+            panic!("expected an overlay tunnel, got {:?}", interface.tunnel);
+        };
+        assert_eq!(tunnel.listen_port, 4789);
+        assert_eq!(tunnel.remotes, vec![Ipv4Addr::new(192, 168, 1, 2)]);
+        assert_eq!(interface.advmss(), None);
+        assert_eq!(serde_json::to_value(&interface).unwrap(), cached);
+    }
+}
@@ -0,0 +1,143 @@
+#[cfg(test)]
+pub mod fake;
+
+use crate::{
+    system::netlink::{Address, FdbEntry, Link, LinkKind, Netlink, NetlinkError, Route, Rule},
+    wireguard::{
+        device::{Device, DeviceUpdate, WireguardControl},
+        userspace,
+    },
+};
+
+// Everything the workflows change in a netns goes through an Executor, so
+// the sequence of operations can be tested without root or real namespaces.
+// Like the netlink handle it wraps, an executor belongs to the netns it was
+// opened in.
+pub trait Executor {
+    fn add_link(&mut self, name: &str, kind: LinkKind) -> Result<(), NetlinkError>;
+    fn link(&mut self, name: &str) -> Result<Option<Link>, NetlinkError>;
+    fn set_link_up(&mut self, index: u32) -> Result<(), NetlinkError>;
+    fn set_link_mtu(&mut self, index: u32, mtu: u32) -> Result<(), NetlinkError>;
+    fn del_link(&mut self, index: u32) -> Result<(), NetlinkError>;
+
+    fn add_address(&mut self, index: u32, address: Address) -> Result<(), NetlinkError>;
+    fn del_address(&mut self, index: u32, address: Address) -> Result<(), NetlinkError>;
+    fn addresses(&mut self, index: u32) -> Result<Vec<Address>, NetlinkError>;
+
+    fn add_route(&mut self, route: &Route) -> Result<(), NetlinkError>;
+    fn del_route(&mut self, route: &Route) -> Result<(), NetlinkError>;
+    fn routes(&mut self) -> Result<Vec<Route>, NetlinkError>;
+
+    fn add_rule(&mut self, rule: &Rule) -> Result<(), NetlinkError>;
+    fn del_rule(&mut self, rule: &Rule) -> Result<(), NetlinkError>;
+    fn rules(&mut self) -> Result<Vec<Rule>, NetlinkError>;
+
+    fn add_fdb_entry(&mut self, index: u32, entry: &FdbEntry) -> Result<(), NetlinkError>;
+    fn del_fdb_entry(&mut self, index: u32, entry: &FdbEntry) -> Result<(), NetlinkError>;
+    fn fdb_entries(&mut self, index: u32) -> Result<Vec<FdbEntry>, NetlinkError>;
+
+    fn get_device(&mut self, name: &str) -> anyhow::Result<Device>;
+    fn set_device(&mut self, name: &str, update: &DeviceUpdate) -> anyhow::Result<()>;
+
+    // Creates the interface as a userspace WireGuard device, for kernels
+    // without the module.
+    fn spawn_userspace_device(&mut self, netns: &str, name: &str) -> anyhow::Result<()>;
+}
+
+// Applies operations to the netns of the calling thread.
+pub struct SystemExecutor {
+    netlink: Netlink,
+}
+
+impl SystemExecutor {
+    pub fn open() -> Result<Self, NetlinkError> {
+        Ok(SystemExecutor {
+            netlink: Netlink::open()?,
+        })
+    }
+}
+
+impl Executor for SystemExecutor {
+    fn add_link(&mut self, name: &str, kind: LinkKind) -> Result<(), NetlinkError> {
+        self.netlink.add_link(name, kind)
+    }
+
+    fn link(&mut self, name: &str) -> Result<Option<Link>, NetlinkError> {
+        self.netlink.link(name)
+    }
+
+    fn set_link_up(&mut self, index: u32) -> Result<(), NetlinkError> {
+        self.netlink.set_link_up(index)
+    }
+
+    fn set_link_mtu(&mut self, index: u32, mtu: u32) -> Result<(), NetlinkError> {
+        self.netlink.set_link_mtu(index, mtu)
+    }
+
+    fn del_link(&mut self, index: u32) -> Result<(), NetlinkError> {
+        self.netlink.del_link(index)
+    }
+
+    fn add_address(&mut self, index: u32, address: Address) -> Result<(), NetlinkError> {
+        self.netlink.add_address(index, address)
+    }
+
+    fn del_address(&mut self, index: u32, address: Address) -> Result<(), NetlinkError> {
+        self.netlink.del_address(index, address)
+    }
+
+    fn addresses(&mut self, index: u32) -> Result<Vec<Address>, NetlinkError> {
+        self.netlink.addresses(index)
+    }
+
+    fn add_route(&mut self, route: &Route) -> Result<(), NetlinkError> {
+        self.netlink.add_route(route)
+    }
+
+    fn del_route(&mut self, route: &Route) -> Result<(), NetlinkError> {
+        self.netlink.del_route(route)
+    }
+
+    fn routes(&mut self) -> Result<Vec<Route>, NetlinkError> {
+        self.netlink.routes()
+    }
+
+    fn add_rule(&mut self, rule: &Rule) -> Result<(), NetlinkError> {
+        self.netlink.add_rule(rule)
+    }
+
+    fn del_rule(&mut self, rule: &Rule) -> Result<(), NetlinkError> {
+        self.netlink.del_rule(rule)
+    }
+
+    fn rules(&mut self) -> Result<Vec<Rule>, NetlinkError> {
+        self.netlink.rules()
+    }
+
+    fn add_fdb_entry(&mut self, index: u32, entry: &FdbEntry) -> Result<(), NetlinkError> {
+        self.netlink.add_fdb_entry(index, entry)
+    }
+
+    fn del_fdb_entry(&mut self, index: u32, entry: &FdbEntry) -> Result<(), NetlinkError> {
+        self.netlink.del_fdb_entry(index, entry)
+    }
+
+    fn fdb_entries(&mut self, index: u32) -> Result<Vec<FdbEntry>, NetlinkError> {
+        self.netlink.fdb_entries(index)
+    }
+
+    // the device is opened on every call, the wireguard family only exists
+    // with the module loaded and a userspace device may have been started
+    // since
+    fn get_device(&mut self, name: &str) -> anyhow::Result<Device> {
+        WireguardControl::open(name)?.get_device(name)
+    }
+
+    fn set_device(&mut self, name: &str, update: &DeviceUpdate) -> anyhow::Result<()> {
+        WireguardControl::open(name)?.set_device(name, update)
+    }
+
+    fn spawn_userspace_device(&mut self, netns: &str, name: &str) -> anyhow::Result<()> {
+        userspace::spawn(netns, name)
+    }
+}
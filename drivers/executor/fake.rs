@@ -0,0 +1,456 @@
+use crate::{
+    executor::Executor,
+    system::netlink::{Address, FdbEntry, Link, LinkKind, NetlinkError, Route, Rule},
+    wireguard::device::{Device, DeviceUpdate, Peer},
+};
+
+use std::{collections::HashMap, fmt::Write, io};
+
+use anyhow::anyhow;
+
+// An in-memory netns which records every change applied to it, in order.
+// Reads are answered from the state those changes built up and aren't
+// recorded, so that reconcile and verify see what apply left behind and
+// tests only pin down what was changed.
+#[derive(Debug, Default)]
+pub struct RecordingExecutor {
+    pub operations: Vec<String>,
+    pub links: Vec<Link>,
+    pub addresses: Vec<(u32, Address)>,
+    pub routes: Vec<Route>,
+    pub rules: Vec<Rule>,
+    pub fdb_entries: Vec<(u32, FdbEntry)>,
+    pub devices: HashMap<String, Device>,
+    // adding a wireguard link fails like on kernels without the module
+    pub without_wireguard_module: bool,
+    failures: Vec<String>,
+}
+
+impl RecordingExecutor {
+    // The next operation starting with the given prefix is recorded and then
+    // fails with EPERM.
+    pub fn fail(&mut self, operation: &str) {
+        self.failures.push(operation.to_string());
+    }
+
+    pub fn take_operations(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.operations)
+    }
+
+    fn record(&mut self, operation: String) -> Result<(), NetlinkError> {
+        let failure = self
+            .failures
+            .iter()
+            .position(|failure| operation.starts_with(failure.as_str()));
+        self.operations.push(operation);
+        match failure {
+            Some(failure) => {
+                self.failures.remove(failure);
+                Err(NetlinkError::Kernel(io::Error::from_raw_os_error(
+                    libc::EPERM,
+                )))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn create_link(&mut self, name: &str) -> Result<u32, NetlinkError> {
+        if self.links.iter().any(|link| link.name == name) {
+            return Err(NetlinkError::Exists);
+        }
+        // 1 is taken by the loopback interface
+        let index = self.links.iter().map(|link| link.index).max().unwrap_or(1) + 1;
+        self.links.push(Link {
+            index,
+            name: name.to_string(),
+            up: false,
+            mtu: Some(1420),
+            mac: None,
+            rx_bytes: 0,
+            tx_bytes: 0,
+        });
+        Ok(index)
+    }
+
+    fn create_device(&mut self, name: &str, index: u32) {
+        self.devices.insert(
+            name.to_string(),
+            Device {
+                index,
+                name: name.to_string(),
+                ..Device::default()
+            },
+        );
+    }
+
+    fn has_link(&self, index: u32) -> bool {
+        self.links.iter().any(|link| link.index == index)
+    }
+}
+
+impl Executor for RecordingExecutor {
+    fn add_link(&mut self, name: &str, kind: LinkKind) -> Result<(), NetlinkError> {
+        self.record(format!("add_link {} {:?}", name, kind))?;
+        if kind == LinkKind::Wireguard && self.without_wireguard_module {
+            return Err(NetlinkError::Kernel(io::Error::from_raw_os_error(
+                libc::EOPNOTSUPP,
+            )));
+        }
+        let index = self.create_link(name)?;
+        if kind == LinkKind::Wireguard {
+            self.create_device(name, index);
+        }
+        Ok(())
+    }
+
+    fn link(&mut self, name: &str) -> Result<Option<Link>, NetlinkError> {
+        Ok(self.links.iter().find(|link| link.name == name).cloned())
+    }
+
+    fn set_link_up(&mut self, index: u32) -> Result<(), NetlinkError> {
+        self.record(format!("set_link_up {}", index))?;
+        let link = self
+            .links
+            .iter_mut()
+            .find(|link| link.index == index)
+            .ok_or(NetlinkError::NoDevice)?;
+        link.up = true;
+        Ok(())
+    }
+
+    fn set_link_mtu(&mut self, index: u32, mtu: u32) -> Result<(), NetlinkError> {
+        self.record(format!("set_link_mtu {} {}", index, mtu))?;
+        let link = self
+            .links
+            .iter_mut()
+            .find(|link| link.index == index)
+            .ok_or(NetlinkError::NoDevice)?;
+        link.mtu = Some(mtu);
+        Ok(())
+    }
+
+    // like in the kernel, everything on the link goes with it
+    fn del_link(&mut self, index: u32) -> Result<(), NetlinkError> {
+        self.record(format!("del_link {}", index))?;
+        let position = self
+            .links
+            .iter()
+            .position(|link| link.index == index)
+            .ok_or(NetlinkError::NoDevice)?;
+        let link = self.links.remove(position);
+        self.addresses.retain(|(link, _)| *link != index);
+        self.routes.retain(|route| route.interface != Some(index));
+        self.fdb_entries.retain(|(link, _)| *link != index);
+        self.devices.remove(&link.name);
+        Ok(())
+    }
+
+    fn add_address(&mut self, index: u32, address: Address) -> Result<(), NetlinkError> {
+        self.record(format!(
+            "add_address {} {}/{}",
+            index, address.address, address.prefix
+        ))?;
+        if !self.has_link(index) {
+            return Err(NetlinkError::NoDevice);
+        }
+        if self.addresses.contains(&(index, address)) {
+            return Err(NetlinkError::Exists);
+        }
+        self.addresses.push((index, address));
+        Ok(())
+    }
+
+    fn del_address(&mut self, index: u32, address: Address) -> Result<(), NetlinkError> {
+        self.record(format!(
+            "del_address {} {}/{}",
+            index, address.address, address.prefix
+        ))?;
+        let position = self
+            .addresses
+            .iter()
+            .position(|existing| *existing == (index, address))
+            .ok_or(NetlinkError::NotFound)?;
+        self.addresses.remove(position);
+        Ok(())
+    }
+
+    fn addresses(&mut self, index: u32) -> Result<Vec<Address>, NetlinkError> {
+        Ok(self
+            .addresses
+            .iter()
+            .filter(|(link, _)| *link == index)
+            .map(|(_, address)| *address)
+            .collect())
+    }
+
+    fn add_route(&mut self, route: &Route) -> Result<(), NetlinkError> {
+        self.record(format!("add_route {}", describe_route(route)))?;
+        if route.interface.is_some_and(|index| !self.has_link(index)) {
+            return Err(NetlinkError::NoDevice);
+        }
+        if self.routes.contains(route) {
+            return Err(NetlinkError::Exists);
+        }
+        self.routes.push(route.clone());
+        Ok(())
+    }
+
+    fn del_route(&mut self, route: &Route) -> Result<(), NetlinkError> {
+        self.record(format!("del_route {}", describe_route(route)))?;
+        let position = self
+            .routes
+            .iter()
+            .position(|existing| existing == route)
+            .ok_or(NetlinkError::NotFound)?;
+        self.routes.remove(position);
+        Ok(())
+    }
+
+    fn routes(&mut self) -> Result<Vec<Route>, NetlinkError> {
+        Ok(self.routes.clone())
+    }
+
+    fn add_rule(&mut self, rule: &Rule) -> Result<(), NetlinkError> {
+        self.record(format!("add_rule {}", describe_rule(rule)))?;
+        if self.rules.contains(rule) {
+            return Err(NetlinkError::Exists);
+        }
+        self.rules.push(rule.clone());
+        Ok(())
+    }
+
+    fn del_rule(&mut self, rule: &Rule) -> Result<(), NetlinkError> {
+        self.record(format!("del_rule {}", describe_rule(rule)))?;
+        let position = self
+            .rules
+            .iter()
+            .position(|existing| existing == rule)
+            .ok_or(NetlinkError::NotFound)?;
+        self.rules.remove(position);
+        Ok(())
+    }
+
+    fn rules(&mut self) -> Result<Vec<Rule>, NetlinkError> {
+        Ok(self.rules.clone())
+    }
+
+    fn add_fdb_entry(&mut self, index: u32, entry: &FdbEntry) -> Result<(), NetlinkError> {
+        self.record(format!(
+            "add_fdb_entry {} {}",
+            index,
+            describe_fdb_entry(entry)
+        ))?;
+        if !self.has_link(index) {
+            return Err(NetlinkError::NoDevice);
+        }
+        if !self.fdb_entries.contains(&(index, *entry)) {
+            self.fdb_entries.push((index, *entry));
+        }
+        Ok(())
+    }
+
+    fn del_fdb_entry(&mut self, index: u32, entry: &FdbEntry) -> Result<(), NetlinkError> {
+        self.record(format!(
+            "del_fdb_entry {} {}",
+            index,
+            describe_fdb_entry(entry)
+        ))?;
+        self.fdb_entries
+            .retain(|existing| *existing != (index, *entry));
+        Ok(())
+    }
+
+    fn fdb_entries(&mut self, index: u32) -> Result<Vec<FdbEntry>, NetlinkError> {
+        Ok(self
+            .fdb_entries
+            .iter()
+            .filter(|(link, _)| *link == index)
+            .map(|(_, entry)| *entry)
+            .collect())
+    }
+
+    fn get_device(&mut self, name: &str) -> anyhow::Result<Device> {
+        self.devices
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("no wireguard device {}", name))
+    }
+
+    fn set_device(&mut self, name: &str, update: &DeviceUpdate) -> anyhow::Result<()> {
+        self.record(format!("set_device {} {}", name, describe_update(update)))?;
+        let device = self
+            .devices
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("no wireguard device {}", name))?;
+        apply_update(device, update);
+        Ok(())
+    }
+
+    fn spawn_userspace_device(&mut self, _netns: &str, name: &str) -> anyhow::Result<()> {
+        self.record(format!("spawn_userspace_device {}", name))?;
+        let index = self.create_link(name)?;
+        self.create_device(name, index);
+        Ok(())
+    }
+}
+
+// the semantics of WG_CMD_SET_DEVICE
+fn apply_update(device: &mut Device, update: &DeviceUpdate) {
+    if let Some(private_key) = &update.private_key {
+        device.public_key = Some(private_key.public_key());
+        device.private_key = Some(private_key.clone());
+    }
+    if let Some(listen_port) = update.listen_port {
+        device.listen_port = listen_port;
+    }
+    if let Some(fwmark) = update.fwmark {
+        device.fwmark = fwmark;
+    }
+    if update.replace_peers {
+        device.peers.clear();
+    }
+
+    for peer_update in &update.peers {
+        let existing = device
+            .peers
+            .iter()
+            .position(|peer| peer.public_key == peer_update.public_key);
+        let peer = match existing {
+            Some(position) if peer_update.remove => {
+                device.peers.remove(position);
+                continue;
+            }
+            Some(position) => &mut device.peers[position],
+            None if peer_update.remove || peer_update.update_only => continue,
+            None => {
+                device.peers.push(Peer {
+                    public_key: peer_update.public_key,
+                    preshared_key: None,
+                    endpoint: None,
+                    allowed_ips: vec![],
+                    persistent_keepalive: 0,
+                    last_handshake: None,
+                    rx_bytes: 0,
+                    tx_bytes: 0,
+                });
+                device.peers.last_mut().expect("a peer was just added")
+            }
+        };
+        if let Some(preshared_key) = &peer_update.preshared_key {
+            peer.preshared_key = Some(preshared_key.clone()).filter(|key| !key.is_zero());
+        }
+        if peer_update.endpoint.is_some() {
+            peer.endpoint = peer_update.endpoint;
+        }
+        if let Some(persistent_keepalive) = peer_update.persistent_keepalive {
+            peer.persistent_keepalive = persistent_keepalive;
+        }
+        if peer_update.replace_allowed_ips {
+            peer.allowed_ips.clear();
+        }
+        for allowed_ip in &peer_update.allowed_ips {
+            if !peer.allowed_ips.contains(allowed_ip) {
+                peer.allowed_ips.push(*allowed_ip);
+            }
+        }
+    }
+}
+
+fn describe_address(address: &Address) -> String {
+    format!("{}/{}", address.address, address.prefix)
+}
+
+fn describe_route(route: &Route) -> String {
+    let destination = route
+        .destination
+        .as_ref()
+        .map_or("default".to_string(), describe_address);
+    let mut description = match route.interface {
+        Some(interface) => format!("{} dev {} table {}", destination, interface, route.table),
+        None => format!("{} table {}", destination, route.table),
+    };
+    if let Some(advmss) = route.advmss {
+        let _ = write!(description, " advmss {}", advmss);
+    }
+    description
+}
+
+fn describe_rule(rule: &Rule) -> String {
+    let mut description = format!("priority {}", rule.priority);
+    if let Some(destination) = &rule.destination {
+        let _ = write!(description, " to {}", describe_address(destination));
+    }
+    if rule.fwmark != 0 {
+        let _ = write!(description, " fwmark {}", rule.fwmark);
+    }
+    let _ = write!(description, " table {}", rule.table);
+    if let Some(suppress_prefixlength) = rule.suppress_prefixlength {
+        let _ = write!(
+            description,
+            " suppress_prefixlength {}",
+            suppress_prefixlength
+        );
+    }
+    description
+}
+
+fn describe_fdb_entry(entry: &FdbEntry) -> String {
+    let mac: Vec<String> = entry
+        .mac
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("{} dst {}", mac.join(":"), entry.destination)
+}
+
+// private and preshared keys are only ever reported as being set
+fn describe_update(update: &DeviceUpdate) -> String {
+    let mut description = vec![];
+    if update.private_key.is_some() {
+        description.push("private_key".to_string());
+    }
+    if let Some(listen_port) = update.listen_port {
+        description.push(format!("listen_port={}", listen_port));
+    }
+    if let Some(fwmark) = update.fwmark {
+        description.push(format!("fwmark={}", fwmark));
+    }
+    if update.replace_peers {
+        description.push("replace_peers".to_string());
+    }
+    for peer in &update.peers {
+        let mut peer_description = format!("peer={}", peer.public_key);
+        if peer.remove {
+            peer_description.push_str(" remove");
+        }
+        if peer.update_only {
+            peer_description.push_str(" update_only");
+        }
+        match &peer.preshared_key {
+            Some(preshared_key) if preshared_key.is_zero() => {
+                peer_description.push_str(" preshared_key=none")
+            }
+            Some(_) => peer_description.push_str(" preshared_key"),
+            None => {}
+        }
+        if let Some(endpoint) = peer.endpoint {
+            let _ = write!(peer_description, " endpoint={}", endpoint);
+        }
+        if let Some(persistent_keepalive) = peer.persistent_keepalive {
+            let _ = write!(
+                peer_description,
+                " persistent_keepalive={}",
+                persistent_keepalive
+            );
+        }
+        if peer.replace_allowed_ips {
+            peer_description.push_str(" replace_allowed_ips");
+        }
+        if !peer.allowed_ips.is_empty() {
+            let allowed_ips: Vec<String> = peer.allowed_ips.iter().map(describe_address).collect();
+            let _ = write!(peer_description, " allowed_ips={}", allowed_ips.join(","));
+        }
+        description.push(peer_description);
+    }
+    description.join(" ")
+}
@@ -0,0 +1,49 @@
+use crate::settings::Settings;
+
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::Context;
+use kube::{
+    Client as KubeClient,
+    config::{KubeConfigOptions, Kubeconfig},
+};
+use thiserror::Error;
+
+// how long drivers wait between polls of a config which isn't ready yet
+pub(crate) const INITIAL_READINESS_BACKOFF: Duration = Duration::from_millis(250);
+pub(crate) const MAX_READINESS_BACKOFF: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Error)]
+#[error("{kind} {name} was not ready within {timeout:?}")]
+pub struct NotReady {
+    pub kind: &'static str,
+    pub name: String,
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Error)]
+#[error("podtunnel kubeconfig {} does not exist, it is written by the podtunnel agent", kubeconfig.display())]
+pub struct MissingCredentials {
+    pub kubeconfig: PathBuf,
+}
+
+// The plugin only ever authenticates with the dedicated kubeconfig written by
+// the agent, never with whatever credentials happen to be on the node.
+pub(crate) async fn kube_client(settings: &Settings) -> anyhow::Result<KubeClient> {
+    if !settings.kubeconfig.exists() {
+        return Err(MissingCredentials {
+            kubeconfig: settings.kubeconfig.clone(),
+        }
+        .into());
+    }
+
+    let kubeconfig = Kubeconfig::read_from(&settings.kubeconfig).with_context(|| {
+        format!(
+            "failed to read kubeconfig {}",
+            settings.kubeconfig.display()
+        )
+    })?;
+    let config =
+        kube::Config::from_custom_kubeconfig(kubeconfig, &KubeConfigOptions::default()).await?;
+    Ok(KubeClient::try_from(config)?)
+}
@@ -1,2 +1,8 @@
+pub mod driver;
+pub mod executor;
+pub mod kube;
+pub mod overlay;
+pub mod settings;
 pub mod system;
+pub mod transaction;
 pub mod wireguard;
@@ -0,0 +1,68 @@
+use crate::{
+    driver::{ConfiguredInterface, Tunnel, TunnelDriver, TunnelStats},
+    overlay::workflows::{
+        configure_overlay_for_pod, overlay_stats, reconcile_overlay_for_pod,
+        teardown_overlay_for_pod, verify_overlay_for_pod, verify_overlay_interface,
+    },
+    settings::Settings,
+};
+
+use std::net::Ipv4Addr;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OverlayDriver;
+
+impl TunnelDriver for OverlayDriver {
+    async fn create(
+        &self,
+        name: &str,
+        namespace: &str,
+        netns: &str,
+        pod_address: &Ipv4Addr,
+        settings: &Settings,
+    ) -> anyhow::Result<Option<ConfiguredInterface>> {
+        configure_overlay_for_pod(name, namespace, netns, pod_address, settings).await
+    }
+
+    async fn update(
+        &self,
+        name: &str,
+        namespace: &str,
+        netns: &str,
+        settings: &Settings,
+    ) -> anyhow::Result<Vec<String>> {
+        reconcile_overlay_for_pod(name, namespace, netns, settings).await
+    }
+
+    async fn verify(
+        &self,
+        name: &str,
+        namespace: &str,
+        netns: &str,
+        expected: Option<&ConfiguredInterface>,
+        settings: &Settings,
+    ) -> anyhow::Result<Vec<String>> {
+        // results cached by the other driver are verified like missing ones
+        match expected {
+            Some(expected) if matches!(expected.tunnel, Tunnel::Overlay(_)) => {
+                verify_overlay_interface(netns, expected, settings)
+            }
+            _ => verify_overlay_for_pod(name, namespace, netns, settings).await,
+        }
+    }
+
+    async fn delete(
+        &self,
+        name: &str,
+        namespace: &str,
+        netns: Option<&str>,
+        pod_address: Option<Ipv4Addr>,
+        settings: &Settings,
+    ) -> anyhow::Result<()> {
+        teardown_overlay_for_pod(name, namespace, netns, pod_address, settings).await
+    }
+
+    async fn stats(&self, netns: &str, settings: &Settings) -> anyhow::Result<TunnelStats> {
+        overlay_stats(netns, settings)
+    }
+}
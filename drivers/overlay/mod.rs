@@ -0,0 +1,3 @@
+pub mod driver;
+pub mod status;
+pub mod workflows;
@@ -0,0 +1,36 @@
+use crate::{kube::kube_client, settings::Settings};
+use api::overlay::OverlayConfig;
+
+use kube::{Api, Error as KubeError, api::ListParams};
+use thiserror::Error;
+use tracing::info;
+
+// The vxlan and geneve modules are loaded on demand when the first interface
+// is added, so unlike wireguard there is no module to check for.
+#[derive(Debug, Error)]
+pub enum NotAvailable {
+    #[error("failed to load kubeconfig: {0:#}")]
+    Kubeconfig(anyhow::Error),
+    #[error("the OverlayConfig CRD is not installed")]
+    MissingCrds,
+    #[error("the Kubernetes API is unreachable: {0}")]
+    ApiUnreachable(KubeError),
+}
+
+pub async fn plugin_status(settings: &Settings) -> Result<(), NotAvailable> {
+    info!("checking the kubeconfig");
+    let kube_client = kube_client(settings)
+        .await
+        .map_err(NotAvailable::Kubeconfig)?;
+
+    info!("checking the Kubernetes API");
+    let overlay_configs: Api<OverlayConfig> = Api::all(kube_client);
+    match overlay_configs
+        .list_metadata(&ListParams::default().limit(1))
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(KubeError::Api(api_err)) if api_err.code == 404 => Err(NotAvailable::MissingCrds),
+        Err(err) => Err(NotAvailable::ApiUnreachable(err)),
+    }
+}
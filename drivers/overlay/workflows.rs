@@ -0,0 +1,935 @@
+use crate::{
+    driver::{ConfiguredInterface, Tunnel, TunnelStats},
+    executor::{Executor, SystemExecutor},
+    kube::{INITIAL_READINESS_BACKOFF, MAX_READINESS_BACKOFF, NotReady, kube_client},
+    settings::Settings,
+    system::{
+        netlink::{Address, FdbEntry, Link, LinkKind, Netlink, ignore_not_found},
+        netns::run_in_netns,
+    },
+    transaction::{StepFailed, Transaction, Undo},
+};
+use api::overlay::{
+    Encapsulation, OverlayConfig, OverlayConfigStatus, OverlayInterface, OverlayPeerConfig,
+};
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use anyhow::{Context, anyhow};
+use k8s_openapi::serde_json::json;
+use kube::{
+    Api, Client as KubeClient, Error as KubeError,
+    api::{Patch, PatchParams},
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::{Instant, sleep};
+use tracing::info;
+
+// What the overlay driver configured besides the interface itself. VXLAN
+// interfaces flood to the remotes, GENEVE ones have theirs in the link.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OverlayTunnel {
+    pub listen_port: u16,
+    pub remotes: Vec<Ipv4Addr>,
+}
+
+// VNIs are 24 bits wide for both VXLAN and GENEVE
+const MAX_VNI: u32 = (1 << 24) - 1;
+
+// broadcast and unknown unicast traffic is flooded to every remote
+const FLOOD_MAC: [u8; 6] = [0; 6];
+
+pub async fn configure_overlay_for_pod(
+    name: &str,
+    namespace: &str,
+    netns: &str,
+    pod_ip: &Ipv4Addr,
+    settings: &Settings,
+) -> anyhow::Result<Option<ConfiguredInterface>> {
+    let kube_client = kube_client(settings).await?;
+
+    info!("finding OverlayConfig for Pod {}", name);
+    let overlay_config = match get_overlay_config(
+        &kube_client,
+        namespace,
+        name,
+        pod_ip,
+        settings.readiness_timeout,
+    )
+    .await?
+    {
+        Some(overlay_config) => overlay_config,
+        None => return Ok(None),
+    };
+
+    let interface = &overlay_config.spec.interface;
+    let status = overlay_config
+        .status
+        .as_ref()
+        .context("OverlayConfig has no status")?;
+    let address = tunnel_address(status)?;
+    let remotes = endpoint_addresses(&status.peers);
+    let kind = link_kind(interface, &remotes)?;
+
+    let container_netns_file = File::open(netns)?;
+    let link = run_in_netns(&container_netns_file, || {
+        apply_overlay_state(
+            &mut SystemExecutor::open()?,
+            kind,
+            address,
+            &remotes,
+            settings,
+        )
+    })?;
+
+    Ok(Some(ConfiguredInterface {
+        name: settings.interface_name.clone(),
+        address: host_address(address),
+        prefix: address.prefix,
+        mtu: link.mtu.map(|mtu| mtu as usize),
+        mac: link.mac,
+        routes: vec![],
+        dns: vec![],
+        tunnel: Tunnel::Overlay(OverlayTunnel {
+            listen_port: interface.port(),
+            remotes,
+        }),
+    }))
+}
+
+// Either the whole configuration is applied, or the steps which succeeded
+// are undone again before the error is returned.
+fn apply_overlay_state(
+    executor: &mut impl Executor,
+    kind: LinkKind,
+    address: Address,
+    remotes: &[Ipv4Addr],
+    settings: &Settings,
+) -> anyhow::Result<Link> {
+    let mut transaction = Transaction::default();
+
+    let result = apply_overlay_steps(executor, &mut transaction, kind, address, remotes, settings);
+    if result.is_err() {
+        transaction.rollback(executor);
+    }
+    Ok(result?)
+}
+
+fn apply_overlay_steps(
+    executor: &mut impl Executor,
+    transaction: &mut Transaction,
+    kind: LinkKind,
+    address: Address,
+    remotes: &[Ipv4Addr],
+    settings: &Settings,
+) -> Result<Link, StepFailed> {
+    let interface_name = settings.interface_name.as_str();
+
+    let link = transaction.apply(
+        "add the overlay interface",
+        Some(Undo::DelLink(interface_name.to_string())),
+        || -> anyhow::Result<Link> {
+            executor.add_link(interface_name, kind)?;
+            executor
+                .link(interface_name)?
+                .context("overlay interface vanished after it was added")
+        },
+    )?;
+
+    transaction.apply(
+        format!("add address {}/{}", host_address(address), address.prefix),
+        Some(Undo::DelAddress(link.index, address)),
+        || executor.add_address(link.index, address),
+    )?;
+
+    // the FDB entries go away together with the interface
+    if let LinkKind::Vxlan { .. } = kind {
+        for &remote in remotes {
+            transaction.apply(format!("add the FDB entry for {}", remote), None, || {
+                executor.add_fdb_entry(link.index, &flood_entry(remote))
+            })?;
+        }
+    }
+
+    transaction.apply("bring the overlay interface up", None, || {
+        executor.set_link_up(link.index)
+    })?;
+
+    Ok(link)
+}
+
+pub async fn teardown_overlay_for_pod(
+    name: &str,
+    namespace: &str,
+    netns: Option<&str>,
+    pod_address: Option<Ipv4Addr>,
+    settings: &Settings,
+) -> anyhow::Result<()> {
+    match netns {
+        Some(netns) => teardown_overlay_interface(netns, settings)?,
+        None => info!(
+            "no netns provided for Pod {}, skipping interface teardown",
+            name
+        ),
+    }
+
+    let kube_client = kube_client(settings).await?;
+
+    info!("clearing pod_address for OverlayConfig {}", name);
+    clear_pod_address(&kube_client, namespace, name, pod_address).await
+}
+
+pub fn teardown_overlay_interface(netns: &str, settings: &Settings) -> anyhow::Result<()> {
+    let container_netns_file = match File::open(netns) {
+        Ok(container_netns_file) => container_netns_file,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            info!("pod netns {} is already gone, skipping", netns);
+            return Ok(());
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    run_in_netns(&container_netns_file, || {
+        remove_overlay_state(&mut SystemExecutor::open()?, settings)
+    })
+}
+
+fn remove_overlay_state(executor: &mut impl Executor, settings: &Settings) -> anyhow::Result<()> {
+    info!("removing the overlay interface");
+    if let Some(link) = executor.link(&settings.interface_name)? {
+        ignore_not_found(executor.del_link(link.index))?;
+    }
+    Ok(())
+}
+
+// Brings a running tunnel in line with the current OverlayConfig status and
+// returns what had to change. The VNI, port and encapsulation can't be changed
+// on a live interface, they only change together with the Pod.
+pub async fn reconcile_overlay_for_pod(
+    name: &str,
+    namespace: &str,
+    netns: &str,
+    settings: &Settings,
+) -> anyhow::Result<Vec<String>> {
+    let kube_client = kube_client(settings).await?;
+
+    info!("finding OverlayConfig for Pod {}", name);
+    let overlay_configs: Api<OverlayConfig> = Api::namespaced(kube_client, namespace);
+    let overlay_config = overlay_configs.get(name).await?;
+    let status = overlay_config
+        .status
+        .context("OverlayConfig has no status")?;
+    let address = tunnel_address(&status)?;
+    let remotes = endpoint_addresses(&status.peers);
+
+    if overlay_config.spec.interface.encapsulation == Encapsulation::Geneve {
+        // the remote is fixed when the link is created, this only checks
+        // that there still is exactly one
+        link_kind(&overlay_config.spec.interface, &remotes)?;
+    }
+
+    let container_netns_file = File::open(netns)?;
+    run_in_netns(&container_netns_file, || {
+        reconcile_overlay_state(
+            &mut SystemExecutor::open()?,
+            overlay_config.spec.interface.encapsulation,
+            address,
+            &remotes,
+            settings,
+        )
+    })
+}
+
+fn reconcile_overlay_state(
+    executor: &mut impl Executor,
+    encapsulation: Encapsulation,
+    address: Address,
+    remotes: &[Ipv4Addr],
+    settings: &Settings,
+) -> anyhow::Result<Vec<String>> {
+    let interface_name = settings.interface_name.as_str();
+    let mut changes = vec![];
+
+    let link = executor
+        .link(interface_name)?
+        .with_context(|| format!("interface {} does not exist", interface_name))?;
+
+    let addresses = executor.addresses(link.index)?;
+    if !addresses.contains(&address) {
+        executor.add_address(link.index, address)?;
+        changes.push(format!(
+            "added address {}/{}",
+            host_address(address),
+            address.prefix
+        ));
+    }
+    for stale in addresses
+        .into_iter()
+        .filter(|stale| stale.address.is_ipv4() && *stale != address)
+    {
+        ignore_not_found(executor.del_address(link.index, stale))?;
+        changes.push(format!(
+            "removed address {}/{}",
+            stale.address, stale.prefix
+        ));
+    }
+
+    if encapsulation == Encapsulation::Vxlan {
+        let desired: HashSet<FdbEntry> = remotes.iter().copied().map(flood_entry).collect();
+        let live: HashSet<FdbEntry> = executor
+            .fdb_entries(link.index)?
+            .into_iter()
+            .filter(|entry| entry.mac == FLOOD_MAC)
+            .collect();
+
+        for entry in desired.difference(&live) {
+            executor.add_fdb_entry(link.index, entry)?;
+            changes.push(format!("added the FDB entry for {}", entry.destination));
+        }
+        for entry in live.difference(&desired) {
+            ignore_not_found(executor.del_fdb_entry(link.index, entry))?;
+            changes.push(format!("removed the FDB entry for {}", entry.destination));
+        }
+    }
+
+    if !link.up {
+        executor.set_link_up(link.index)?;
+        changes.push(format!("brought interface {} up", interface_name));
+    }
+
+    Ok(changes)
+}
+
+pub async fn verify_overlay_for_pod(
+    name: &str,
+    namespace: &str,
+    netns: &str,
+    settings: &Settings,
+) -> anyhow::Result<Vec<String>> {
+    let kube_client = kube_client(settings).await?;
+
+    info!("finding OverlayConfig for Pod {}", name);
+    let overlay_configs: Api<OverlayConfig> = Api::namespaced(kube_client, namespace);
+    let overlay_config = match overlay_configs.get(name).await {
+        Ok(overlay_config) => overlay_config,
+        Err(KubeError::Api(api_err)) if api_err.code == 404 => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+
+    let status = overlay_config
+        .status
+        .context("OverlayConfig has no status")?;
+    let address = tunnel_address(&status)?;
+    let remotes = match overlay_config.spec.interface.encapsulation {
+        Encapsulation::Vxlan => endpoint_addresses(&status.peers),
+        Encapsulation::Geneve => vec![],
+    };
+
+    let expected = ConfiguredInterface {
+        name: settings.interface_name.clone(),
+        address: host_address(address),
+        prefix: address.prefix,
+        mtu: None,
+        mac: None,
+        routes: vec![],
+        dns: vec![],
+        tunnel: Tunnel::Overlay(OverlayTunnel {
+            listen_port: overlay_config.spec.interface.port(),
+            remotes,
+        }),
+    };
+
+    verify_overlay_interface(netns, &expected, settings)
+}
+
+// The link info isn't read back, so the VNI, port and a GENEVE remote aren't
+// verified. VXLAN remotes are, through the FDB.
+pub fn verify_overlay_interface(
+    netns: &str,
+    expected: &ConfiguredInterface,
+    settings: &Settings,
+) -> anyhow::Result<Vec<String>> {
+    let container_netns_file = File::open(netns)?;
+    run_in_netns(&container_netns_file, || {
+        inspect_overlay_state(&mut SystemExecutor::open()?, expected, settings)
+    })
+}
+
+fn inspect_overlay_state(
+    executor: &mut impl Executor,
+    expected: &ConfiguredInterface,
+    settings: &Settings,
+) -> anyhow::Result<Vec<String>> {
+    let Tunnel::Overlay(tunnel) = &expected.tunnel else {
+        return Err(anyhow!(
+            "interface {} was not configured by the overlay driver",
+            expected.name
+        ));
+    };
+    let interface_name = settings.interface_name.as_str();
+    let mut drift = vec![];
+
+    info!("inspecting the overlay interface");
+    let link = match executor.link(interface_name)? {
+        Some(link) => link,
+        None => {
+            drift.push(format!("interface {} does not exist", interface_name));
+            return Ok(drift);
+        }
+    };
+    if !link.up {
+        drift.push(format!("interface {} is not up", interface_name));
+    }
+
+    let address = Address {
+        address: IpAddr::V4(expected.address),
+        prefix: expected.prefix,
+    };
+    if !executor.addresses(link.index)?.contains(&address) {
+        drift.push(format!(
+            "address {}/{} is not assigned",
+            expected.address, expected.prefix
+        ));
+    }
+
+    info!("inspecting the FDB entries");
+    let entries = executor.fdb_entries(link.index)?;
+    for &remote in &tunnel.remotes {
+        if !entries.contains(&flood_entry(remote)) {
+            drift.push(format!("FDB entry for {} is missing", remote));
+        }
+    }
+
+    Ok(drift)
+}
+
+// Overlay links have no per peer counters, only the interface ones.
+pub fn overlay_stats(netns: &str, settings: &Settings) -> anyhow::Result<TunnelStats> {
+    let container_netns_file = File::open(netns)?;
+    let link = run_in_netns(&container_netns_file, || {
+        Netlink::open()?
+            .link(&settings.interface_name)?
+            .with_context(|| format!("interface {} does not exist", settings.interface_name))
+    })?;
+
+    Ok(TunnelStats {
+        interface: link.name,
+        rx_bytes: link.rx_bytes,
+        tx_bytes: link.tx_bytes,
+        peers: vec![],
+    })
+}
+
+fn link_kind(interface: &OverlayInterface, remotes: &[Ipv4Addr]) -> anyhow::Result<LinkKind> {
+    if interface.vni > MAX_VNI {
+        return Err(anyhow!(
+            "vni {} is out of range, the maximum is {}",
+            interface.vni,
+            MAX_VNI
+        ));
+    }
+
+    match interface.encapsulation {
+        Encapsulation::Vxlan => Ok(LinkKind::Vxlan {
+            vni: interface.vni,
+            port: interface.port(),
+        }),
+        Encapsulation::Geneve => match remotes {
+            [remote] => Ok(LinkKind::Geneve {
+                vni: interface.vni,
+                remote: *remote,
+                port: interface.port(),
+            }),
+            _ => Err(anyhow!(
+                "GENEVE interfaces reach a single peer, got {}",
+                remotes.len()
+            )),
+        },
+    }
+}
+
+fn tunnel_address(status: &OverlayConfigStatus) -> anyhow::Result<Address> {
+    match (status.tunnel_address, status.tunnel_address_prefix) {
+        (Some(address), Some(prefix)) => Ok(Address {
+            address: IpAddr::V4(address),
+            prefix,
+        }),
+        _ => Err(anyhow!("OverlayConfig has no tunnel address assigned")),
+    }
+}
+
+fn host_address(address: Address) -> Ipv4Addr {
+    match address.address {
+        IpAddr::V4(address) => address,
+        IpAddr::V6(_) => unreachable!("overlay tunnel addresses are IPv4"),
+    }
+}
+
+fn flood_entry(destination: Ipv4Addr) -> FdbEntry {
+    FdbEntry {
+        mac: FLOOD_MAC,
+        destination,
+    }
+}
+
+fn endpoint_addresses(peers: &[OverlayPeerConfig]) -> Vec<Ipv4Addr> {
+    let mut endpoint_addresses = vec![];
+    for peer in peers {
+        if !endpoint_addresses.contains(&peer.endpoint_address) {
+            endpoint_addresses.push(peer.endpoint_address);
+        }
+    }
+    endpoint_addresses
+}
+
+async fn get_overlay_config(
+    kube_client: &KubeClient,
+    namespace: &str,
+    name: &str,
+    pod_ip: &Ipv4Addr,
+    readiness_timeout: Duration,
+) -> anyhow::Result<Option<OverlayConfig>> {
+    let overlay_configs: Api<OverlayConfig> = Api::namespaced(kube_client.clone(), namespace);
+    let deadline = Instant::now() + readiness_timeout;
+    let mut backoff = INITIAL_READINESS_BACKOFF;
+    let mut pod_address_reported = false;
+
+    loop {
+        let overlay_config = match overlay_configs.get(name).await {
+            Ok(overlay_config) => Some(overlay_config),
+            Err(KubeError::Api(api_err)) if api_err.code == 404 => return Ok(None),
+            Err(err) => {
+                info!("failed to get OverlayConfig {}: {}", name, err);
+                None
+            }
+        };
+
+        if let Some(overlay_config) = overlay_config {
+            if !pod_address_reported {
+                let patch = json!({
+                    "status": {
+                        "pod_address": pod_ip,
+                    }
+                });
+                match overlay_configs
+                    .patch_status(name, &PatchParams::default(), &Patch::Merge(&patch))
+                    .await
+                {
+                    Ok(_) => pod_address_reported = true,
+                    Err(err) => info!("failed to report pod_address for {}: {}", name, err),
+                }
+            }
+
+            if let OverlayConfig {
+                status:
+                    Some(OverlayConfigStatus {
+                        interface_ready,
+                        ref peers,
+                        ..
+                    }),
+                ..
+            } = overlay_config
+                && interface_ready
+                && !peers.is_empty()
+                && pod_address_reported
+            {
+                return Ok(Some(overlay_config));
+            }
+        }
+
+        if Instant::now() + backoff > deadline {
+            return Err(NotReady {
+                kind: "OverlayConfig",
+                name: name.to_string(),
+                timeout: readiness_timeout,
+            }
+            .into());
+        }
+
+        info!(
+            "OverlayConfig {} not ready, retrying in {:?}",
+            name, backoff
+        );
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_READINESS_BACKOFF);
+    }
+}
+
+async fn clear_pod_address(
+    kube_client: &KubeClient,
+    namespace: &str,
+    name: &str,
+    pod_address: Option<Ipv4Addr>,
+) -> anyhow::Result<()> {
+    let overlay_configs: Api<OverlayConfig> = Api::namespaced(kube_client.clone(), namespace);
+    if let Some(pod_address) = pod_address {
+        let current_pod_address = match overlay_configs.get(name).await {
+            Ok(overlay_config) => overlay_config.status.and_then(|status| status.pod_address),
+            Err(KubeError::Api(api_err)) if api_err.code == 404 => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        if current_pod_address != Some(pod_address) {
+            info!("pod_address for {} was already replaced, skipping", name);
+            return Ok(());
+        }
+    }
+
+    let patch = json!({
+        "status": {
+            "pod_address": null,
+        }
+    });
+
+    match overlay_configs
+        .patch_status(name, &PatchParams::default(), &Patch::Merge(&patch))
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(KubeError::Api(api_err)) if api_err.code == 404 => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        driver::DriverKind, executor::fake::RecordingExecutor,
+        wireguard::workflows::WireguardTunnel,
+    };
+    use api::wireguard::{WireguardAddress, WireguardNetwork};
+
+    const VNI: u32 = 42;
+
+    fn settings() -> Settings {
+        Settings {
+            driver: DriverKind::Overlay,
+            interface_name: DriverKind::Overlay.default_interface_name().to_string(),
+            ..Settings::default()
+        }
+    }
+
+    fn interface(encapsulation: Encapsulation, vni: u32) -> OverlayInterface {
+        OverlayInterface {
+            encapsulation,
+            vni,
+            port: None,
+            address: WireguardAddress::NetworkAddress(WireguardNetwork {
+                address: Ipv4Addr::new(10, 0, 0, 0),
+                prefix: 24,
+            }),
+        }
+    }
+
+    fn address(host: u8) -> Address {
+        Address {
+            address: IpAddr::V4(Ipv4Addr::new(10, 0, 0, host)),
+            prefix: 24,
+        }
+    }
+
+    fn remote(host: u8) -> Ipv4Addr {
+        Ipv4Addr::new(192, 168, 1, host)
+    }
+
+    fn apply(
+        executor: &mut RecordingExecutor,
+        encapsulation: Encapsulation,
+        remotes: &[Ipv4Addr],
+    ) -> anyhow::Result<Link> {
+        let kind = link_kind(&interface(encapsulation, VNI), remotes)?;
+        apply_overlay_state(executor, kind, address(1), remotes, &settings())
+    }
+
+    fn reconcile(
+        executor: &mut RecordingExecutor,
+        encapsulation: Encapsulation,
+        address: Address,
+        remotes: &[Ipv4Addr],
+    ) -> Vec<String> {
+        reconcile_overlay_state(executor, encapsulation, address, remotes, &settings()).unwrap()
+    }
+
+    fn expected(remotes: Vec<Ipv4Addr>) -> ConfiguredInterface {
+        ConfiguredInterface {
+            name: settings().interface_name,
+            address: Ipv4Addr::new(10, 0, 0, 1),
+            prefix: 24,
+            mtu: None,
+            mac: None,
+            routes: vec![],
+            dns: vec![],
+            tunnel: Tunnel::Overlay(OverlayTunnel {
+                listen_port: 4789,
+                remotes,
+            }),
+        }
+    }
+
+    #[test]
+    fn apply_configures_vxlan_with_fdb_entries() {
+        let mut executor = RecordingExecutor::default();
+
+        let link = apply(&mut executor, Encapsulation::Vxlan, &[remote(2), remote(3)]).unwrap();
+
+        assert_eq!(link.index, 2);
+        assert_eq!(
+            executor.take_operations(),
+            [
+                "add_link ovl0 Vxlan { vni: 42, port: 4789 }",
+                "add_address 2 10.0.0.1/24",
+                "add_fdb_entry 2 00:00:00:00:00:00 dst 192.168.1.2",
+                "add_fdb_entry 2 00:00:00:00:00:00 dst 192.168.1.3",
+                "set_link_up 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_configures_geneve_without_fdb_entries() {
+        let mut executor = RecordingExecutor::default();
+
+        apply(&mut executor, Encapsulation::Geneve, &[remote(2)]).unwrap();
+
+        assert_eq!(
+            executor.take_operations(),
+            [
+                "add_link ovl0 Geneve { vni: 42, remote: 192.168.1.2, port: 6081 }",
+                "add_address 2 10.0.0.1/24",
+                "set_link_up 2",
+            ]
+        );
+        assert!(executor.fdb_entries.is_empty());
+    }
+
+    #[test]
+    fn geneve_reaches_exactly_one_remote() {
+        let interface = interface(Encapsulation::Geneve, VNI);
+
+        for remotes in [vec![], vec![remote(2), remote(3)]] {
+            let err = link_kind(&interface, &remotes).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "GENEVE interfaces reach a single peer, got {}",
+                    remotes.len()
+                )
+            );
+        }
+        assert!(link_kind(&interface, &[remote(2)]).is_ok());
+    }
+
+    #[test]
+    fn vni_is_24_bits() {
+        for encapsulation in [Encapsulation::Vxlan, Encapsulation::Geneve] {
+            let kind = link_kind(&interface(encapsulation, MAX_VNI), &[remote(2)]).unwrap();
+            assert!(matches!(
+                kind,
+                LinkKind::Vxlan { vni: MAX_VNI, .. } | LinkKind::Geneve { vni: MAX_VNI, .. }
+            ));
+
+            let err = link_kind(&interface(encapsulation, MAX_VNI + 1), &[remote(2)]).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "vni 16777216 is out of range, the maximum is 16777215"
+            );
+        }
+    }
+
+    #[test]
+    fn apply_rolls_back_when_an_fdb_entry_fails() {
+        let mut executor = RecordingExecutor::default();
+        executor.fail("add_fdb_entry 2 00:00:00:00:00:00 dst 192.168.1.3");
+
+        let err = apply(&mut executor, Encapsulation::Vxlan, &[remote(2), remote(3)]).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "failed to add the FDB entry for 192.168.1.3"
+        );
+        let operations = executor.take_operations();
+        assert_eq!(operations[4..], ["del_address 2 10.0.0.1/24", "del_link 2"]);
+        assert!(executor.links.is_empty());
+        assert!(executor.fdb_entries.is_empty());
+    }
+
+    #[test]
+    fn apply_rolls_back_when_the_interface_cannot_be_brought_up() {
+        let mut executor = RecordingExecutor::default();
+        executor.fail("set_link_up");
+
+        let err = apply(&mut executor, Encapsulation::Geneve, &[remote(2)]).unwrap_err();
+
+        assert_eq!(err.to_string(), "failed to bring the overlay interface up");
+        let operations = executor.take_operations();
+        assert_eq!(operations[3..], ["del_address 2 10.0.0.1/24", "del_link 2"]);
+        assert!(executor.links.is_empty());
+    }
+
+    #[test]
+    fn reconcile_after_apply_changes_nothing() {
+        let mut executor = RecordingExecutor::default();
+        let remotes = [remote(2), remote(3)];
+        apply(&mut executor, Encapsulation::Vxlan, &remotes).unwrap();
+        executor.take_operations();
+
+        let changes = reconcile(&mut executor, Encapsulation::Vxlan, address(1), &remotes);
+
+        assert!(changes.is_empty(), "{:?}", changes);
+        assert!(executor.take_operations().is_empty());
+    }
+
+    #[test]
+    fn reconcile_replaces_changed_remotes_and_address() {
+        let mut executor = RecordingExecutor::default();
+        apply(&mut executor, Encapsulation::Vxlan, &[remote(2)]).unwrap();
+        executor.take_operations();
+
+        let changes = reconcile(
+            &mut executor,
+            Encapsulation::Vxlan,
+            address(5),
+            &[remote(3)],
+        );
+
+        assert_eq!(
+            changes,
+            [
+                "added address 10.0.0.5/24",
+                "removed address 10.0.0.1/24",
+                "added the FDB entry for 192.168.1.3",
+                "removed the FDB entry for 192.168.1.2",
+            ]
+        );
+        assert_eq!(
+            executor.take_operations(),
+            [
+                "add_address 2 10.0.0.5/24",
+                "del_address 2 10.0.0.1/24",
+                "add_fdb_entry 2 00:00:00:00:00:00 dst 192.168.1.3",
+                "del_fdb_entry 2 00:00:00:00:00:00 dst 192.168.1.2",
+            ]
+        );
+    }
+
+    #[test]
+    fn reconcile_leaves_geneve_fdb_alone_and_brings_the_interface_up() {
+        let mut executor = RecordingExecutor::default();
+        apply(&mut executor, Encapsulation::Geneve, &[remote(2)]).unwrap();
+        executor.links[0].up = false;
+        executor.take_operations();
+
+        let changes = reconcile(
+            &mut executor,
+            Encapsulation::Geneve,
+            address(1),
+            &[remote(2)],
+        );
+
+        assert_eq!(changes, ["brought interface ovl0 up"]);
+        assert_eq!(executor.take_operations(), ["set_link_up 2"]);
+        assert!(executor.fdb_entries.is_empty());
+    }
+
+    #[test]
+    fn reconcile_requires_the_interface() {
+        let mut executor = RecordingExecutor::default();
+
+        let err = reconcile_overlay_state(
+            &mut executor,
+            Encapsulation::Vxlan,
+            address(1),
+            &[remote(2)],
+            &settings(),
+        )
+        .unwrap_err();
+
+        assert_eq!(err.to_string(), "interface ovl0 does not exist");
+    }
+
+    #[test]
+    fn inspect_reports_drift() {
+        let mut executor = RecordingExecutor::default();
+        let remotes = vec![remote(2), remote(3)];
+        apply(&mut executor, Encapsulation::Vxlan, &remotes).unwrap();
+
+        assert!(
+            inspect_overlay_state(&mut executor, &expected(remotes.clone()), &settings())
+                .unwrap()
+                .is_empty()
+        );
+
+        executor.links[0].up = false;
+        executor.addresses.clear();
+        executor
+            .fdb_entries
+            .retain(|(_, entry)| entry.destination != remote(3));
+        assert_eq!(
+            inspect_overlay_state(&mut executor, &expected(remotes), &settings()).unwrap(),
+            [
+                "interface ovl0 is not up",
+                "address 10.0.0.1/24 is not assigned",
+                "FDB entry for 192.168.1.3 is missing",
+            ]
+        );
+
+        executor.links.clear();
+        assert_eq!(
+            inspect_overlay_state(&mut executor, &expected(vec![]), &settings()).unwrap(),
+            ["interface ovl0 does not exist"]
+        );
+    }
+
+    #[test]
+    fn inspect_rejects_results_of_other_drivers() {
+        let mut executor = RecordingExecutor::default();
+        let mut expected = expected(vec![]);
+        expected.tunnel = Tunnel::Wireguard(WireguardTunnel {
+            listen_port: 51820,
+            public_key: None,
+            peers: vec![],
+            routing_mode: None,
+            mss_clamping: false,
+        });
+
+        let err = inspect_overlay_state(&mut executor, &expected, &settings()).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "interface ovl0 was not configured by the overlay driver"
+        );
+    }
+
+    #[test]
+    fn remove_deletes_the_interface_and_its_fdb() {
+        let mut executor = RecordingExecutor::default();
+        apply(&mut executor, Encapsulation::Vxlan, &[remote(2)]).unwrap();
+        executor.take_operations();
+
+        remove_overlay_state(&mut executor, &settings()).unwrap();
+        assert_eq!(executor.take_operations(), ["del_link 2"]);
+        assert!(executor.links.is_empty());
+        assert!(executor.addresses.is_empty());
+        assert!(executor.fdb_entries.is_empty());
+
+        remove_overlay_state(&mut executor, &settings()).unwrap();
+        assert!(executor.take_operations().is_empty());
+    }
+
+    #[test]
+    fn endpoint_addresses_are_deduplicated() {
+        let peer = |host| OverlayPeerConfig {
+            endpoint_address: remote(host),
+            tunnel_address: None,
+        };
+
+        assert_eq!(
+            endpoint_addresses(&[peer(2), peer(3), peer(2)]),
+            [remote(2), remote(3)]
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use crate::driver::DriverKind;
+
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_INTERFACE_NAME: &str = "wg0";
+pub const DEFAULT_FWMARK: u32 = 921481285;
+pub const DEFAULT_ROUTING_TABLE: u32 = 129518285;
+pub const DEFAULT_KUBECONFIG: &str = "/etc/cni/net.d/podtunnel.d/podtunnel.kubeconfig";
+pub const DEFAULT_READINESS_TIMEOUT: Duration = Duration::from_secs(60);
+
+// the kernel limits interface names to IFNAMSIZ (16) including the nul byte
+const MAX_INTERFACE_NAME_LENGTH: usize = 15;
+
+// routing tables the kernel reserves: unspec, default, main and local
+const RESERVED_ROUTING_TABLES: [u32; 4] = [0, 253, 254, 255];
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Settings {
+    // attachments cached before drivers were configurable used WireGuard
+    #[serde(default)]
+    pub driver: DriverKind,
+    pub interface_name: String,
+    pub fwmark: u32,
+    pub routing_table: u32,
+    pub kubeconfig: PathBuf,
+    pub readiness_timeout: Duration,
+    // the MTU of the Pod's interface from the previous result, the tunnel
+    // runs on top of it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub underlay_mtu: Option<u32>,
+    // the userspace wireguard implementation hasn't had a security review
+    // yet, so nodes without the kernel module only use it when asked to
+    #[serde(default)]
+    pub userspace_fallback: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            driver: DriverKind::default(),
+            interface_name: DEFAULT_INTERFACE_NAME.to_string(),
+            fwmark: DEFAULT_FWMARK,
+            routing_table: DEFAULT_ROUTING_TABLE,
+            kubeconfig: PathBuf::from(DEFAULT_KUBECONFIG),
+            readiness_timeout: DEFAULT_READINESS_TIMEOUT,
+            underlay_mtu: None,
+            userspace_fallback: false,
+        }
+    }
+}
+
+impl Settings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let interface_name = &self.interface_name;
+        if interface_name.is_empty()
+            || interface_name.len() > MAX_INTERFACE_NAME_LENGTH
+            || interface_name == "."
+            || interface_name == ".."
+            || interface_name
+                .chars()
+                .any(|c| c == '/' || c == ':' || c.is_whitespace())
+        {
+            return Err(anyhow!("invalid interface name {:?}", interface_name));
+        }
+
+        if self.fwmark == 0 {
+            return Err(anyhow!("fwmark must be non-zero"));
+        }
+
+        if RESERVED_ROUTING_TABLES.contains(&self.routing_table) {
+            return Err(anyhow!(
+                "routing table {} is reserved by the kernel",
+                self.routing_table
+            ));
+        }
+
+        if !self.kubeconfig.is_absolute() {
+            return Err(anyhow!(
+                "kubeconfig path {} must be absolute",
+                self.kubeconfig.display()
+            ));
+        }
+
+        if self.readiness_timeout.is_zero() {
+            return Err(anyhow!("readiness timeout must be greater than zero"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_the_defaults() {
+        Settings::default().validate().unwrap();
+        Settings {
+            interface_name: "a".repeat(MAX_INTERFACE_NAME_LENGTH),
+            fwmark: 1,
+            routing_table: 1,
+            ..Settings::default()
+        }
+        .validate()
+        .unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let long_name = "a".repeat(MAX_INTERFACE_NAME_LENGTH + 1);
+        let with_interface_name = |name: &str| Settings {
+            interface_name: name.to_string(),
+            ..Settings::default()
+        };
+        let with_routing_table = |routing_table| Settings {
+            routing_table,
+            ..Settings::default()
+        };
+
+        for (settings, error) in [
+            (with_interface_name(""), "invalid interface name \"\""),
+            (
+                with_interface_name(&long_name),
+                "invalid interface name \"aaaaaaaaaaaaaaaa\"",
+            ),
+            (with_interface_name("."), "invalid interface name \".\""),
+            (with_interface_name(".."), "invalid interface name \"..\""),
+            (
+                with_interface_name("wg/0"),
+                "invalid interface name \"wg/0\"",
+            ),
+            (
+                with_interface_name("wg:0"),
+                "invalid interface name \"wg:0\"",
+            ),
+            (
+                with_interface_name("wg 0"),
+                "invalid interface name \"wg 0\"",
+            ),
+            (
+                with_interface_name("wg\t0"),
+                "invalid interface name \"wg\\t0\"",
+            ),
+            (
+                Settings {
+                    fwmark: 0,
+                    ..Settings::default()
+                },
+                "fwmark must be non-zero",
+            ),
+            (
+                with_routing_table(0),
+                "routing table 0 is reserved by the kernel",
+            ),
+            (
+                with_routing_table(253),
+                "routing table 253 is reserved by the kernel",
+            ),
+            (
+                with_routing_table(254),
+                "routing table 254 is reserved by the kernel",
+            ),
+            (
+                with_routing_table(255),
+                "routing table 255 is reserved by the kernel",
+            ),
+            (
+                Settings {
+                    kubeconfig: PathBuf::from("podtunnel.kubeconfig"),
+                    ..Settings::default()
+                },
+                "kubeconfig path podtunnel.kubeconfig must be absolute",
+            ),
+            (
+                Settings {
+                    readiness_timeout: Duration::ZERO,
+                    ..Settings::default()
+                },
+                "readiness timeout must be greater than zero",
+            ),
+        ] {
+            assert_eq!(settings.validate().unwrap_err().to_string(), error);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use crate::system::netlink::{Connection, NetlinkError};
+
+use netlink_packet_core::{NetlinkDeserializable, NetlinkHeader, NetlinkSerializable};
+use netlink_sys::protocols::NETLINK_GENERIC;
+
+const GENL_HEADER_LEN: usize = 4;
+const NLA_HEADER_LEN: usize = 4;
+const NLA_F_NESTED: u16 = 1 << 15;
+pub(crate) const NLA_TYPE_MASK: u16 = !(NLA_F_NESTED | 1 << 14);
+
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+// A generic netlink message whose attributes are kept in their wire format,
+// families encode and decode them with the helpers below.
+#[derive(Debug)]
+pub(crate) struct GenericMessage {
+    pub family: u16,
+    pub command: u8,
+    pub version: u8,
+    pub attributes: Vec<u8>,
+}
+
+impl NetlinkSerializable for GenericMessage {
+    fn message_type(&self) -> u16 {
+        self.family
+    }
+
+    fn buffer_len(&self) -> usize {
+        GENL_HEADER_LEN + self.attributes.len()
+    }
+
+    fn serialize(&self, buffer: &mut [u8]) {
+        buffer[0] = self.command;
+        buffer[1] = self.version;
+        buffer[2..GENL_HEADER_LEN].fill(0);
+        buffer[GENL_HEADER_LEN..].copy_from_slice(&self.attributes);
+    }
+}
+
+impl NetlinkDeserializable for GenericMessage {
+    type Error = NetlinkError;
+
+    fn deserialize(header: &NetlinkHeader, payload: &[u8]) -> Result<Self, Self::Error> {
+        if payload.len() < GENL_HEADER_LEN {
+            return Err(NetlinkError::Decode(
+                "truncated generic netlink header".to_string(),
+            ));
+        }
+
+        Ok(GenericMessage {
+            family: header.message_type,
+            command: payload[0],
+            version: payload[1],
+            attributes: payload[GENL_HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+pub(crate) struct GenericNetlink {
+    connection: Connection,
+}
+
+impl GenericNetlink {
+    pub(crate) fn open() -> Result<Self, NetlinkError> {
+        Ok(GenericNetlink {
+            connection: Connection::open(NETLINK_GENERIC)?,
+        })
+    }
+
+    // Family ids are assigned when the module registers, so they have to be
+    // looked up by name. A family which isn't registered is reported as
+    // NotFound.
+    pub(crate) fn resolve_family(&mut self, name: &str) -> Result<u16, NetlinkError> {
+        let mut attributes = vec![];
+        put_string(&mut attributes, CTRL_ATTR_FAMILY_NAME, name);
+
+        let replies = self.request(GENL_ID_CTRL, CTRL_CMD_GETFAMILY, 1, attributes, 0)?;
+        for reply in replies {
+            for (kind, value) in parse_attributes(&reply.attributes)? {
+                if kind == CTRL_ATTR_FAMILY_ID {
+                    return get_u16(value);
+                }
+            }
+        }
+        Err(NetlinkError::NotFound)
+    }
+
+    pub(crate) fn request(
+        &mut self,
+        family: u16,
+        command: u8,
+        version: u8,
+        attributes: Vec<u8>,
+        flags: u16,
+    ) -> Result<Vec<GenericMessage>, NetlinkError> {
+        let message = GenericMessage {
+            family,
+            command,
+            version,
+            attributes,
+        };
+        self.connection
+            .request(message, netlink_packet_core::NLM_F_ACK | flags)
+    }
+}
+
+pub(crate) fn put(buffer: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    let length = NLA_HEADER_LEN + value.len();
+    buffer.extend_from_slice(&(length as u16).to_ne_bytes());
+    buffer.extend_from_slice(&kind.to_ne_bytes());
+    buffer.extend_from_slice(value);
+    pad(buffer);
+}
+
+pub(crate) fn put_u16(buffer: &mut Vec<u8>, kind: u16, value: u16) {
+    put(buffer, kind, &value.to_ne_bytes());
+}
+
+pub(crate) fn put_u32(buffer: &mut Vec<u8>, kind: u16, value: u32) {
+    put(buffer, kind, &value.to_ne_bytes());
+}
+
+pub(crate) fn put_string(buffer: &mut Vec<u8>, kind: u16, value: &str) {
+    let mut value = value.as_bytes().to_vec();
+    value.push(0);
+    put(buffer, kind, &value);
+}
+
+pub(crate) fn put_nested(buffer: &mut Vec<u8>, kind: u16, f: impl FnOnce(&mut Vec<u8>)) {
+    let start = buffer.len();
+    buffer.extend_from_slice(&[0; NLA_HEADER_LEN]);
+    f(buffer);
+
+    let length = (buffer.len() - start) as u16;
+    buffer[start..start + 2].copy_from_slice(&length.to_ne_bytes());
+    buffer[start + 2..start + 4].copy_from_slice(&(kind | NLA_F_NESTED).to_ne_bytes());
+}
+
+pub(crate) fn parse_attributes(mut buffer: &[u8]) -> Result<Vec<(u16, &[u8])>, NetlinkError> {
+    let mut attributes = vec![];
+    while buffer.len() >= NLA_HEADER_LEN {
+        let length = u16::from_ne_bytes([buffer[0], buffer[1]]) as usize;
+        let kind = u16::from_ne_bytes([buffer[2], buffer[3]]) & NLA_TYPE_MASK;
+        if length < NLA_HEADER_LEN || length > buffer.len() {
+            return Err(NetlinkError::Decode(format!(
+                "attribute {} has invalid length {}",
+                kind, length
+            )));
+        }
+
+        attributes.push((kind, &buffer[NLA_HEADER_LEN..length]));
+        buffer = &buffer[aligned(length).min(buffer.len())..];
+    }
+    Ok(attributes)
+}
+
+pub(crate) fn get_u16(value: &[u8]) -> Result<u16, NetlinkError> {
+    Ok(u16::from_ne_bytes(fixed(value)?))
+}
+
+pub(crate) fn get_u32(value: &[u8]) -> Result<u32, NetlinkError> {
+    Ok(u32::from_ne_bytes(fixed(value)?))
+}
+
+pub(crate) fn get_u64(value: &[u8]) -> Result<u64, NetlinkError> {
+    Ok(u64::from_ne_bytes(fixed(value)?))
+}
+
+pub(crate) fn get_string(value: &[u8]) -> Result<String, NetlinkError> {
+    let value = value.strip_suffix(&[0]).unwrap_or(value);
+    String::from_utf8(value.to_vec()).map_err(|err| NetlinkError::Decode(err.to_string()))
+}
+
+pub(crate) fn fixed<const N: usize>(value: &[u8]) -> Result<[u8; N], NetlinkError> {
+    value.try_into().map_err(|_| {
+        NetlinkError::Decode(format!(
+            "expected a {} byte attribute, got {} bytes",
+            N,
+            value.len()
+        ))
+    })
+}
+
+fn aligned(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+fn pad(buffer: &mut Vec<u8>) {
+    buffer.resize(aligned(buffer.len()), 0);
+}
@@ -1 +1,3 @@
-pub mod linux;
+pub mod genetlink;
+pub mod netlink;
+pub mod netns;
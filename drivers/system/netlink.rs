@@ -0,0 +1,696 @@
+use std::{
+    fmt::Debug,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use crate::system::genetlink::{NLA_TYPE_MASK, get_u32, parse_attributes, put, put_u32};
+
+use netlink_packet_core::{
+    NLM_F_ACK, NLM_F_APPEND, NLM_F_CREATE, NLM_F_DUMP, NLM_F_EXCL, NLM_F_REQUEST,
+    NetlinkDeserializable, NetlinkHeader, NetlinkMessage, NetlinkPayload, NetlinkSerializable,
+};
+use netlink_packet_route::{
+    AF_BRIDGE, AF_INET, AF_INET6, AddressMessage, FR_ACT_TO_TBL, IFF_UP, IFLA_LINKINFO,
+    LINK_HEADER_LEN, LinkMessage, NTF_SELF, NUD_NOARP, NUD_PERMANENT, NeighbourMessage,
+    RT_SCOPE_LINK, RT_SCOPE_NOWHERE, RT_TABLE_MAIN, RT_TABLE_UNSPEC, RTAX_ADVMSS, RTM_NEWLINK,
+    RTN_UNICAST, RTPROT_BOOT, RouteMessage, RtnlMessage, RuleMessage,
+    nlas::{
+        address::Nla as AddressNla,
+        link::{Info, InfoData, InfoKind, InfoVxlan, Nla as LinkNla},
+        neighbour::Nla as NeighbourNla,
+        route::Nla as RouteNla,
+        rule::Nla as RuleNla,
+    },
+};
+use netlink_sys::{Socket, SocketAddr, protocols::NETLINK_ROUTE};
+use thiserror::Error;
+
+pub const MAIN_TABLE: u32 = RT_TABLE_MAIN as u32;
+
+// netlink-packet-route has no GENEVE attributes, these are from
+// include/uapi/linux/if_link.h
+const IFLA_GENEVE_ID: u16 = 1;
+const IFLA_GENEVE_REMOTE: u16 = 2;
+const IFLA_GENEVE_PORT: u16 = 5;
+
+#[derive(Debug, Error)]
+pub enum NetlinkError {
+    #[error("object already exists")]
+    Exists,
+    #[error("no such device")]
+    NoDevice,
+    #[error("no such object")]
+    NotFound,
+    #[error("netlink request failed: {0}")]
+    Kernel(io::Error),
+    #[error("netlink socket failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed netlink message: {0}")]
+    Decode(String),
+}
+
+impl NetlinkError {
+    fn from_errno(errno: i32) -> Self {
+        match errno {
+            libc::EEXIST => NetlinkError::Exists,
+            libc::ENODEV => NetlinkError::NoDevice,
+            libc::ENOENT | libc::ESRCH => NetlinkError::NotFound,
+            errno => NetlinkError::Kernel(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+// Teardown can race with the kernel removing state on its own, e.g. routes
+// go away together with their interface.
+pub(crate) fn ignore_not_found(result: Result<(), NetlinkError>) -> Result<(), NetlinkError> {
+    match result {
+        Err(NetlinkError::NotFound | NetlinkError::NoDevice) => Ok(()),
+        result => result,
+    }
+}
+
+// A netlink socket which sends one request at a time and collects the
+// replies. The socket belongs to the network namespace of the thread which
+// opened it, so it has to be opened after switching namespaces.
+pub(crate) struct Connection {
+    socket: Socket,
+    sequence: u32,
+}
+
+impl Connection {
+    pub(crate) fn open(protocol: isize) -> Result<Self, NetlinkError> {
+        let mut socket = Socket::new(protocol)?;
+        socket.bind_auto()?;
+        socket.connect(&SocketAddr::new(0, 0))?;
+        Ok(Connection {
+            socket,
+            sequence: 0,
+        })
+    }
+
+    pub(crate) fn request<I>(&mut self, message: I, flags: u16) -> Result<Vec<I>, NetlinkError>
+    where
+        I: NetlinkSerializable + NetlinkDeserializable + Debug,
+    {
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let mut request = NetlinkMessage::new(
+            NetlinkHeader::default(),
+            NetlinkPayload::InnerMessage(message),
+        );
+        request.header.flags = NLM_F_REQUEST | flags;
+        request.header.sequence_number = self.sequence;
+        request.finalize();
+
+        let mut buffer = vec![0; request.buffer_len()];
+        request.serialize(&mut buffer);
+        self.socket.send(&buffer, 0)?;
+
+        // dumps end with NLMSG_DONE and everything else is acknowledged with
+        // an NLMSG_ERROR carrying a zero code
+        let mut replies = vec![];
+        loop {
+            let (buffer, _) = self.socket.recv_from_full()?;
+            let mut offset = 0;
+            while offset < buffer.len() {
+                let reply = NetlinkMessage::<I>::deserialize(&buffer[offset..])
+                    .map_err(|err| NetlinkError::Decode(err.to_string()))?;
+                let length = reply.header.length as usize;
+                if length == 0 {
+                    return Err(NetlinkError::Decode("zero length message".to_string()));
+                }
+                offset += length;
+
+                if reply.header.sequence_number != self.sequence {
+                    continue;
+                }
+                match reply.payload {
+                    NetlinkPayload::Done(_) => return Ok(replies),
+                    NetlinkPayload::Error(err) => match err.code {
+                        Some(code) => return Err(NetlinkError::from_errno(-code.get())),
+                        None => return Ok(replies),
+                    },
+                    NetlinkPayload::InnerMessage(message) => replies.push(message),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+// netlink-packet-route rejects link info attributes it doesn't know, which
+// newer kernels report for VXLAN links. Nothing reads the link info back, so
+// it's dropped from link replies before they are parsed.
+#[derive(Debug)]
+struct WithoutLinkInfo(RtnlMessage);
+
+impl NetlinkSerializable for WithoutLinkInfo {
+    fn message_type(&self) -> u16 {
+        self.0.message_type()
+    }
+
+    fn buffer_len(&self) -> usize {
+        self.0.buffer_len()
+    }
+
+    fn serialize(&self, buffer: &mut [u8]) {
+        self.0.serialize(buffer)
+    }
+}
+
+impl NetlinkDeserializable for WithoutLinkInfo {
+    type Error = NetlinkError;
+
+    fn deserialize(header: &NetlinkHeader, payload: &[u8]) -> Result<Self, Self::Error> {
+        let mut stripped;
+        let mut payload = payload;
+        if header.message_type == RTM_NEWLINK && payload.len() >= LINK_HEADER_LEN {
+            let (link_header, mut attributes) = payload.split_at(LINK_HEADER_LEN);
+            stripped = link_header.to_vec();
+            while attributes.len() >= 4 {
+                let length = u16::from_ne_bytes([attributes[0], attributes[1]]) as usize;
+                let kind = u16::from_ne_bytes([attributes[2], attributes[3]]) & NLA_TYPE_MASK;
+                if length < 4 || length > attributes.len() {
+                    break;
+                }
+                let aligned = ((length + 3) & !3).min(attributes.len());
+                if kind != IFLA_LINKINFO {
+                    stripped.extend_from_slice(&attributes[..aligned]);
+                }
+                attributes = &attributes[aligned..];
+            }
+            payload = &stripped;
+        }
+
+        RtnlMessage::deserialize(header, payload)
+            .map(WithoutLinkInfo)
+            .map_err(|err| NetlinkError::Decode(err.to_string()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkKind {
+    Wireguard,
+    // remotes are added as FDB entries, learning is disabled
+    Vxlan {
+        vni: u32,
+        port: u16,
+    },
+    // GENEVE has no FDB, so the link only ever reaches a single remote
+    Geneve {
+        vni: u32,
+        remote: Ipv4Addr,
+        port: u16,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Link {
+    pub index: u32,
+    pub name: String,
+    pub up: bool,
+    pub mtu: Option<u32>,
+    pub mac: Option<String>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Address {
+    pub address: IpAddr,
+    pub prefix: u8,
+}
+
+// Routes and rules only cover what podtunnel manages: a destination, an
+// output interface, a table and the MSS advertised over routes, and a table
+// lookup for rules.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub destination: Option<Address>,
+    pub interface: Option<u32>,
+    pub table: u32,
+    pub advmss: Option<u32>,
+}
+
+// A bridge forwarding entry on a tunnel link, the all-zeroes MAC floods
+// broadcast and unknown unicast traffic to the destination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FdbEntry {
+    pub mac: [u8; 6],
+    pub destination: Ipv4Addr,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub priority: u32,
+    pub destination: Option<Address>,
+    // zero matches packets regardless of their mark
+    pub fwmark: u32,
+    pub table: u32,
+    pub suppress_prefixlength: Option<u32>,
+}
+
+// A route netlink handle for the network namespace it was opened in.
+pub struct Netlink {
+    connection: Connection,
+}
+
+impl Netlink {
+    pub fn open() -> Result<Self, NetlinkError> {
+        Ok(Netlink {
+            connection: Connection::open(NETLINK_ROUTE)?,
+        })
+    }
+
+    pub fn add_link(&mut self, name: &str, kind: LinkKind) -> Result<(), NetlinkError> {
+        let info = match kind {
+            LinkKind::Wireguard => vec![Info::Kind(InfoKind::Wireguard)],
+            LinkKind::Vxlan { vni, port } => vec![
+                Info::Kind(InfoKind::Vxlan),
+                Info::Data(InfoData::Vxlan(vec![
+                    InfoVxlan::Id(vni),
+                    InfoVxlan::Port(port),
+                    InfoVxlan::Learning(0),
+                ])),
+            ],
+            LinkKind::Geneve { vni, remote, port } => {
+                let mut data = vec![];
+                put_u32(&mut data, IFLA_GENEVE_ID, vni);
+                put(&mut data, IFLA_GENEVE_REMOTE, &remote.octets());
+                put(&mut data, IFLA_GENEVE_PORT, &port.to_be_bytes());
+                vec![
+                    Info::Kind(InfoKind::Other("geneve".to_string())),
+                    Info::Data(InfoData::Other(data)),
+                ]
+            }
+        };
+
+        let mut message = LinkMessage::default();
+        message.nlas.push(LinkNla::IfName(name.to_string()));
+        message.nlas.push(LinkNla::Info(info));
+        self.connection.request(
+            RtnlMessage::NewLink(message),
+            NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL,
+        )?;
+        Ok(())
+    }
+
+    pub fn link(&mut self, name: &str) -> Result<Option<Link>, NetlinkError> {
+        let mut message = LinkMessage::default();
+        message.nlas.push(LinkNla::IfName(name.to_string()));
+        let replies = match self
+            .connection
+            .request(WithoutLinkInfo(RtnlMessage::GetLink(message)), NLM_F_ACK)
+        {
+            Ok(replies) => replies,
+            Err(NetlinkError::NoDevice) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        Ok(replies.into_iter().find_map(|reply| match reply.0 {
+            RtnlMessage::NewLink(message) => Some(parse_link(message)),
+            _ => None,
+        }))
+    }
+
+    pub fn set_link_up(&mut self, index: u32) -> Result<(), NetlinkError> {
+        let mut message = LinkMessage::default();
+        message.header.index = index;
+        message.header.flags = IFF_UP;
+        message.header.change_mask = IFF_UP;
+        self.connection
+            .request(RtnlMessage::SetLink(message), NLM_F_ACK)?;
+        Ok(())
+    }
+
+    pub fn set_link_mtu(&mut self, index: u32, mtu: u32) -> Result<(), NetlinkError> {
+        let mut message = LinkMessage::default();
+        message.header.index = index;
+        message.nlas.push(LinkNla::Mtu(mtu));
+        self.connection
+            .request(RtnlMessage::SetLink(message), NLM_F_ACK)?;
+        Ok(())
+    }
+
+    pub fn del_link(&mut self, index: u32) -> Result<(), NetlinkError> {
+        let mut message = LinkMessage::default();
+        message.header.index = index;
+        self.connection
+            .request(RtnlMessage::DelLink(message), NLM_F_ACK)?;
+        Ok(())
+    }
+
+    pub fn add_address(&mut self, index: u32, address: Address) -> Result<(), NetlinkError> {
+        let octets = octets(address.address);
+
+        let mut message = AddressMessage::default();
+        message.header.family = family(address.address);
+        message.header.prefix_len = address.prefix;
+        message.header.index = index;
+        message.nlas.push(AddressNla::Local(octets.clone()));
+        message.nlas.push(AddressNla::Address(octets));
+        self.connection.request(
+            RtnlMessage::NewAddress(message),
+            NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL,
+        )?;
+        Ok(())
+    }
+
+    pub fn del_address(&mut self, index: u32, address: Address) -> Result<(), NetlinkError> {
+        let mut message = AddressMessage::default();
+        message.header.family = family(address.address);
+        message.header.prefix_len = address.prefix;
+        message.header.index = index;
+        message
+            .nlas
+            .push(AddressNla::Local(octets(address.address)));
+        self.connection
+            .request(RtnlMessage::DelAddress(message), NLM_F_ACK)?;
+        Ok(())
+    }
+
+    pub fn addresses(&mut self, index: u32) -> Result<Vec<Address>, NetlinkError> {
+        let replies = self.connection.request(
+            RtnlMessage::GetAddress(AddressMessage::default()),
+            NLM_F_DUMP,
+        )?;
+
+        Ok(replies
+            .into_iter()
+            .filter_map(|reply| match reply {
+                RtnlMessage::NewAddress(message) if message.header.index == index => {
+                    let prefix = message.header.prefix_len;
+                    message.nlas.into_iter().find_map(|nla| match nla {
+                        AddressNla::Local(octets) | AddressNla::Address(octets) => {
+                            parse_address(&octets, prefix)
+                        }
+                        _ => None,
+                    })
+                }
+                _ => None,
+            })
+            .collect())
+    }
+
+    pub fn add_route(&mut self, route: &Route) -> Result<(), NetlinkError> {
+        let mut message = route_message(route);
+        message.header.protocol = RTPROT_BOOT;
+        message.header.kind = RTN_UNICAST;
+        // routes without a gateway are directly connected
+        message.header.scope = RT_SCOPE_LINK;
+        self.connection.request(
+            RtnlMessage::NewRoute(message),
+            NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL,
+        )?;
+        Ok(())
+    }
+
+    pub fn routes(&mut self) -> Result<Vec<Route>, NetlinkError> {
+        let mut message = RouteMessage::default();
+        message.header.address_family = AF_INET as u8;
+        let replies = self
+            .connection
+            .request(RtnlMessage::GetRoute(message), NLM_F_DUMP)?;
+
+        Ok(replies
+            .into_iter()
+            .filter_map(|reply| match reply {
+                RtnlMessage::NewRoute(message) => Some(parse_route(message)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    pub fn del_route(&mut self, route: &Route) -> Result<(), NetlinkError> {
+        let mut message = route_message(route);
+        message.header.scope = RT_SCOPE_NOWHERE;
+        self.connection
+            .request(RtnlMessage::DelRoute(message), NLM_F_ACK)?;
+        Ok(())
+    }
+
+    pub fn add_rule(&mut self, rule: &Rule) -> Result<(), NetlinkError> {
+        self.connection.request(
+            RtnlMessage::NewRule(rule_message(rule)),
+            NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL,
+        )?;
+        Ok(())
+    }
+
+    pub fn rules(&mut self) -> Result<Vec<Rule>, NetlinkError> {
+        let mut message = RuleMessage::default();
+        message.header.family = AF_INET as u8;
+        let replies = self
+            .connection
+            .request(RtnlMessage::GetRule(message), NLM_F_DUMP)?;
+
+        Ok(replies
+            .into_iter()
+            .filter_map(|reply| match reply {
+                RtnlMessage::NewRule(message) => Some(parse_rule(message)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    pub fn del_rule(&mut self, rule: &Rule) -> Result<(), NetlinkError> {
+        self.connection
+            .request(RtnlMessage::DelRule(rule_message(rule)), NLM_F_ACK)?;
+        Ok(())
+    }
+
+    // Entries for the same MAC are appended, so the all-zeroes MAC can point
+    // at every remote of a link.
+    pub fn add_fdb_entry(&mut self, index: u32, entry: &FdbEntry) -> Result<(), NetlinkError> {
+        self.connection.request(
+            RtnlMessage::NewNeighbour(fdb_message(index, entry)),
+            NLM_F_ACK | NLM_F_CREATE | NLM_F_APPEND,
+        )?;
+        Ok(())
+    }
+
+    pub fn fdb_entries(&mut self, index: u32) -> Result<Vec<FdbEntry>, NetlinkError> {
+        let mut message = NeighbourMessage::default();
+        message.header.family = AF_BRIDGE as u8;
+        let replies = self
+            .connection
+            .request(RtnlMessage::GetNeighbour(message), NLM_F_DUMP)?;
+
+        Ok(replies
+            .into_iter()
+            .filter_map(|reply| match reply {
+                RtnlMessage::NewNeighbour(message) if message.header.ifindex == index => {
+                    parse_fdb_entry(message)
+                }
+                _ => None,
+            })
+            .collect())
+    }
+
+    pub fn del_fdb_entry(&mut self, index: u32, entry: &FdbEntry) -> Result<(), NetlinkError> {
+        self.connection.request(
+            RtnlMessage::DelNeighbour(fdb_message(index, entry)),
+            NLM_F_ACK,
+        )?;
+        Ok(())
+    }
+}
+
+fn parse_link(message: LinkMessage) -> Link {
+    let mut link = Link {
+        index: message.header.index,
+        name: String::new(),
+        up: message.header.flags & IFF_UP != 0,
+        mtu: None,
+        mac: None,
+        rx_bytes: 0,
+        tx_bytes: 0,
+    };
+    for nla in message.nlas {
+        match nla {
+            // struct rtnl_link_stats64 starts with the packet counters
+            LinkNla::Stats64(stats) if stats.len() >= 32 => {
+                link.rx_bytes = u64::from_ne_bytes(stats[16..24].try_into().unwrap_or_default());
+                link.tx_bytes = u64::from_ne_bytes(stats[24..32].try_into().unwrap_or_default());
+            }
+            LinkNla::IfName(name) => link.name = name,
+            LinkNla::Mtu(mtu) => link.mtu = Some(mtu),
+            LinkNla::Address(address) if !address.is_empty() => {
+                link.mac = Some(
+                    address
+                        .iter()
+                        .map(|byte| format!("{:02x}", byte))
+                        .collect::<Vec<_>>()
+                        .join(":"),
+                )
+            }
+            _ => {}
+        }
+    }
+    link
+}
+
+fn fdb_message(index: u32, entry: &FdbEntry) -> NeighbourMessage {
+    let mut message = NeighbourMessage::default();
+    message.header.family = AF_BRIDGE as u8;
+    message.header.ifindex = index;
+    message.header.state = NUD_PERMANENT | NUD_NOARP;
+    message.header.flags = NTF_SELF;
+    message
+        .nlas
+        .push(NeighbourNla::LinkLocalAddress(entry.mac.to_vec()));
+    message.nlas.push(NeighbourNla::Destination(
+        entry.destination.octets().to_vec(),
+    ));
+    message
+}
+
+fn parse_fdb_entry(message: NeighbourMessage) -> Option<FdbEntry> {
+    let mut mac = None;
+    let mut destination = None;
+    for nla in message.nlas {
+        match nla {
+            NeighbourNla::LinkLocalAddress(address) => mac = address.try_into().ok(),
+            NeighbourNla::Destination(address) => {
+                destination = <[u8; 4]>::try_from(address).ok().map(Ipv4Addr::from)
+            }
+            _ => {}
+        }
+    }
+    Some(FdbEntry {
+        mac: mac?,
+        destination: destination?,
+    })
+}
+
+// Tables above 255 don't fit the header and are passed as an attribute
+// instead, the same way iproute2 does it.
+fn header_table(table: u32) -> u8 {
+    u8::try_from(table).unwrap_or(RT_TABLE_UNSPEC)
+}
+
+fn route_message(route: &Route) -> RouteMessage {
+    let mut message = RouteMessage::default();
+    message.header.address_family = route
+        .destination
+        .map_or(AF_INET as u8, |destination| family(destination.address));
+    message.header.table = header_table(route.table);
+    message.nlas.push(RouteNla::Table(route.table));
+    if let Some(destination) = route.destination {
+        message.header.destination_prefix_length = destination.prefix;
+        message
+            .nlas
+            .push(RouteNla::Destination(octets(destination.address)));
+    }
+    if let Some(interface) = route.interface {
+        message.nlas.push(RouteNla::Oif(interface));
+    }
+    if let Some(advmss) = route.advmss {
+        let mut metrics = vec![];
+        put_u32(&mut metrics, RTAX_ADVMSS, advmss);
+        message.nlas.push(RouteNla::Metrics(metrics));
+    }
+    message
+}
+
+fn parse_route(message: RouteMessage) -> Route {
+    let prefix = message.header.destination_prefix_length;
+    let mut route = Route {
+        destination: None,
+        interface: None,
+        table: message.header.table.into(),
+        advmss: None,
+    };
+    for nla in message.nlas {
+        match nla {
+            RouteNla::Destination(octets) => route.destination = parse_address(&octets, prefix),
+            RouteNla::Oif(interface) => route.interface = Some(interface),
+            RouteNla::Table(table) => route.table = table,
+            RouteNla::Metrics(metrics) => {
+                route.advmss = parse_attributes(&metrics)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .find(|(kind, _)| *kind == RTAX_ADVMSS)
+                    .and_then(|(_, value)| get_u32(value).ok())
+            }
+            _ => {}
+        }
+    }
+    route
+}
+
+fn rule_message(rule: &Rule) -> RuleMessage {
+    let mut message = RuleMessage::default();
+    message.header.family = rule
+        .destination
+        .map_or(AF_INET as u8, |destination| family(destination.address));
+    message.header.table = header_table(rule.table);
+    message.header.action = FR_ACT_TO_TBL;
+    message.nlas.push(RuleNla::Priority(rule.priority));
+    message.nlas.push(RuleNla::Table(rule.table));
+    if let Some(destination) = rule.destination {
+        message.header.dst_len = destination.prefix;
+        message
+            .nlas
+            .push(RuleNla::Destination(octets(destination.address)));
+    }
+    if rule.fwmark != 0 {
+        message.nlas.push(RuleNla::FwMark(rule.fwmark));
+    }
+    if let Some(suppress_prefixlength) = rule.suppress_prefixlength {
+        message
+            .nlas
+            .push(RuleNla::SuppressPrefixLen(suppress_prefixlength));
+    }
+    message
+}
+
+fn parse_rule(message: RuleMessage) -> Rule {
+    let prefix = message.header.dst_len;
+    let mut rule = Rule {
+        priority: 0,
+        destination: None,
+        fwmark: 0,
+        table: message.header.table.into(),
+        suppress_prefixlength: None,
+    };
+    for nla in message.nlas {
+        match nla {
+            RuleNla::Priority(priority) => rule.priority = priority,
+            RuleNla::Destination(octets) => rule.destination = parse_address(&octets, prefix),
+            RuleNla::FwMark(fwmark) => rule.fwmark = fwmark,
+            RuleNla::Table(table) => rule.table = table,
+            // the kernel reports -1 for rules without suppress_prefixlength
+            RuleNla::SuppressPrefixLen(u32::MAX) => {}
+            RuleNla::SuppressPrefixLen(suppress_prefixlength) => {
+                rule.suppress_prefixlength = Some(suppress_prefixlength)
+            }
+            _ => {}
+        }
+    }
+    rule
+}
+
+fn family(address: IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => AF_INET as u8,
+        IpAddr::V6(_) => AF_INET6 as u8,
+    }
+}
+
+fn octets(address: IpAddr) -> Vec<u8> {
+    match address {
+        IpAddr::V4(address) => address.octets().to_vec(),
+        IpAddr::V6(address) => address.octets().to_vec(),
+    }
+}
+
+fn parse_address(octets: &[u8], prefix: u8) -> Option<Address> {
+    let address = match octets.len() {
+        4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(octets).ok()?)),
+        16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(octets).ok()?)),
+        _ => return None,
+    };
+    Some(Address { address, prefix })
+}
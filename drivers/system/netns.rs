@@ -0,0 +1,66 @@
+use std::{fs::File, thread};
+
+use anyhow::{Context, anyhow};
+use nix::sched::{CloneFlags, setns};
+use tracing::info;
+
+// setns only moves the calling thread, so this has to be the thread's own
+// namespace and not the one of the process.
+const THREAD_NETNS: &str = "/proc/thread-self/ns/net";
+
+// Switches the current thread into a network namespace until dropped, also
+// when the thread unwinds from a panic.
+pub struct NetnsGuard {
+    original_netns_file: File,
+}
+
+impl NetnsGuard {
+    pub fn enter(netns_file: &File) -> anyhow::Result<Self> {
+        let original_netns_file =
+            File::open(THREAD_NETNS).context("failed to open the current netns")?;
+
+        info!("entering netns {:?}", netns_file);
+        setns(netns_file, CloneFlags::CLONE_NEWNET).context("failed to enter the netns")?;
+
+        Ok(NetnsGuard {
+            original_netns_file,
+        })
+    }
+}
+
+impl Drop for NetnsGuard {
+    fn drop(&mut self) {
+        info!("returning back to original netns");
+        if let Err(err) = setns(&self.original_netns_file, CloneFlags::CLONE_NEWNET) {
+            info!("failed to return to the original netns: {}", err);
+        }
+    }
+}
+
+// Runs f inside the given network namespace on a dedicated thread, so that
+// neither the caller nor the async runtime's worker threads ever leave their
+// own namespace.
+pub fn run_in_netns<T, F>(netns_file: &File, f: F) -> anyhow::Result<T>
+where
+    T: Send,
+    F: FnOnce() -> anyhow::Result<T> + Send,
+{
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            let _guard = NetnsGuard::enter(netns_file)?;
+            f()
+        });
+
+        match handle.join() {
+            Ok(result) => result,
+            Err(payload) => {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                Err(anyhow!("netns worker panicked: {}", message))
+            }
+        }
+    })
+}
@@ -0,0 +1,87 @@
+use crate::{
+    executor::Executor,
+    system::netlink::{Address, NetlinkError, Route, Rule},
+};
+
+use thiserror::Error;
+use tracing::info;
+
+#[derive(Debug, Error)]
+#[error("failed to {step}")]
+pub struct StepFailed {
+    pub step: String,
+    #[source]
+    pub source: anyhow::Error,
+}
+
+// How to take back a step which was applied. Removing the interface also
+// removes everything configured on it, the other steps only need to be
+// undone when they outlive it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Undo {
+    DelLink(String),
+    DelAddress(u32, Address),
+    DelRoute(Route),
+    DelRule(Rule),
+}
+
+// Records the steps applied to a netns, so that a configuration which fails
+// halfway can be rolled back instead of being left partially applied.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    applied: Vec<(String, Undo)>,
+}
+
+impl Transaction {
+    pub fn apply<T, E>(
+        &mut self,
+        step: impl Into<String>,
+        undo: Option<Undo>,
+        f: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, StepFailed>
+    where
+        E: Into<anyhow::Error>,
+    {
+        let step = step.into();
+        info!("applying step: {}", step);
+        match f() {
+            Ok(value) => {
+                if let Some(undo) = undo {
+                    self.applied.push((step, undo));
+                }
+                Ok(value)
+            }
+            Err(err) => Err(StepFailed {
+                step,
+                source: err.into(),
+            }),
+        }
+    }
+
+    pub fn record(&mut self, step: impl Into<String>, undo: Undo) {
+        self.applied.push((step.into(), undo));
+    }
+
+    // Undoes the applied steps in reverse order. Rollback is best effort:
+    // a step which can't be undone is logged and the rest still are, DEL
+    // removes whatever is left over.
+    pub fn rollback(self, executor: &mut impl Executor) {
+        for (step, undo) in self.applied.into_iter().rev() {
+            info!("rolling back step: {}", step);
+            let result = match &undo {
+                Undo::DelLink(name) => match executor.link(name) {
+                    Ok(Some(link)) => executor.del_link(link.index),
+                    Ok(None) => Ok(()),
+                    Err(err) => Err(err),
+                },
+                Undo::DelAddress(index, address) => executor.del_address(*index, *address),
+                Undo::DelRoute(route) => executor.del_route(route),
+                Undo::DelRule(rule) => executor.del_rule(rule),
+            };
+            match result {
+                Ok(()) | Err(NetlinkError::NotFound | NetlinkError::NoDevice) => {}
+                Err(err) => info!("failed to roll back step {}: {}", step, err),
+            }
+        }
+    }
+}
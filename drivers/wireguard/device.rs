@@ -0,0 +1,423 @@
+use crate::{
+    system::{
+        genetlink::{
+            GenericNetlink, fixed, get_string, get_u16, get_u32, get_u64, parse_attributes, put,
+            put_nested, put_string, put_u16, put_u32,
+        },
+        netlink::{Address, NetlinkError},
+    },
+    wireguard::{
+        key::{KEY_LEN, PresharedKey, PrivateKey, PublicKey},
+        userspace::{self, uapi::Uapi},
+    },
+};
+use api::wireguard::WireguardMode;
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    time::{Duration, SystemTime},
+};
+
+use netlink_packet_core::NLM_F_DUMP;
+
+// see include/uapi/linux/wireguard.h
+const WG_GENL_NAME: &str = "wireguard";
+const WG_GENL_VERSION: u8 = 1;
+
+const WG_CMD_GET_DEVICE: u8 = 0;
+const WG_CMD_SET_DEVICE: u8 = 1;
+
+const WGDEVICE_A_IFINDEX: u16 = 1;
+const WGDEVICE_A_IFNAME: u16 = 2;
+const WGDEVICE_A_PRIVATE_KEY: u16 = 3;
+const WGDEVICE_A_PUBLIC_KEY: u16 = 4;
+const WGDEVICE_A_FLAGS: u16 = 5;
+const WGDEVICE_A_LISTEN_PORT: u16 = 6;
+const WGDEVICE_A_FWMARK: u16 = 7;
+const WGDEVICE_A_PEERS: u16 = 8;
+
+const WGDEVICE_F_REPLACE_PEERS: u32 = 1 << 0;
+
+const WGPEER_A_PUBLIC_KEY: u16 = 1;
+const WGPEER_A_PRESHARED_KEY: u16 = 2;
+const WGPEER_A_FLAGS: u16 = 3;
+const WGPEER_A_ENDPOINT: u16 = 4;
+const WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL: u16 = 5;
+const WGPEER_A_LAST_HANDSHAKE_TIME: u16 = 6;
+const WGPEER_A_RX_BYTES: u16 = 7;
+const WGPEER_A_TX_BYTES: u16 = 8;
+const WGPEER_A_ALLOWEDIPS: u16 = 9;
+
+const WGPEER_F_REMOVE_ME: u32 = 1 << 0;
+const WGPEER_F_REPLACE_ALLOWEDIPS: u32 = 1 << 1;
+const WGPEER_F_UPDATE_ONLY: u32 = 1 << 2;
+
+const WGALLOWEDIP_A_FAMILY: u16 = 1;
+const WGALLOWEDIP_A_IPADDR: u16 = 2;
+const WGALLOWEDIP_A_CIDR_MASK: u16 = 3;
+
+#[derive(Clone, Debug, Default)]
+pub struct Device {
+    pub index: u32,
+    pub name: String,
+    pub private_key: Option<PrivateKey>,
+    pub public_key: Option<PublicKey>,
+    pub listen_port: u16,
+    pub fwmark: u32,
+    pub peers: Vec<Peer>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Peer {
+    pub public_key: PublicKey,
+    pub preshared_key: Option<PresharedKey>,
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<Address>,
+    pub persistent_keepalive: u16,
+    pub last_handshake: Option<SystemTime>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+// Fields left as None are not changed on the device. All of it is applied in
+// a single request, which the kernel applies as a whole.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceUpdate {
+    pub private_key: Option<PrivateKey>,
+    pub listen_port: Option<u16>,
+    pub fwmark: Option<u32>,
+    pub replace_peers: bool,
+    pub peers: Vec<PeerUpdate>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PeerUpdate {
+    pub public_key: PublicKey,
+    pub remove: bool,
+    pub update_only: bool,
+    // an all zero key removes the one the peer has
+    pub preshared_key: Option<PresharedKey>,
+    pub endpoint: Option<SocketAddr>,
+    pub persistent_keepalive: Option<u16>,
+    pub replace_allowed_ips: bool,
+    pub allowed_ips: Vec<Address>,
+}
+
+impl PeerUpdate {
+    pub fn new(public_key: PublicKey) -> Self {
+        PeerUpdate {
+            public_key,
+            remove: false,
+            update_only: false,
+            preshared_key: None,
+            endpoint: None,
+            persistent_keepalive: None,
+            replace_allowed_ips: false,
+            allowed_ips: vec![],
+        }
+    }
+
+    pub fn remove(public_key: PublicKey) -> Self {
+        PeerUpdate {
+            remove: true,
+            ..PeerUpdate::new(public_key)
+        }
+    }
+}
+
+// A handle on the wireguard generic netlink family of the network namespace
+// it was opened in.
+pub struct WireguardNetlink {
+    connection: GenericNetlink,
+    family: u16,
+}
+
+impl WireguardNetlink {
+    pub fn open() -> Result<Self, NetlinkError> {
+        let mut connection = GenericNetlink::open()?;
+        let family = connection.resolve_family(WG_GENL_NAME)?;
+        Ok(WireguardNetlink { connection, family })
+    }
+
+    pub fn get_device(&mut self, name: &str) -> Result<Device, NetlinkError> {
+        let mut attributes = vec![];
+        put_string(&mut attributes, WGDEVICE_A_IFNAME, name);
+
+        let replies = self.connection.request(
+            self.family,
+            WG_CMD_GET_DEVICE,
+            WG_GENL_VERSION,
+            attributes,
+            NLM_F_DUMP,
+        )?;
+
+        // devices with many peers are split over several messages, each one
+        // repeating the device and continuing where the last peer left off
+        let mut device = Device::default();
+        for reply in replies {
+            parse_device(&reply.attributes, &mut device)?;
+        }
+        Ok(device)
+    }
+
+    pub fn set_device(&mut self, name: &str, update: &DeviceUpdate) -> Result<(), NetlinkError> {
+        let mut attributes = vec![];
+        put_string(&mut attributes, WGDEVICE_A_IFNAME, name);
+        if let Some(private_key) = &update.private_key {
+            put(
+                &mut attributes,
+                WGDEVICE_A_PRIVATE_KEY,
+                private_key.as_bytes(),
+            );
+        }
+        if let Some(listen_port) = update.listen_port {
+            put_u16(&mut attributes, WGDEVICE_A_LISTEN_PORT, listen_port);
+        }
+        if let Some(fwmark) = update.fwmark {
+            put_u32(&mut attributes, WGDEVICE_A_FWMARK, fwmark);
+        }
+        if update.replace_peers {
+            put_u32(&mut attributes, WGDEVICE_A_FLAGS, WGDEVICE_F_REPLACE_PEERS);
+        }
+        put_nested(&mut attributes, WGDEVICE_A_PEERS, |peers| {
+            for (index, peer) in update.peers.iter().enumerate() {
+                put_nested(peers, index as u16, |attributes| {
+                    emit_peer(attributes, peer)
+                });
+            }
+        });
+
+        self.connection.request(
+            self.family,
+            WG_CMD_SET_DEVICE,
+            WG_GENL_VERSION,
+            attributes,
+            0,
+        )?;
+        Ok(())
+    }
+}
+
+// Drives whichever implementation backs the device in the netns it was
+// opened in: the kernel module, or the userspace fallback when its control
+// socket exists.
+pub enum WireguardControl {
+    Kernel(WireguardNetlink),
+    Userspace(Uapi),
+}
+
+impl WireguardControl {
+    pub fn open(interface_name: &str) -> anyhow::Result<Self> {
+        let socket_path = userspace::socket_path(interface_name)?;
+        if socket_path.exists() {
+            return Ok(WireguardControl::Userspace(Uapi::open(socket_path)));
+        }
+        Ok(WireguardControl::Kernel(WireguardNetlink::open()?))
+    }
+
+    pub fn mode(&self) -> WireguardMode {
+        match self {
+            WireguardControl::Kernel(_) => WireguardMode::Kernel,
+            WireguardControl::Userspace(_) => WireguardMode::Userspace,
+        }
+    }
+
+    pub fn get_device(&mut self, name: &str) -> anyhow::Result<Device> {
+        match self {
+            WireguardControl::Kernel(netlink) => Ok(netlink.get_device(name)?),
+            WireguardControl::Userspace(uapi) => uapi.get_device(name),
+        }
+    }
+
+    pub fn set_device(&mut self, name: &str, update: &DeviceUpdate) -> anyhow::Result<()> {
+        match self {
+            WireguardControl::Kernel(netlink) => Ok(netlink.set_device(name, update)?),
+            WireguardControl::Userspace(uapi) => uapi.set_device(name, update),
+        }
+    }
+}
+
+fn emit_peer(attributes: &mut Vec<u8>, peer: &PeerUpdate) {
+    put(attributes, WGPEER_A_PUBLIC_KEY, peer.public_key.as_bytes());
+
+    let mut flags = 0;
+    if peer.remove {
+        flags |= WGPEER_F_REMOVE_ME;
+    }
+    if peer.update_only {
+        flags |= WGPEER_F_UPDATE_ONLY;
+    }
+    if peer.replace_allowed_ips {
+        flags |= WGPEER_F_REPLACE_ALLOWEDIPS;
+    }
+    put_u32(attributes, WGPEER_A_FLAGS, flags);
+
+    if let Some(preshared_key) = &peer.preshared_key {
+        put(attributes, WGPEER_A_PRESHARED_KEY, preshared_key.as_bytes());
+    }
+
+    if let Some(endpoint) = peer.endpoint {
+        put(attributes, WGPEER_A_ENDPOINT, &sockaddr(endpoint));
+    }
+    if let Some(persistent_keepalive) = peer.persistent_keepalive {
+        put_u16(
+            attributes,
+            WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL,
+            persistent_keepalive,
+        );
+    }
+    put_nested(attributes, WGPEER_A_ALLOWEDIPS, |allowed_ips| {
+        for (index, allowed_ip) in peer.allowed_ips.iter().enumerate() {
+            put_nested(allowed_ips, index as u16, |attributes| {
+                let (family, octets) = match allowed_ip.address {
+                    IpAddr::V4(address) => (libc::AF_INET, address.octets().to_vec()),
+                    IpAddr::V6(address) => (libc::AF_INET6, address.octets().to_vec()),
+                };
+                put_u16(attributes, WGALLOWEDIP_A_FAMILY, family as u16);
+                put(attributes, WGALLOWEDIP_A_IPADDR, &octets);
+                put(attributes, WGALLOWEDIP_A_CIDR_MASK, &[allowed_ip.prefix]);
+            });
+        }
+    });
+}
+
+fn parse_device(buffer: &[u8], device: &mut Device) -> Result<(), NetlinkError> {
+    for (kind, value) in parse_attributes(buffer)? {
+        match kind {
+            WGDEVICE_A_IFINDEX => device.index = get_u32(value)?,
+            WGDEVICE_A_IFNAME => device.name = get_string(value)?,
+            WGDEVICE_A_PRIVATE_KEY => device.private_key = parse_key(value)?.map(PrivateKey::from),
+            WGDEVICE_A_PUBLIC_KEY => device.public_key = parse_key(value)?.map(PublicKey::from),
+            WGDEVICE_A_LISTEN_PORT => device.listen_port = get_u16(value)?,
+            WGDEVICE_A_FWMARK => device.fwmark = get_u32(value)?,
+            WGDEVICE_A_PEERS => {
+                for (_, peer) in parse_attributes(value)? {
+                    let peer = parse_peer(peer)?;
+                    match device.peers.last_mut() {
+                        Some(last) if last.public_key == peer.public_key => {
+                            last.allowed_ips.extend(peer.allowed_ips)
+                        }
+                        _ => device.peers.push(peer),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn parse_peer(buffer: &[u8]) -> Result<Peer, NetlinkError> {
+    let mut peer = Peer {
+        public_key: PublicKey::from([0; KEY_LEN]),
+        preshared_key: None,
+        endpoint: None,
+        allowed_ips: vec![],
+        persistent_keepalive: 0,
+        last_handshake: None,
+        rx_bytes: 0,
+        tx_bytes: 0,
+    };
+    for (kind, value) in parse_attributes(buffer)? {
+        match kind {
+            WGPEER_A_PUBLIC_KEY => peer.public_key = PublicKey::from(fixed(value)?),
+            WGPEER_A_PRESHARED_KEY => {
+                peer.preshared_key = parse_key(value)?.map(PresharedKey::from)
+            }
+            WGPEER_A_ENDPOINT => peer.endpoint = parse_sockaddr(value)?,
+            WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL => peer.persistent_keepalive = get_u16(value)?,
+            WGPEER_A_LAST_HANDSHAKE_TIME => peer.last_handshake = parse_timespec(value)?,
+            WGPEER_A_RX_BYTES => peer.rx_bytes = get_u64(value)?,
+            WGPEER_A_TX_BYTES => peer.tx_bytes = get_u64(value)?,
+            WGPEER_A_ALLOWEDIPS => {
+                for (_, allowed_ip) in parse_attributes(value)? {
+                    peer.allowed_ips.extend(parse_allowed_ip(allowed_ip)?);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(peer)
+}
+
+fn parse_allowed_ip(buffer: &[u8]) -> Result<Option<Address>, NetlinkError> {
+    let mut address = None;
+    let mut prefix = None;
+    for (kind, value) in parse_attributes(buffer)? {
+        match kind {
+            WGALLOWEDIP_A_IPADDR => {
+                address = match value.len() {
+                    4 => Some(IpAddr::V4(Ipv4Addr::from(fixed::<4>(value)?))),
+                    16 => Some(IpAddr::V6(Ipv6Addr::from(fixed::<16>(value)?))),
+                    _ => None,
+                }
+            }
+            WGALLOWEDIP_A_CIDR_MASK => prefix = value.first().copied(),
+            _ => {}
+        }
+    }
+    Ok(address
+        .zip(prefix)
+        .map(|(address, prefix)| Address { address, prefix }))
+}
+
+// unset keys are reported as all zeroes
+fn parse_key(value: &[u8]) -> Result<Option<[u8; KEY_LEN]>, NetlinkError> {
+    let key: [u8; KEY_LEN] = fixed(value)?;
+    Ok(Some(key).filter(|key| key.iter().any(|byte| *byte != 0)))
+}
+
+fn parse_timespec(value: &[u8]) -> Result<Option<SystemTime>, NetlinkError> {
+    let seconds = get_u64(&value[..value.len().min(8)])?;
+    let nanoseconds = get_u64(&value[value.len().min(8)..])?;
+    if seconds == 0 && nanoseconds == 0 {
+        return Ok(None);
+    }
+    Ok(SystemTime::UNIX_EPOCH.checked_add(Duration::new(seconds, nanoseconds as u32)))
+}
+
+// struct sockaddr_in and sockaddr_in6, with the port and flow info in
+// network byte order and the family in host byte order
+fn sockaddr(endpoint: SocketAddr) -> Vec<u8> {
+    let mut buffer = vec![];
+    match endpoint {
+        SocketAddr::V4(endpoint) => {
+            buffer.extend_from_slice(&(libc::AF_INET as u16).to_ne_bytes());
+            buffer.extend_from_slice(&endpoint.port().to_be_bytes());
+            buffer.extend_from_slice(&endpoint.ip().octets());
+            buffer.extend_from_slice(&[0; 8]);
+        }
+        SocketAddr::V6(endpoint) => {
+            buffer.extend_from_slice(&(libc::AF_INET6 as u16).to_ne_bytes());
+            buffer.extend_from_slice(&endpoint.port().to_be_bytes());
+            buffer.extend_from_slice(&endpoint.flowinfo().to_be_bytes());
+            buffer.extend_from_slice(&endpoint.ip().octets());
+            buffer.extend_from_slice(&endpoint.scope_id().to_ne_bytes());
+        }
+    }
+    buffer
+}
+
+fn parse_sockaddr(value: &[u8]) -> Result<Option<SocketAddr>, NetlinkError> {
+    if value.len() < 2 {
+        return Ok(None);
+    }
+    let family = u16::from_ne_bytes([value[0], value[1]]) as i32;
+    match family {
+        libc::AF_INET if value.len() >= 8 => {
+            let port = u16::from_be_bytes([value[2], value[3]]);
+            let address = Ipv4Addr::from(fixed::<4>(&value[4..8])?);
+            Ok(Some(SocketAddr::V4(SocketAddrV4::new(address, port))))
+        }
+        libc::AF_INET6 if value.len() >= 28 => {
+            let port = u16::from_be_bytes([value[2], value[3]]);
+            let flowinfo = u32::from_be_bytes(fixed(&value[4..8])?);
+            let address = Ipv6Addr::from(fixed::<16>(&value[8..24])?);
+            let scope_id = u32::from_ne_bytes(fixed(&value[24..28])?);
+            Ok(Some(SocketAddr::V6(SocketAddrV6::new(
+                address, port, flowinfo, scope_id,
+            ))))
+        }
+        _ => Ok(None),
+    }
+}
@@ -1,13 +1,237 @@
-use crate::system::linux::{run, run_with_stdin};
+use std::{fmt, str::FromStr};
 
-use anyhow::Context;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use thiserror::Error;
+use x25519_dalek::StaticSecret;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
-pub type PrivateKey = String;
-pub type PublicKey = String;
+pub const KEY_LEN: usize = 32;
 
-pub fn generate() -> anyhow::Result<(PrivateKey, PublicKey)> {
-    let private_key = run("wg", vec!["genkey"]).context("private key generation failed")?;
-    let public_key = run_with_stdin("wg", vec!["pubkey"], &private_key)
-        .context("public key generation failed")?;
-    Ok((private_key, public_key))
+#[derive(Debug, Error)]
+pub enum InvalidKey {
+    #[error("key is not valid base64")]
+    Encoding,
+    #[error("key is {0} bytes instead of {KEY_LEN}")]
+    Length(usize),
+}
+
+// Private keys are wiped from memory when dropped and never show up in logs,
+// the only way to get at the encoded key is to ask for it explicitly.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct PrivateKey([u8; KEY_LEN]);
+
+impl PrivateKey {
+    pub fn generate() -> Self {
+        PrivateKey(StaticSecret::random().to_bytes())
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        let secret = StaticSecret::from(self.0);
+        PublicKey(x25519_dalek::PublicKey::from(&secret).to_bytes())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    pub fn to_base64(&self) -> Zeroizing<String> {
+        Zeroizing::new(STANDARD.encode(self.0))
+    }
+}
+
+impl From<[u8; KEY_LEN]> for PrivateKey {
+    fn from(key: [u8; KEY_LEN]) -> Self {
+        PrivateKey(key)
+    }
+}
+
+impl FromStr for PrivateKey {
+    type Err = InvalidKey;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        decode(key).map(|key| PrivateKey(*key))
+    }
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PrivateKey(<redacted>)")
+    }
+}
+
+// An extra symmetric key mixed into the handshake with one peer. Both sides
+// have to hold the same key, it's as secret as a private key.
+#[derive(Clone, PartialEq, Eq, Zeroize, ZeroizeOnDrop)]
+pub struct PresharedKey([u8; KEY_LEN]);
+
+impl PresharedKey {
+    pub fn generate() -> Self {
+        let mut key = [0; KEY_LEN];
+        getrandom::getrandom(&mut key).expect("the system random number generator failed");
+        PresharedKey(key)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    // WireGuard takes and reports an all zero key for no key at all
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|byte| *byte == 0)
+    }
+
+    pub fn to_base64(&self) -> Zeroizing<String> {
+        Zeroizing::new(STANDARD.encode(self.0))
+    }
+}
+
+impl From<[u8; KEY_LEN]> for PresharedKey {
+    fn from(key: [u8; KEY_LEN]) -> Self {
+        PresharedKey(key)
+    }
+}
+
+impl FromStr for PresharedKey {
+    type Err = InvalidKey;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        decode(key).map(|key| PresharedKey(*key))
+    }
+}
+
+impl fmt::Debug for PresharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PresharedKey(<redacted>)")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicKey([u8; KEY_LEN]);
+
+impl PublicKey {
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+}
+
+impl From<[u8; KEY_LEN]> for PublicKey {
+    fn from(key: [u8; KEY_LEN]) -> Self {
+        PublicKey(key)
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = InvalidKey;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        decode(key).map(|key| PublicKey(*key))
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&STANDARD.encode(self.0))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+pub fn generate() -> (PrivateKey, PublicKey) {
+    let private_key = PrivateKey::generate();
+    let public_key = private_key.public_key();
+    (private_key, public_key)
+}
+
+fn decode(key: &str) -> Result<Zeroizing<[u8; KEY_LEN]>, InvalidKey> {
+    let decoded = Zeroizing::new(
+        STANDARD
+            .decode(key.trim())
+            .map_err(|_| InvalidKey::Encoding)?,
+    );
+    let key: [u8; KEY_LEN] = decoded
+        .as_slice()
+        .try_into()
+        .map_err(|_| InvalidKey::Length(decoded.len()))?;
+    Ok(Zeroizing::new(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 7748, section 6.1, encoded like wg genkey and wg pubkey do
+    const ALICE_PRIVATE_KEY: &str = "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=";
+    const ALICE_PUBLIC_KEY: &str = "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo=";
+    const BOB_PRIVATE_KEY: &str = "XasIfmJKikt54X+Lg4AO5m87sSkmGLb9HC+LJ/+I4Os=";
+    const BOB_PUBLIC_KEY: &str = "3p7bfXt9wbTTW2HC7OQ1Nz+DQ8hbeGdNrfx+FG+IK08=";
+
+    const SHORT_KEY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA==";
+    const LONG_KEY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA";
+
+    #[test]
+    fn derives_the_known_public_keys() {
+        for (private_key, public_key) in [
+            (ALICE_PRIVATE_KEY, ALICE_PUBLIC_KEY),
+            (BOB_PRIVATE_KEY, BOB_PUBLIC_KEY),
+        ] {
+            let private_key: PrivateKey = private_key.parse().unwrap();
+            assert_eq!(private_key.public_key().to_string(), public_key);
+            assert_eq!(private_key.public_key(), public_key.parse().unwrap());
+        }
+    }
+
+    #[test]
+    fn round_trips_keys() {
+        let private_key: PrivateKey = format!("{}\n", ALICE_PRIVATE_KEY).parse().unwrap();
+        assert_eq!(*private_key.to_base64(), ALICE_PRIVATE_KEY);
+
+        let preshared_key: PresharedKey = BOB_PRIVATE_KEY.parse().unwrap();
+        assert_eq!(*preshared_key.to_base64(), BOB_PRIVATE_KEY);
+        assert!(!preshared_key.is_zero());
+    }
+
+    #[test]
+    fn rejects_invalid_keys() {
+        fn errors(key: &str) -> [InvalidKey; 3] {
+            [
+                key.parse::<PrivateKey>().unwrap_err(),
+                key.parse::<PublicKey>().unwrap_err(),
+                key.parse::<PresharedKey>().unwrap_err(),
+            ]
+        }
+
+        // None for keys which aren't base64, or the decoded length
+        for (key, len) in [
+            ("not base64!", None),
+            ("hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo", None),
+            ("", Some(0)),
+            (SHORT_KEY, Some(31)),
+            (LONG_KEY, Some(33)),
+        ] {
+            for err in errors(key) {
+                match (err, len) {
+                    (InvalidKey::Encoding, None) => {}
+                    (InvalidKey::Length(decoded), Some(len)) => assert_eq!(decoded, len, "{}", key),
+                    (err, _) => panic!("{}: unexpected {:?}", key, err),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn redacts_secret_keys() {
+        let private_key: PrivateKey = ALICE_PRIVATE_KEY.parse().unwrap();
+        let preshared_key: PresharedKey = BOB_PRIVATE_KEY.parse().unwrap();
+
+        assert_eq!(format!("{:?}", private_key), "PrivateKey(<redacted>)");
+        assert_eq!(format!("{:?}", preshared_key), "PresharedKey(<redacted>)");
+        assert_eq!(
+            format!("{:?}", private_key.public_key()),
+            format!("PublicKey({})", ALICE_PUBLIC_KEY)
+        );
+    }
 }
@@ -1,2 +1,6 @@
+pub mod device;
+pub mod driver;
 pub mod key;
+pub mod status;
+pub mod userspace;
 pub mod workflows;
@@ -0,0 +1,193 @@
+use crate::{kube::kube_client, settings::Settings, wireguard::userspace};
+use api::wireguard::WireguardConfig;
+
+use std::{fs, path::Path};
+
+use kube::{Api, Error as KubeError, api::ListParams};
+use thiserror::Error;
+use tracing::info;
+
+const WIREGUARD_MODULE: &str = "/sys/module/wireguard";
+const MODULES_DIR: &str = "/lib/modules";
+
+#[derive(Debug, Error)]
+pub enum NotAvailable {
+    #[error(
+        "the wireguard kernel module is missing and the userspace fallback is disabled or the podtunnel agent is not running"
+    )]
+    MissingKernelModule,
+    #[error("failed to load kubeconfig: {0:#}")]
+    Kubeconfig(anyhow::Error),
+    #[error("the WireguardConfig CRD is not installed")]
+    MissingCrds,
+    #[error("the Kubernetes API is unreachable: {0}")]
+    ApiUnreachable(KubeError),
+}
+
+// Reports whether this node can service ADD requests at all, so that a node
+// which can't is flagged once instead of failing every Pod on it.
+pub async fn plugin_status(settings: &Settings) -> Result<(), NotAvailable> {
+    // without the module, tunnels may fall back to userspace devices hosted
+    // by the node agent
+    info!("checking for the wireguard kernel module");
+    let falls_back = settings.userspace_fallback && userspace::is_supported();
+    if !has_wireguard_module() && !falls_back {
+        return Err(NotAvailable::MissingKernelModule);
+    }
+
+    info!("checking the kubeconfig");
+    let kube_client = kube_client(settings)
+        .await
+        .map_err(NotAvailable::Kubeconfig)?;
+
+    info!("checking the Kubernetes API");
+    let wireguard_configs: Api<WireguardConfig> = Api::all(kube_client);
+    match wireguard_configs
+        .list_metadata(&ListParams::default().limit(1))
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(KubeError::Api(api_err)) if api_err.code == 404 => Err(NotAvailable::MissingCrds),
+        Err(err) => Err(NotAvailable::ApiUnreachable(err)),
+    }
+}
+
+// wireguard may also be built into the kernel, in which case it's listed in
+// modules.builtin, or be a module the kernel loads when the first interface
+// is created, in which case it's listed in modules.dep or at least installed.
+fn has_wireguard_module() -> bool {
+    let Ok(release) = fs::read_to_string("/proc/sys/kernel/osrelease") else {
+        return Path::new(WIREGUARD_MODULE).exists();
+    };
+    has_wireguard_module_in(
+        Path::new(WIREGUARD_MODULE),
+        &Path::new(MODULES_DIR).join(release.trim()),
+    )
+}
+
+fn has_wireguard_module_in(loaded_module: &Path, modules_dir: &Path) -> bool {
+    if loaded_module.exists() {
+        return true;
+    }
+
+    let lists_wireguard = |list: &str, module_of: fn(&str) -> &str| {
+        fs::read_to_string(modules_dir.join(list))
+            .is_ok_and(|modules| modules.lines().any(|line| is_wireguard(module_of(line))))
+    };
+    // modules.dep lines are the module followed by its dependencies
+    lists_wireguard("modules.builtin", |line| line)
+        || lists_wireguard("modules.dep", |line| {
+            line.split_once(':').map_or(line, |(module, _)| module)
+        })
+        || installs_wireguard(&modules_dir.join("kernel"))
+}
+
+// modules may be compressed, as wireguard.ko.xz or wireguard.ko.zst
+fn is_wireguard(module: &str) -> bool {
+    let name = module.rsplit('/').next().unwrap_or(module);
+    name == "wireguard.ko" || name.starts_with("wireguard.ko.")
+}
+
+fn installs_wireguard(dir: &Path) -> bool {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => installs_wireguard(&path),
+            Ok(_) => is_wireguard(&entry.file_name().to_string_lossy()),
+            Err(_) => false,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    fn modules_dir() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::write(
+            dir.path().join("modules.builtin"),
+            "kernel/fs/ext4/ext4.ko\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("modules.dep"),
+            "kernel/fs/fat/vfat.ko: kernel/fs/fat/fat.ko\n",
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn finds_a_loaded_module() {
+        let modules = modules_dir();
+        let loaded = modules.path().join("sys-module-wireguard");
+        fs::create_dir(&loaded).unwrap();
+
+        assert!(has_wireguard_module_in(&loaded, modules.path()));
+    }
+
+    #[test]
+    fn finds_a_builtin_module() {
+        let modules = modules_dir();
+        fs::write(
+            modules.path().join("modules.builtin"),
+            "kernel/fs/ext4/ext4.ko\nkernel/drivers/net/wireguard/wireguard.ko\n",
+        )
+        .unwrap();
+
+        assert!(has_wireguard_module_in(
+            &modules.path().join("unloaded"),
+            modules.path()
+        ));
+    }
+
+    #[test]
+    fn finds_a_loadable_module() {
+        let modules = modules_dir();
+        fs::write(
+            modules.path().join("modules.dep"),
+            "kernel/drivers/net/wireguard/wireguard.ko.zst: kernel/net/ipv6/ip6_udp_tunnel.ko.zst\n",
+        )
+        .unwrap();
+
+        assert!(has_wireguard_module_in(
+            &modules.path().join("unloaded"),
+            modules.path()
+        ));
+    }
+
+    #[test]
+    fn finds_an_installed_module_missing_from_modules_dep() {
+        let modules = modules_dir();
+        let directory = modules.path().join("kernel/drivers/net/wireguard");
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("wireguard.ko.xz"), "").unwrap();
+
+        assert!(has_wireguard_module_in(
+            &modules.path().join("unloaded"),
+            modules.path()
+        ));
+    }
+
+    #[test]
+    fn misses_an_absent_module() {
+        let modules = modules_dir();
+        // a dependency named like it isn't the module itself
+        fs::write(
+            modules.path().join("modules.dep"),
+            "kernel/net/foo.ko: kernel/drivers/net/wireguard/wireguard.ko\n",
+        )
+        .unwrap();
+
+        assert!(!has_wireguard_module_in(
+            &modules.path().join("unloaded"),
+            modules.path()
+        ));
+    }
+}
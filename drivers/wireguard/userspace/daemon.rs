@@ -0,0 +1,815 @@
+use crate::{
+    system::netlink::Address,
+    wireguard::{
+        device::{Device, DeviceUpdate, Peer, PeerUpdate},
+        key::{PresharedKey, PrivateKey, PublicKey},
+        userspace::{
+            SavedDevice,
+            noise::{self, Cookie, Identity, PendingHandshake, Session},
+            tun::Tun,
+            uapi::{format_device, format_update, parse_update, read_message},
+        },
+    },
+};
+
+use std::{
+    collections::VecDeque,
+    ffi::CString,
+    io::{self, BufReader, ErrorKind, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    os::{
+        fd::AsRawFd,
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, bail};
+use tracing::info;
+
+// see https://www.wireguard.com/papers/wireguard.pdf, section 6
+const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+const REKEY_ATTEMPT_TIME: Duration = Duration::from_secs(90);
+const REKEY_TIMEOUT: Duration = Duration::from_secs(5);
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+const COOKIE_LIFETIME: Duration = Duration::from_secs(120);
+
+const TICK: Duration = Duration::from_millis(250);
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_QUEUED_PACKETS: usize = 128;
+const MAX_PACKET_LEN: usize = 65535;
+
+// A WireGuard device driven entirely from userspace: packets routed to the
+// TUN interface are encrypted and sent to their peer over UDP, and the other
+// way round. Everything runs on the one thread, timers are checked on every
+// tick.
+//
+// Only what podtunnel needs is implemented. Endpoints are IPv4 like
+// everywhere else, and cookies are never sent. The cookies of peers under
+// load are used on the handshakes retried after REKEY_TIMEOUT.
+pub struct Daemon {
+    tun: Tun,
+    socket: UdpSocket,
+    listener: UnixListener,
+    identity: Option<Identity>,
+    private_key: Option<PrivateKey>,
+    listen_port: u16,
+    fwmark: u32,
+    peers: Vec<PeerState>,
+    state_path: PathBuf,
+    saved: SavedDevice,
+}
+
+struct PeerState {
+    public_key: PublicKey,
+    preshared_key: Option<PresharedKey>,
+    endpoint: Option<SocketAddr>,
+    allowed_ips: Vec<Address>,
+    persistent_keepalive: u16,
+    last_handshake: Option<SystemTime>,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    pending: Option<PendingHandshake>,
+    attempts_started: Option<Instant>,
+    // initiations carry a timestamp which has to grow, so they can't be
+    // replayed
+    last_initiation: [u8; 12],
+    // the sender index and mac1 of the last handshake message sent, which
+    // a cookie reply answers
+    last_mac1: Option<(u32, [u8; 16])>,
+    cookie: Option<(Instant, Cookie)>,
+    current: Option<Session>,
+    previous: Option<Session>,
+    next: Option<Session>,
+    queue: VecDeque<Vec<u8>>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl PeerState {
+    fn new(public_key: PublicKey) -> Self {
+        PeerState {
+            public_key,
+            preshared_key: None,
+            endpoint: None,
+            allowed_ips: vec![],
+            persistent_keepalive: 0,
+            last_handshake: None,
+            rx_bytes: 0,
+            tx_bytes: 0,
+            pending: None,
+            attempts_started: None,
+            last_initiation: [0; 12],
+            last_mac1: None,
+            cookie: None,
+            current: None,
+            previous: None,
+            next: None,
+            queue: VecDeque::new(),
+            last_sent: None,
+            last_received: None,
+        }
+    }
+
+    fn clear_sessions(&mut self) {
+        self.pending = None;
+        self.attempts_started = None;
+        self.current = None;
+        self.previous = None;
+        self.next = None;
+        self.queue.clear();
+    }
+
+    fn cookie(&self) -> Option<&Cookie> {
+        self.cookie
+            .as_ref()
+            .filter(|(received, _)| received.elapsed() < COOKIE_LIFETIME)
+            .map(|(_, cookie)| cookie)
+    }
+
+    fn sessions(&self) -> impl Iterator<Item = &Session> {
+        [&self.current, &self.previous, &self.next]
+            .into_iter()
+            .flatten()
+    }
+}
+
+impl Daemon {
+    pub fn new(
+        tun: Tun,
+        listener: UnixListener,
+        state_path: PathBuf,
+        saved: SavedDevice,
+    ) -> anyhow::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Daemon {
+            tun,
+            socket: bind(0, 0)?,
+            listener,
+            identity: None,
+            private_key: None,
+            listen_port: 0,
+            fwmark: 0,
+            peers: vec![],
+            state_path,
+            saved,
+        })
+    }
+
+    // Runs until the interface is deleted or the netns it was created for
+    // goes away.
+    pub fn serve(&mut self, interface_name: &str, netns: &Path) -> anyhow::Result<()> {
+        let interface_name = CString::new(interface_name)?;
+        let mut buffer = vec![0u8; MAX_PACKET_LEN];
+        let mut last_tick = Instant::now();
+
+        loop {
+            let mut fds = [
+                pollfd(self.tun.as_raw_fd()),
+                pollfd(self.socket.as_raw_fd()),
+                pollfd(self.listener.as_raw_fd()),
+            ];
+            // SAFETY: fds is valid for the duration of the call
+            let ready = unsafe {
+                libc::poll(
+                    fds.as_mut_ptr(),
+                    fds.len() as libc::nfds_t,
+                    TICK.as_millis() as libc::c_int,
+                )
+            };
+            if ready < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err.into());
+            }
+
+            // the TUN fd reports an error once its interface was deleted
+            if fds[0].revents & (libc::POLLERR | libc::POLLHUP) != 0 {
+                return Ok(());
+            }
+            if fds[0].revents & libc::POLLIN != 0 {
+                while let Some(len) = would_block(self.tun.recv(&mut buffer))? {
+                    self.handle_outbound(&buffer[..len]);
+                }
+            }
+            if fds[1].revents & libc::POLLIN != 0 {
+                while let Some((len, source)) = would_block(self.socket.recv_from(&mut buffer))? {
+                    self.handle_inbound(&buffer[..len], source);
+                }
+            }
+            if fds[2].revents & libc::POLLIN != 0 {
+                while let Some((stream, _)) = would_block(self.listener.accept())? {
+                    if let Err(err) = self.handle_control(stream) {
+                        info!("userspace wireguard control request failed: {:#}", err);
+                    }
+                }
+            }
+
+            if last_tick.elapsed() >= TICK {
+                last_tick = Instant::now();
+                self.handle_timers();
+
+                // SAFETY: interface_name is a valid nul terminated string
+                if unsafe { libc::if_nametoindex(interface_name.as_ptr()) } == 0 || !netns.exists()
+                {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn handle_outbound(&mut self, packet: &[u8]) {
+        let Some(destination) = destination_address(packet) else {
+            return;
+        };
+        if let Some(index) = self.route(destination) {
+            self.send_packet(index, packet.to_vec());
+        }
+    }
+
+    // Sends over the current session, or queues the packet until a handshake
+    // established one. Empty packets are keepalives, which are never queued.
+    fn send_packet(&mut self, index: usize, packet: Vec<u8>) {
+        let peer = &mut self.peers[index];
+        if let (Some(endpoint), Some(session)) = (peer.endpoint, peer.current.as_mut())
+            && session.confirmed
+            && session.created.elapsed() < REJECT_AFTER_TIME
+            && let Some(message) = session.encrypt(&packet)
+        {
+            let rekey = session.initiator && session.created.elapsed() >= REKEY_AFTER_TIME;
+            send(&self.socket, peer, &message, endpoint);
+            if rekey {
+                self.initiate(index);
+            }
+            return;
+        }
+
+        if !packet.is_empty() {
+            if peer.queue.len() >= MAX_QUEUED_PACKETS {
+                peer.queue.pop_front();
+            }
+            peer.queue.push_back(packet);
+        }
+        self.initiate(index);
+    }
+
+    fn initiate(&mut self, index: usize) {
+        let local_index = self.unused_index();
+        let Some(identity) = &self.identity else {
+            return;
+        };
+        let peer = &mut self.peers[index];
+        let Some(endpoint) = peer.endpoint else {
+            return;
+        };
+        if peer
+            .pending
+            .as_ref()
+            .is_some_and(|pending| pending.sent.elapsed() < REKEY_TIMEOUT)
+        {
+            return;
+        }
+
+        let Some((message, pending)) =
+            noise::create_initiation(identity, &peer.public_key, local_index, peer.cookie())
+        else {
+            info!("refusing a handshake with the low order key of a peer");
+            return;
+        };
+        peer.last_mac1 = Some((local_index, noise::mac1(&message)));
+        peer.attempts_started.get_or_insert_with(Instant::now);
+        peer.pending = Some(pending);
+        send(&self.socket, peer, &message, endpoint);
+    }
+
+    fn handle_inbound(&mut self, message: &[u8], source: SocketAddr) {
+        // the type is a little endian u32 with the upper bytes reserved
+        if message.len() < 4 || message[1..4] != [0, 0, 0] {
+            return;
+        }
+        match message[0] {
+            noise::MESSAGE_INITIATION => self.handle_initiation(message, source),
+            noise::MESSAGE_RESPONSE => self.handle_response(message, source),
+            noise::MESSAGE_COOKIE_REPLY => self.handle_cookie_reply(message),
+            noise::MESSAGE_TRANSPORT => self.handle_transport(message, source),
+            _ => {}
+        }
+    }
+
+    fn handle_initiation(&mut self, message: &[u8], source: SocketAddr) {
+        let local_index = self.unused_index();
+        let Some(identity) = &self.identity else {
+            return;
+        };
+        let Some(initiation) = noise::consume_initiation(identity, message) else {
+            return;
+        };
+        let Some(peer) = self
+            .peers
+            .iter_mut()
+            .find(|peer| peer.public_key == initiation.peer)
+        else {
+            return;
+        };
+        if initiation.timestamp <= peer.last_initiation {
+            return;
+        }
+
+        let preshared_key = peer.preshared_key.as_ref().map(PresharedKey::as_bytes);
+        let Some((response, session)) =
+            noise::create_response(&initiation, preshared_key, local_index, peer.cookie())
+        else {
+            return;
+        };
+        peer.last_mac1 = Some((local_index, noise::mac1(&response)));
+        peer.last_initiation = initiation.timestamp;
+        peer.last_handshake = Some(SystemTime::now());
+        peer.endpoint = Some(source);
+        peer.next = Some(session);
+        peer.rx_bytes += message.len() as u64;
+        send(&self.socket, peer, &response, source);
+    }
+
+    fn handle_response(&mut self, message: &[u8], source: SocketAddr) {
+        let Some(identity) = &self.identity else {
+            return;
+        };
+        if message.len() != noise::RESPONSE_LEN {
+            return;
+        }
+        let receiver_index = u32::from_le_bytes([message[8], message[9], message[10], message[11]]);
+        let Some(index) = self.peers.iter().position(|peer| {
+            peer.pending
+                .as_ref()
+                .is_some_and(|pending| pending.local_index == receiver_index)
+        }) else {
+            return;
+        };
+        let peer = &mut self.peers[index];
+        let Some(session) = peer.pending.as_ref().and_then(|pending| {
+            let preshared_key = peer.preshared_key.as_ref().map(PresharedKey::as_bytes);
+            noise::consume_response(identity, pending, preshared_key, message)
+        }) else {
+            return;
+        };
+
+        peer.pending = None;
+        peer.attempts_started = None;
+        peer.last_handshake = Some(SystemTime::now());
+        peer.endpoint = Some(source);
+        peer.rx_bytes += message.len() as u64;
+        peer.previous = peer.current.replace(session);
+        peer.next = None;
+
+        // the responder only starts sending once it received something over
+        // the new session
+        let queued: Vec<Vec<u8>> = peer.queue.drain(..).collect();
+        if queued.is_empty() {
+            self.send_packet(index, vec![]);
+        }
+        for packet in queued {
+            self.send_packet(index, packet);
+        }
+    }
+
+    // A peer under load didn't process our handshake message, the cookie
+    // makes the next one pass.
+    fn handle_cookie_reply(&mut self, message: &[u8]) {
+        if message.len() != noise::COOKIE_REPLY_LEN {
+            return;
+        }
+        let receiver_index = u32::from_le_bytes([message[4], message[5], message[6], message[7]]);
+        let Some(peer) = self.peers.iter_mut().find(|peer| {
+            peer.last_mac1
+                .is_some_and(|(local_index, _)| local_index == receiver_index)
+        }) else {
+            return;
+        };
+        let Some((_, mac1)) = peer.last_mac1 else {
+            return;
+        };
+        if let Some(cookie) = noise::consume_cookie_reply(&peer.public_key, &mac1, message) {
+            peer.cookie = Some((Instant::now(), cookie));
+            peer.rx_bytes += message.len() as u64;
+        }
+    }
+
+    fn handle_transport(&mut self, message: &[u8], source: SocketAddr) {
+        if message.len() < noise::TRANSPORT_HEADER_LEN {
+            return;
+        }
+        let receiver_index = u32::from_le_bytes([message[4], message[5], message[6], message[7]]);
+        let Some(index) = self.peers.iter().position(|peer| {
+            peer.sessions()
+                .any(|session| session.local_index == receiver_index)
+        }) else {
+            return;
+        };
+        let peer = &mut self.peers[index];
+        let is_next = peer
+            .next
+            .as_ref()
+            .is_some_and(|session| session.local_index == receiver_index);
+        let session = [
+            peer.current.as_mut(),
+            peer.previous.as_mut(),
+            peer.next.as_mut(),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|session| session.local_index == receiver_index)
+        .expect("the session was just found");
+        if session.created.elapsed() >= REJECT_AFTER_TIME {
+            return;
+        }
+        let Some(mut packet) = session.decrypt(message) else {
+            return;
+        };
+        let initiator = session.initiator;
+        let age = session.created.elapsed();
+
+        // the peer confirmed the handshake it initiated
+        if is_next {
+            peer.previous = peer.current.take();
+            peer.current = peer.next.take();
+            let queued: Vec<Vec<u8>> = peer.queue.drain(..).collect();
+            for packet in queued {
+                self.send_packet(index, packet);
+            }
+        }
+
+        let peer = &mut self.peers[index];
+        peer.endpoint = Some(source);
+        peer.rx_bytes += message.len() as u64;
+        if initiator && age >= REJECT_AFTER_TIME - KEEPALIVE_TIMEOUT - REKEY_TIMEOUT {
+            self.initiate(index);
+        }
+
+        // keepalives carry no packet
+        if packet.is_empty() {
+            return;
+        }
+        let peer = &mut self.peers[index];
+        peer.last_received = Some(Instant::now());
+
+        let Some(len) = packet_len(&packet) else {
+            return;
+        };
+        packet.truncate(len);
+        // a peer may only send from the addresses it is allowed to
+        let allowed = source_address(&packet).and_then(|address| self.route(address));
+        if allowed == Some(index)
+            && let Err(err) = self.tun.send(&packet)
+        {
+            info!("failed to write to the userspace wireguard device: {}", err);
+        }
+    }
+
+    fn handle_timers(&mut self) {
+        for index in 0..self.peers.len() {
+            let peer = &mut self.peers[index];
+
+            if let Some(pending) = &peer.pending
+                && pending.sent.elapsed() >= REKEY_TIMEOUT
+            {
+                if peer
+                    .attempts_started
+                    .is_some_and(|started| started.elapsed() >= REKEY_ATTEMPT_TIME)
+                {
+                    info!("handshake with peer {} timed out", peer.public_key);
+                    peer.pending = None;
+                    peer.attempts_started = None;
+                    peer.queue.clear();
+                } else {
+                    self.initiate(index);
+                }
+            }
+
+            let peer = &mut self.peers[index];
+            if peer
+                .current
+                .as_ref()
+                .is_some_and(|session| session.created.elapsed() >= REJECT_AFTER_TIME * 3)
+            {
+                peer.clear_sessions();
+            }
+
+            let since_sent = peer.last_sent.map(|last_sent| last_sent.elapsed());
+            let persistent_keepalive = Duration::from_secs(peer.persistent_keepalive.into());
+            let keepalive_due = !persistent_keepalive.is_zero()
+                && since_sent.is_none_or(|since_sent| since_sent >= persistent_keepalive);
+            // data which was received but not answered gets acknowledged
+            let passive_keepalive_due = peer.last_received.is_some_and(|last_received| {
+                last_received.elapsed() >= KEEPALIVE_TIMEOUT
+                    && peer
+                        .last_sent
+                        .is_none_or(|last_sent| last_sent < last_received)
+            });
+            if keepalive_due || passive_keepalive_due {
+                peer.last_received = None;
+                self.send_packet(index, vec![]);
+            }
+        }
+    }
+
+    fn handle_control(&mut self, stream: UnixStream) -> anyhow::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+        let Some(request) = read_message(&mut BufReader::new(&stream))? else {
+            return Ok(());
+        };
+
+        let (command, body) = request.split_once('\n').unwrap_or((request.as_str(), ""));
+        let mut response = match command {
+            "get=1" => format_device(&self.device()),
+            "set=1" => match parse_update(body).and_then(|update| self.configure(update)) {
+                Ok(()) => Default::default(),
+                Err(err) => {
+                    info!(
+                        "failed to configure the userspace wireguard device: {:#}",
+                        err
+                    );
+                    (&stream).write_all(format!("errno={}\n\n", libc::EINVAL).as_bytes())?;
+                    return Ok(());
+                }
+            },
+            _ => bail!("unsupported operation {:?}", command),
+        };
+        response.push_str("errno=0\n\n");
+        (&stream).write_all(response.as_bytes())?;
+        Ok(())
+    }
+
+    fn device(&self) -> Device {
+        Device {
+            private_key: self.private_key.clone(),
+            public_key: self.identity.as_ref().map(Identity::public_key),
+            listen_port: self.listen_port,
+            fwmark: self.fwmark,
+            peers: self
+                .peers
+                .iter()
+                .map(|peer| Peer {
+                    public_key: peer.public_key,
+                    preshared_key: peer.preshared_key.clone(),
+                    endpoint: peer.endpoint,
+                    allowed_ips: peer.allowed_ips.clone(),
+                    persistent_keepalive: peer.persistent_keepalive,
+                    last_handshake: peer.last_handshake,
+                    rx_bytes: peer.rx_bytes,
+                    tx_bytes: peer.tx_bytes,
+                })
+                .collect(),
+            ..Device::default()
+        }
+    }
+
+    // Applies the update and saves the resulting configuration, for the
+    // agent to restore the device after a restart.
+    pub fn configure(&mut self, update: DeviceUpdate) -> anyhow::Result<()> {
+        self.apply(update)?;
+        let device = self.device();
+        let update = DeviceUpdate {
+            private_key: device.private_key,
+            listen_port: Some(device.listen_port),
+            fwmark: Some(device.fwmark),
+            replace_peers: true,
+            peers: device
+                .peers
+                .into_iter()
+                .map(|peer| PeerUpdate {
+                    preshared_key: peer.preshared_key,
+                    endpoint: peer.endpoint,
+                    persistent_keepalive: Some(peer.persistent_keepalive),
+                    replace_allowed_ips: true,
+                    allowed_ips: peer.allowed_ips,
+                    ..PeerUpdate::new(peer.public_key)
+                })
+                .collect(),
+        };
+        self.saved.config = format_update(&update);
+        self.saved
+            .save(&self.state_path)
+            .context("failed to save the device configuration")
+    }
+
+    // Follows the semantics of the kernel's WG_CMD_SET_DEVICE.
+    fn apply(&mut self, update: DeviceUpdate) -> anyhow::Result<()> {
+        if let Some(private_key) = update.private_key
+            && self.private_key.as_ref().map(PrivateKey::as_bytes) != Some(private_key.as_bytes())
+        {
+            self.identity = Some(Identity::new(&private_key));
+            self.private_key = Some(private_key);
+            for peer in &mut self.peers {
+                peer.clear_sessions();
+            }
+        }
+
+        let fwmark = update.fwmark.unwrap_or(self.fwmark);
+        match update.listen_port {
+            Some(listen_port) if listen_port != self.listen_port => {
+                self.socket = bind(listen_port, fwmark)?;
+                self.listen_port = self.socket.local_addr()?.port();
+            }
+            _ if fwmark != self.fwmark => set_fwmark(&self.socket, fwmark)?,
+            _ => {}
+        }
+        self.fwmark = fwmark;
+
+        if update.replace_peers {
+            self.peers.clear();
+        }
+        for peer_update in update.peers {
+            let existing = self
+                .peers
+                .iter()
+                .position(|peer| peer.public_key == peer_update.public_key);
+            let index = match existing {
+                Some(index) if peer_update.remove => {
+                    self.peers.remove(index);
+                    continue;
+                }
+                Some(index) => index,
+                None if peer_update.remove || peer_update.update_only => continue,
+                None => {
+                    self.peers.push(PeerState::new(peer_update.public_key));
+                    self.peers.len() - 1
+                }
+            };
+
+            let peer = &mut self.peers[index];
+            if let Some(preshared_key) = peer_update.preshared_key {
+                peer.preshared_key = Some(preshared_key).filter(|key| !key.is_zero());
+            }
+            if peer_update.endpoint.is_some() {
+                peer.endpoint = peer_update.endpoint;
+            }
+            if let Some(persistent_keepalive) = peer_update.persistent_keepalive {
+                peer.persistent_keepalive = persistent_keepalive;
+            }
+            if peer_update.replace_allowed_ips {
+                peer.allowed_ips.clear();
+            }
+            // like in the kernel, an allowed ip belongs to one peer only
+            for allowed_ip in peer_update.allowed_ips {
+                let allowed_ip = network(allowed_ip);
+                for (other, peer) in self.peers.iter_mut().enumerate() {
+                    if other != index {
+                        peer.allowed_ips.retain(|address| *address != allowed_ip);
+                    }
+                }
+                let peer = &mut self.peers[index];
+                if !peer.allowed_ips.contains(&allowed_ip) {
+                    peer.allowed_ips.push(allowed_ip);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Cryptokey routing: the peer whose allowed ips match most specifically.
+    fn route(&self, address: IpAddr) -> Option<usize> {
+        self.peers
+            .iter()
+            .enumerate()
+            .flat_map(|(index, peer)| {
+                peer.allowed_ips
+                    .iter()
+                    .map(move |allowed_ip| (index, allowed_ip))
+            })
+            .filter(|(_, allowed_ip)| {
+                network(Address {
+                    address,
+                    ..**allowed_ip
+                }) == **allowed_ip
+            })
+            .max_by_key(|(_, allowed_ip)| allowed_ip.prefix)
+            .map(|(index, _)| index)
+    }
+
+    fn unused_index(&self) -> u32 {
+        loop {
+            let mut bytes = [0u8; 4];
+            getrandom::getrandom(&mut bytes).expect("the system random number generator failed");
+            let index = u32::from_ne_bytes(bytes);
+            let in_use = self.peers.iter().any(|peer| {
+                peer.sessions().any(|session| session.local_index == index)
+                    || peer
+                        .pending
+                        .as_ref()
+                        .is_some_and(|pending| pending.local_index == index)
+            });
+            if !in_use {
+                return index;
+            }
+        }
+    }
+}
+
+fn send(socket: &UdpSocket, peer: &mut PeerState, message: &[u8], endpoint: SocketAddr) {
+    match socket.send_to(message, endpoint) {
+        Ok(_) => {
+            peer.tx_bytes += message.len() as u64;
+            peer.last_sent = Some(Instant::now());
+        }
+        Err(err) => info!("failed to send to peer {}: {}", peer.public_key, err),
+    }
+}
+
+fn bind(port: u16, fwmark: u32) -> anyhow::Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))
+        .with_context(|| format!("failed to bind listen port {}", port))?;
+    socket.set_nonblocking(true)?;
+    set_fwmark(&socket, fwmark)?;
+    Ok(socket)
+}
+
+// Marked packets skip the tunnel routing table, which keeps the encrypted
+// packets themselves from being routed back into the tunnel.
+fn set_fwmark(socket: &UdpSocket, fwmark: u32) -> anyhow::Result<()> {
+    // SAFETY: the fd is open and fwmark outlives the call
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &fwmark as *const u32 as *const libc::c_void,
+            size_of::<u32>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error()).context("failed to set the fwmark");
+    }
+    Ok(())
+}
+
+fn pollfd(fd: libc::c_int) -> libc::pollfd {
+    libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    }
+}
+
+fn would_block<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// clears the host bits
+fn network(address: Address) -> Address {
+    let prefix = address.prefix as u32;
+    let masked = match address.address {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(
+            ip.to_bits() & u32::MAX.checked_shl(32 - prefix).unwrap_or(0),
+        )),
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(
+            ip.to_bits() & u128::MAX.checked_shl(128 - prefix).unwrap_or(0),
+        )),
+    };
+    Address {
+        address: masked,
+        prefix: address.prefix,
+    }
+}
+
+fn destination_address(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 => Some(IpAddr::V4(
+            <[u8; 4]>::try_from(packet.get(16..20)?).ok()?.into(),
+        )),
+        6 => Some(IpAddr::V6(
+            <[u8; 16]>::try_from(packet.get(24..40)?).ok()?.into(),
+        )),
+        _ => None,
+    }
+}
+
+fn source_address(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 => Some(IpAddr::V4(
+            <[u8; 4]>::try_from(packet.get(12..16)?).ok()?.into(),
+        )),
+        6 => Some(IpAddr::V6(
+            <[u8; 16]>::try_from(packet.get(8..24)?).ok()?.into(),
+        )),
+        _ => None,
+    }
+}
+
+// decrypted packets are padded, their real length is in the IP header
+fn packet_len(packet: &[u8]) -> Option<usize> {
+    let len = match packet.first()? >> 4 {
+        4 => u16::from_be_bytes([*packet.get(2)?, *packet.get(3)?]) as usize,
+        6 => 40 + u16::from_be_bytes([*packet.get(4)?, *packet.get(5)?]) as usize,
+        _ => return None,
+    };
+    Some(len).filter(|len| *len <= packet.len())
+}
@@ -0,0 +1,433 @@
+mod daemon;
+mod noise;
+mod tun;
+pub mod uapi;
+
+use crate::wireguard::userspace::{
+    daemon::Daemon,
+    uapi::{parse_update, read_message},
+};
+use tun::Tun;
+
+use std::{
+    collections::HashSet,
+    ffi::CString,
+    fs::{self, File},
+    io::{self, BufReader, ErrorKind, Write},
+    net::UdpSocket,
+    os::{
+        fd::AsRawFd,
+        unix::{
+            fs::{DirBuilderExt, MetadataExt, OpenOptionsExt},
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, mpsc},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, anyhow, bail};
+use nix::sched::{CloneFlags, setns};
+use tracing::{error, info};
+use zeroize::Zeroizing;
+
+// The node agent hosting the devices listens here, see serve.
+pub const AGENT_SOCKET: &str = "/run/podtunnel/agent.sock";
+
+const TUN_DEVICE: &str = "/dev/net/tun";
+const SOCKET_DIRECTORY: &str = "/run/podtunnel/wireguard";
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Devices of the agent, by the path of their control socket.
+type Devices = Arc<Mutex<HashSet<PathBuf>>>;
+
+pub fn is_supported() -> bool {
+    Path::new(TUN_DEVICE).exists() && Path::new(AGENT_SOCKET).exists()
+}
+
+// The control socket of a device in the netns of the calling thread. Every
+// Pod names its interface the same, so sockets are told apart by the cookie
+// of their netns, which unlike its inode is never reused. Kernels before 5.14
+// have no cookies and fall back to the inode.
+pub fn socket_path(interface_name: &str) -> anyhow::Result<PathBuf> {
+    Ok(Path::new(SOCKET_DIRECTORY).join(format!("{}-{}.sock", netns_key()?, interface_name)))
+}
+
+fn netns_key() -> anyhow::Result<String> {
+    let socket = UdpSocket::bind(("127.0.0.1", 0)).context("failed to inspect the netns")?;
+    let mut cookie = 0u64;
+    let mut len = size_of::<u64>() as libc::socklen_t;
+    // SAFETY: the fd is open and cookie and len outlive the call
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_NETNS_COOKIE,
+            &mut cookie as *mut u64 as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result == 0 {
+        return Ok(format!("c{}", cookie));
+    }
+    let netns = fs::metadata("/proc/thread-self/ns/net").context("failed to inspect the netns")?;
+    Ok(netns.ino().to_string())
+}
+
+// Has the agent start a device in the netns at the given path, which is the
+// one of the calling thread, and returns once it accepts configuration.
+pub fn spawn(netns: &str, interface_name: &str) -> anyhow::Result<()> {
+    let stream = UnixStream::connect(AGENT_SOCKET).with_context(|| {
+        format!(
+            "failed to reach the podtunnel agent at {}, is it running on this node?",
+            AGENT_SOCKET
+        )
+    })?;
+    stream.set_read_timeout(Some(2 * STARTUP_TIMEOUT))?;
+    (&stream).write_all(
+        format!("start=1\nnetns={}\ninterface={}\n\n", netns, interface_name).as_bytes(),
+    )?;
+
+    let response = read_message(&mut BufReader::new(&stream))?
+        .context("the podtunnel agent closed the connection")?;
+    let mut error = None;
+    for line in response.lines() {
+        match line.split_once('=') {
+            Some(("errno", "0")) => return Ok(()),
+            Some(("error", message)) => error = Some(message.to_string()),
+            Some(("errno", errno)) => bail!(
+                "the podtunnel agent failed to start the userspace wireguard device: {}",
+                error.unwrap_or_else(|| format!("errno {}", errno))
+            ),
+            _ => {}
+        }
+    }
+    bail!("the podtunnel agent sent an invalid response")
+}
+
+// Forgets the device of an interface which is gone, for when it didn't get
+// to clean up itself.
+pub fn remove(interface_name: &str) -> anyhow::Result<()> {
+    let path = socket_path(interface_name)?;
+    for path in [state_path(&path), path] {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Binds the agent socket, replacing the one of a previous agent.
+pub fn listen() -> anyhow::Result<UnixListener> {
+    // the sockets hand out private keys
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(SOCKET_DIRECTORY)?;
+    match fs::remove_file(AGENT_SOCKET) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    UnixListener::bind(AGENT_SOCKET)
+        .with_context(|| format!("failed to bind the agent socket {}", AGENT_SOCKET))
+}
+
+// Devices outlive the agent, their TUN interfaces are persistent and their
+// configuration is saved next to their socket. A restarted agent picks up the
+// ones whose interface still exists and forgets the rest.
+pub fn restore(devices: &Devices) {
+    let Ok(entries) = fs::read_dir(SOCKET_DIRECTORY) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path
+            .extension()
+            .is_none_or(|extension| extension != "state")
+        {
+            continue;
+        }
+        let result = fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|state| SavedDevice::parse(&state))
+            .and_then(|saved| {
+                start(
+                    devices,
+                    &saved.netns,
+                    &saved.interface_name,
+                    Some(saved.config),
+                )
+            });
+        match result {
+            Ok(()) => info!("restored userspace wireguard device {}", path.display()),
+            Err(err) => {
+                info!(
+                    "dropping userspace wireguard device {}: {:#}",
+                    path.display(),
+                    err
+                );
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+}
+
+// Serves start requests from the plugin, one at a time.
+pub fn serve(listener: UnixListener, devices: Devices) -> anyhow::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        if let Err(err) = handle_request(&stream, &devices) {
+            error!("failed to start a userspace wireguard device: {:#}", err);
+            let message = format!("{:#}", err).replace('\n', " ");
+            let _ = (&stream)
+                .write_all(format!("error={}\nerrno={}\n\n", message, libc::EINVAL).as_bytes());
+        }
+    }
+    Ok(())
+}
+
+pub fn devices() -> Devices {
+    Devices::default()
+}
+
+fn handle_request(stream: &UnixStream, devices: &Devices) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(STARTUP_TIMEOUT))?;
+    let request =
+        read_message(&mut BufReader::new(stream))?.context("the plugin closed the connection")?;
+    let mut lines = request.lines().map(|line| line.split_once('='));
+    let (
+        Some(Some(("start", "1"))),
+        Some(Some(("netns", netns))),
+        Some(Some(("interface", interface_name))),
+    ) = (lines.next(), lines.next(), lines.next())
+    else {
+        bail!("invalid request {:?}", request.as_str());
+    };
+
+    start(devices, netns, interface_name, None)?;
+    (&*stream).write_all(b"errno=0\n\n")?;
+    Ok(())
+}
+
+// Starts a device on its own thread in the given netns and waits until it
+// accepts configuration.
+fn start(
+    devices: &Devices,
+    netns: &str,
+    interface_name: &str,
+    config: Option<Zeroizing<String>>,
+) -> anyhow::Result<()> {
+    let netns_file =
+        File::open(netns).with_context(|| format!("failed to open netns {}", netns))?;
+    let (ready, started) = mpsc::channel();
+    let device = DeviceThread {
+        devices: devices.clone(),
+        netns: netns.to_string(),
+        interface_name: interface_name.to_string(),
+    };
+    thread::Builder::new()
+        .name(format!("wg-{}", interface_name))
+        .spawn(move || device.run(netns_file, config, ready))?;
+
+    started
+        .recv_timeout(STARTUP_TIMEOUT)
+        .map_err(|_| anyhow!("the device did not start within {:?}", STARTUP_TIMEOUT))?
+}
+
+struct DeviceThread {
+    devices: Devices,
+    netns: String,
+    interface_name: String,
+}
+
+impl DeviceThread {
+    // The thread stays in the Pod's netns for good, it ends with the device.
+    fn run(
+        self,
+        netns_file: File,
+        config: Option<Zeroizing<String>>,
+        ready: mpsc::Sender<anyhow::Result<()>>,
+    ) {
+        let path = match self.enter(netns_file) {
+            Ok(path) => path,
+            Err(err) => {
+                let _ = ready.send(Err(err));
+                return;
+            }
+        };
+
+        let result = self.serve(&path, config, &ready);
+        self.devices
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .remove(&path);
+        match result {
+            Ok(()) => {
+                let _ = fs::remove_file(state_path(&path));
+                let _ = fs::remove_file(&path);
+                info!("stopped userspace wireguard device {}", self.interface_name);
+            }
+            // the interface is left for a restarted agent to pick up
+            Err(err) => {
+                let _ = fs::remove_file(&path);
+                let message = format!("{:#}", err);
+                if ready.send(Err(err)).is_err() {
+                    error!(
+                        "userspace wireguard device {} failed: {}",
+                        self.interface_name, message
+                    );
+                }
+            }
+        }
+    }
+
+    fn enter(&self, netns_file: File) -> anyhow::Result<PathBuf> {
+        setns(&netns_file, CloneFlags::CLONE_NEWNET).context("failed to enter the netns")?;
+        // the thread alone keeps the netns alive until its path goes away
+        drop(netns_file);
+
+        let path = socket_path(&self.interface_name)?;
+        if !self
+            .devices
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert(path.clone())
+        {
+            bail!("a device already runs at {}", path.display());
+        }
+        Ok(path)
+    }
+
+    fn serve(
+        &self,
+        path: &Path,
+        config: Option<Zeroizing<String>>,
+        ready: &mpsc::Sender<anyhow::Result<()>>,
+    ) -> anyhow::Result<()> {
+        let tun = match &config {
+            // a restored device has to find its interface where it left it
+            Some(_) => {
+                if !interface_exists(&self.interface_name)? {
+                    bail!("interface {} is gone", self.interface_name);
+                }
+                Tun::attach(&self.interface_name)
+            }
+            None => Tun::create(&self.interface_name),
+        }
+        .with_context(|| format!("failed to open TUN interface {}", self.interface_name))?;
+
+        // bound under a temporary name and moved in place, so the socket only
+        // shows up once it accepts connections
+        let staging_path = path.with_extension("new");
+        let _ = fs::remove_file(&staging_path);
+        let listener = UnixListener::bind(&staging_path)?;
+        let saved = SavedDevice {
+            netns: self.netns.clone(),
+            interface_name: self.interface_name.clone(),
+            config: Zeroizing::new(String::new()),
+        };
+        let mut daemon = Daemon::new(tun, listener, state_path(path), saved)?;
+        if let Some(config) = config {
+            daemon.configure(parse_update(&config)?)?;
+        }
+        fs::rename(&staging_path, path)?;
+        info!("started userspace wireguard device {}", self.interface_name);
+        let _ = ready.send(Ok(()));
+
+        daemon.serve(&self.interface_name, Path::new(&self.netns))
+    }
+}
+
+// What a restarted agent needs to bring a device back.
+pub(crate) struct SavedDevice {
+    pub netns: String,
+    pub interface_name: String,
+    pub config: Zeroizing<String>,
+}
+
+impl SavedDevice {
+    // the netns and interface lead, the rest is a set request of the device's
+    // whole configuration
+    pub fn format(&self) -> Zeroizing<String> {
+        let mut text = Zeroizing::new(format!(
+            "netns={}\ninterface={}\n",
+            self.netns, self.interface_name
+        ));
+        text.push_str(&self.config);
+        text
+    }
+
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut lines = text.splitn(3, '\n');
+        let (Some(netns), Some(interface_name)) = (
+            lines.next().and_then(|line| line.strip_prefix("netns=")),
+            lines
+                .next()
+                .and_then(|line| line.strip_prefix("interface=")),
+        ) else {
+            bail!("invalid saved device");
+        };
+        Ok(SavedDevice {
+            netns: netns.to_string(),
+            interface_name: interface_name.to_string(),
+            config: Zeroizing::new(lines.next().unwrap_or_default().to_string()),
+        })
+    }
+
+    // written aside and moved in place, so a crash never leaves half of it
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let staging_path = path.with_extension("state.new");
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&staging_path)?;
+        file.write_all(self.format().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&staging_path, path)
+    }
+}
+
+fn state_path(socket_path: &Path) -> PathBuf {
+    socket_path.with_extension("state")
+}
+
+fn interface_exists(interface_name: &str) -> anyhow::Result<bool> {
+    let name = CString::new(interface_name)?;
+    // SAFETY: name is a valid nul terminated string
+    Ok(unsafe { libc::if_nametoindex(name.as_ptr()) } != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_devices_round_trip() {
+        let saved = SavedDevice {
+            netns: "/var/run/netns/cni-1234".to_string(),
+            interface_name: "wg0".to_string(),
+            config: Zeroizing::new("listen_port=51820\nreplace_peers=true\n".to_string()),
+        };
+
+        let parsed = SavedDevice::parse(&saved.format()).unwrap();
+
+        assert_eq!(parsed.netns, saved.netns);
+        assert_eq!(parsed.interface_name, saved.interface_name);
+        assert_eq!(parsed.config.as_str(), saved.config.as_str());
+        assert!(SavedDevice::parse("listen_port=51820\n").is_err());
+    }
+
+    #[test]
+    fn sockets_are_keyed_by_netns() {
+        let path = socket_path("wg0").unwrap();
+        assert_eq!(path, socket_path("wg0").unwrap());
+        assert!(path.starts_with(SOCKET_DIRECTORY));
+        assert!(path.to_string_lossy().ends_with("-wg0.sock"));
+        assert_eq!(state_path(&path).extension().unwrap(), "state",);
+    }
+}
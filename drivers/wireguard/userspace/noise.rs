@@ -0,0 +1,834 @@
+use crate::wireguard::key::{KEY_LEN, PrivateKey, PublicKey};
+
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use blake2::{
+    Blake2s256, Blake2sMac, Digest,
+    digest::{FixedOutput, KeyInit, Mac, Update, consts::U16},
+};
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, Nonce, XChaCha20Poly1305, XNonce,
+    aead::{Aead, Payload},
+};
+use hmac::SimpleHmac;
+use x25519_dalek::{PublicKey as DhPublic, StaticSecret};
+use zeroize::{Zeroize, Zeroizing};
+
+// see https://www.wireguard.com/protocol/
+//
+// This implementation hasn't been audited, which is why the fallback to it
+// is off unless a network enables userspaceFallback.
+const CONSTRUCTION: &[u8] = b"Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+const IDENTIFIER: &[u8] = b"WireGuard v1 zx2c4 Jason@zx2c4.com";
+const LABEL_MAC1: &[u8] = b"mac1----";
+const LABEL_COOKIE: &[u8] = b"cookie--";
+
+pub const MESSAGE_INITIATION: u8 = 1;
+pub const MESSAGE_RESPONSE: u8 = 2;
+pub const MESSAGE_COOKIE_REPLY: u8 = 3;
+pub const MESSAGE_TRANSPORT: u8 = 4;
+
+pub const INITIATION_LEN: usize = 148;
+pub const RESPONSE_LEN: usize = 92;
+pub const COOKIE_REPLY_LEN: usize = 64;
+pub const TRANSPORT_HEADER_LEN: usize = 16;
+
+const TAG_LEN: usize = 16;
+const MAC_LEN: usize = 16;
+const TIMESTAMP_LEN: usize = 12;
+const COOKIE_NONCE_LEN: usize = 24;
+
+// counters past this are never used, a new handshake is needed before
+pub const REJECT_AFTER_MESSAGES: u64 = u64::MAX - (1 << 13);
+
+// 2^62 + seconds since the epoch, as defined by TAI64
+const TAI64_EPOCH: u64 = 0x4000_0000_0000_000a;
+
+type Blake2sMac128 = Blake2sMac<U16>;
+type HmacBlake2s = SimpleHmac<Blake2s256>;
+
+// What a peer under load hands out in cookie replies. It's the key of mac2
+// on the handshake messages sent to that peer next.
+pub type Cookie = [u8; MAC_LEN];
+
+// The local static key along with the values derived from it which every
+// handshake needs.
+pub struct Identity {
+    private_key: StaticSecret,
+    public_key: [u8; KEY_LEN],
+    mac1_key: [u8; KEY_LEN],
+    // chaining key and hash after mixing in the responder's static key, the
+    // same for every initiation sent to us
+    initial_chaining_key: [u8; KEY_LEN],
+    initial_hash: [u8; KEY_LEN],
+}
+
+impl Identity {
+    pub fn new(private_key: &PrivateKey) -> Self {
+        let public_key = *private_key.public_key().as_bytes();
+        let (initial_chaining_key, initial_hash) = initial_state(&public_key);
+        Identity {
+            private_key: StaticSecret::from(*private_key.as_bytes()),
+            public_key,
+            mac1_key: hash(&[LABEL_MAC1, &public_key]),
+            initial_chaining_key,
+            initial_hash,
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from(self.public_key)
+    }
+}
+
+// An initiation which was sent and is waiting for its response.
+pub struct PendingHandshake {
+    pub local_index: u32,
+    pub sent: Instant,
+    chaining_key: Zeroizing<[u8; KEY_LEN]>,
+    hash: [u8; KEY_LEN],
+    ephemeral: StaticSecret,
+}
+
+// An initiation which was received and authenticated.
+pub struct ReceivedInitiation {
+    pub peer: PublicKey,
+    pub sender_index: u32,
+    pub timestamp: [u8; TIMESTAMP_LEN],
+    chaining_key: Zeroizing<[u8; KEY_LEN]>,
+    hash: [u8; KEY_LEN],
+    remote_ephemeral: [u8; KEY_LEN],
+}
+
+pub fn create_initiation(
+    identity: &Identity,
+    peer: &PublicKey,
+    local_index: u32,
+    cookie: Option<&Cookie>,
+) -> Option<([u8; INITIATION_LEN], PendingHandshake)> {
+    initiation(
+        identity,
+        peer,
+        local_index,
+        StaticSecret::random(),
+        tai64n(SystemTime::now()),
+        cookie,
+    )
+}
+
+fn initiation(
+    identity: &Identity,
+    peer: &PublicKey,
+    local_index: u32,
+    ephemeral: StaticSecret,
+    timestamp: [u8; TIMESTAMP_LEN],
+    cookie: Option<&Cookie>,
+) -> Option<([u8; INITIATION_LEN], PendingHandshake)> {
+    let (chaining_key, hash_) = initial_state(peer.as_bytes());
+    let mut chaining_key = Zeroizing::new(chaining_key);
+    let mut h = hash_;
+
+    let ephemeral_public = DhPublic::from(&ephemeral).to_bytes();
+
+    let mut message = [0u8; INITIATION_LEN];
+    message[0] = MESSAGE_INITIATION;
+    message[4..8].copy_from_slice(&local_index.to_le_bytes());
+    message[8..40].copy_from_slice(&ephemeral_public);
+
+    *chaining_key = kdf::<1>(&chaining_key, &ephemeral_public)[0];
+    h = hash(&[&h, &ephemeral_public]);
+
+    let shared = dh(&ephemeral, peer.as_bytes())?;
+    let [next_chaining_key, key] = kdf::<2>(&chaining_key, &shared);
+    *chaining_key = next_chaining_key;
+    let encrypted_static = seal(&key, 0, &identity.public_key, &h);
+    message[40..88].copy_from_slice(&encrypted_static);
+    h = hash(&[&h, &encrypted_static]);
+
+    let shared = dh(&identity.private_key, peer.as_bytes())?;
+    let [next_chaining_key, key] = kdf::<2>(&chaining_key, &shared);
+    *chaining_key = next_chaining_key;
+    let encrypted_timestamp = seal(&key, 0, &timestamp, &h);
+    message[88..116].copy_from_slice(&encrypted_timestamp);
+    h = hash(&[&h, &encrypted_timestamp]);
+
+    add_macs(&mut message, peer, cookie);
+
+    let pending = PendingHandshake {
+        local_index,
+        sent: Instant::now(),
+        chaining_key,
+        hash: h,
+        ephemeral,
+    };
+    Some((message, pending))
+}
+
+pub fn consume_initiation(identity: &Identity, message: &[u8]) -> Option<ReceivedInitiation> {
+    if message.len() != INITIATION_LEN || !valid_mac1(&identity.mac1_key, message) {
+        return None;
+    }
+
+    let sender_index = u32::from_le_bytes(message[4..8].try_into().ok()?);
+    let remote_ephemeral: [u8; KEY_LEN] = message[8..40].try_into().ok()?;
+
+    let mut chaining_key = Zeroizing::new(identity.initial_chaining_key);
+    let mut h = identity.initial_hash;
+
+    *chaining_key = kdf::<1>(&chaining_key, &remote_ephemeral)[0];
+    h = hash(&[&h, &remote_ephemeral]);
+
+    let shared = dh(&identity.private_key, &remote_ephemeral)?;
+    let [next_chaining_key, key] = kdf::<2>(&chaining_key, &shared);
+    *chaining_key = next_chaining_key;
+    let peer: [u8; KEY_LEN] = open(&key, 0, &message[40..88], &h)?.try_into().ok()?;
+    h = hash(&[&h, &message[40..88]]);
+
+    let shared = dh(&identity.private_key, &peer)?;
+    let [next_chaining_key, key] = kdf::<2>(&chaining_key, &shared);
+    *chaining_key = next_chaining_key;
+    let timestamp: [u8; TIMESTAMP_LEN] = open(&key, 0, &message[88..116], &h)?.try_into().ok()?;
+    h = hash(&[&h, &message[88..116]]);
+
+    Some(ReceivedInitiation {
+        peer: PublicKey::from(peer),
+        sender_index,
+        timestamp,
+        chaining_key,
+        hash: h,
+        remote_ephemeral,
+    })
+}
+
+pub fn create_response(
+    initiation: &ReceivedInitiation,
+    preshared_key: Option<&[u8; KEY_LEN]>,
+    local_index: u32,
+    cookie: Option<&Cookie>,
+) -> Option<([u8; RESPONSE_LEN], Session)> {
+    response(
+        initiation,
+        preshared_key,
+        local_index,
+        StaticSecret::random(),
+        cookie,
+    )
+}
+
+fn response(
+    initiation: &ReceivedInitiation,
+    preshared_key: Option<&[u8; KEY_LEN]>,
+    local_index: u32,
+    ephemeral: StaticSecret,
+    cookie: Option<&Cookie>,
+) -> Option<([u8; RESPONSE_LEN], Session)> {
+    let mut chaining_key = initiation.chaining_key.clone();
+    let mut h = initiation.hash;
+
+    let ephemeral_public = DhPublic::from(&ephemeral).to_bytes();
+
+    let mut message = [0u8; RESPONSE_LEN];
+    message[0] = MESSAGE_RESPONSE;
+    message[4..8].copy_from_slice(&local_index.to_le_bytes());
+    message[8..12].copy_from_slice(&initiation.sender_index.to_le_bytes());
+    message[12..44].copy_from_slice(&ephemeral_public);
+
+    *chaining_key = kdf::<1>(&chaining_key, &ephemeral_public)[0];
+    h = hash(&[&h, &ephemeral_public]);
+    *chaining_key = kdf::<1>(
+        &chaining_key,
+        &dh(&ephemeral, &initiation.remote_ephemeral)?,
+    )[0];
+    *chaining_key = kdf::<1>(&chaining_key, &dh(&ephemeral, initiation.peer.as_bytes())?)[0];
+
+    let [next_chaining_key, tau, key] =
+        kdf::<3>(&chaining_key, preshared_key.unwrap_or(&[0; KEY_LEN]));
+    *chaining_key = next_chaining_key;
+    h = hash(&[&h, &tau]);
+    let encrypted_nothing = seal(&key, 0, &[], &h);
+    message[44..60].copy_from_slice(&encrypted_nothing);
+
+    add_macs(&mut message, &initiation.peer, cookie);
+
+    let [receive_key, send_key] = kdf::<2>(&chaining_key, b"");
+    let session = Session::new(
+        local_index,
+        initiation.sender_index,
+        send_key,
+        receive_key,
+        false,
+    );
+    Some((message, session))
+}
+
+pub fn consume_response(
+    identity: &Identity,
+    pending: &PendingHandshake,
+    preshared_key: Option<&[u8; KEY_LEN]>,
+    message: &[u8],
+) -> Option<Session> {
+    if message.len() != RESPONSE_LEN || !valid_mac1(&identity.mac1_key, message) {
+        return None;
+    }
+
+    let sender_index = u32::from_le_bytes(message[4..8].try_into().ok()?);
+    let receiver_index = u32::from_le_bytes(message[8..12].try_into().ok()?);
+    if receiver_index != pending.local_index {
+        return None;
+    }
+    let remote_ephemeral: [u8; KEY_LEN] = message[12..44].try_into().ok()?;
+
+    let mut chaining_key = pending.chaining_key.clone();
+    let mut h = pending.hash;
+
+    *chaining_key = kdf::<1>(&chaining_key, &remote_ephemeral)[0];
+    h = hash(&[&h, &remote_ephemeral]);
+    *chaining_key = kdf::<1>(&chaining_key, &dh(&pending.ephemeral, &remote_ephemeral)?)[0];
+    *chaining_key = kdf::<1>(
+        &chaining_key,
+        &dh(&identity.private_key, &remote_ephemeral)?,
+    )[0];
+
+    let [next_chaining_key, tau, key] =
+        kdf::<3>(&chaining_key, preshared_key.unwrap_or(&[0; KEY_LEN]));
+    *chaining_key = next_chaining_key;
+    h = hash(&[&h, &tau]);
+    open(&key, 0, &message[44..60], &h)?;
+
+    let [send_key, receive_key] = kdf::<2>(&chaining_key, b"");
+    Some(Session::new(
+        pending.local_index,
+        sender_index,
+        send_key,
+        receive_key,
+        true,
+    ))
+}
+
+// Opens the reply of a peer under load to the handshake message with the
+// given mac1, see mac1.
+pub fn consume_cookie_reply(
+    peer: &PublicKey,
+    mac1: &[u8; MAC_LEN],
+    message: &[u8],
+) -> Option<Cookie> {
+    if message.len() != COOKIE_REPLY_LEN {
+        return None;
+    }
+    let key = hash(&[LABEL_COOKIE, peer.as_bytes()]);
+    let cookie = XChaCha20Poly1305::new(Key::from_slice(&key))
+        .decrypt(
+            XNonce::from_slice(&message[8..8 + COOKIE_NONCE_LEN]),
+            Payload {
+                msg: &message[8 + COOKIE_NONCE_LEN..],
+                aad: mac1,
+            },
+        )
+        .ok()?;
+    cookie.try_into().ok()
+}
+
+// The mac1 of a handshake message, which cookie replies to it are bound to.
+pub fn mac1(message: &[u8]) -> [u8; MAC_LEN] {
+    let offset = message.len() - 2 * MAC_LEN;
+    message[offset..offset + MAC_LEN]
+        .try_into()
+        .expect("handshake messages end with two macs")
+}
+
+// mac2 stays zero unless the peer sent a cookie
+fn add_macs(message: &mut [u8], peer: &PublicKey, cookie: Option<&Cookie>) {
+    let offset = message.len() - 2 * MAC_LEN;
+    let mac1_key = hash(&[LABEL_MAC1, peer.as_bytes()]);
+    let mac1 = mac(&mac1_key, &message[..offset]);
+    message[offset..offset + MAC_LEN].copy_from_slice(&mac1);
+    if let Some(cookie) = cookie {
+        let mac2 = mac(cookie, &message[..offset + MAC_LEN]);
+        message[offset + MAC_LEN..].copy_from_slice(&mac2);
+    }
+}
+
+// The keys of one completed handshake. A session started by the peer may
+// only be used for sending once the peer used it first, which confirms that
+// it received the response.
+pub struct Session {
+    pub local_index: u32,
+    pub remote_index: u32,
+    pub created: Instant,
+    pub initiator: bool,
+    pub confirmed: bool,
+    send_key: Zeroizing<[u8; KEY_LEN]>,
+    receive_key: Zeroizing<[u8; KEY_LEN]>,
+    send_counter: u64,
+    replay: ReplayWindow,
+}
+
+impl Session {
+    fn new(
+        local_index: u32,
+        remote_index: u32,
+        send_key: [u8; KEY_LEN],
+        receive_key: [u8; KEY_LEN],
+        initiator: bool,
+    ) -> Self {
+        Session {
+            local_index,
+            remote_index,
+            created: Instant::now(),
+            initiator,
+            confirmed: initiator,
+            send_key: Zeroizing::new(send_key),
+            receive_key: Zeroizing::new(receive_key),
+            send_counter: 0,
+            replay: ReplayWindow::default(),
+        }
+    }
+
+    // Wraps a packet into a transport message, padded to a multiple of 16
+    // bytes. An empty packet is a keepalive.
+    pub fn encrypt(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        if self.send_counter >= REJECT_AFTER_MESSAGES {
+            return None;
+        }
+        let counter = self.send_counter;
+        self.send_counter += 1;
+
+        let mut padded = Zeroizing::new(packet.to_vec());
+        padded.resize(packet.len().div_ceil(16) * 16, 0);
+
+        let mut message = Vec::with_capacity(TRANSPORT_HEADER_LEN + padded.len() + TAG_LEN);
+        message.extend_from_slice(&(MESSAGE_TRANSPORT as u32).to_le_bytes());
+        message.extend_from_slice(&self.remote_index.to_le_bytes());
+        message.extend_from_slice(&counter.to_le_bytes());
+        message.extend_from_slice(&seal(&self.send_key, counter, &padded, &[]));
+        Some(message)
+    }
+
+    // Returns the padded packet, the caller trims it to the length from its
+    // IP header.
+    pub fn decrypt(&mut self, message: &[u8]) -> Option<Vec<u8>> {
+        if message.len() < TRANSPORT_HEADER_LEN + TAG_LEN {
+            return None;
+        }
+        let counter = u64::from_le_bytes(message[8..16].try_into().ok()?);
+        if counter >= REJECT_AFTER_MESSAGES || !self.replay.is_new(counter) {
+            return None;
+        }
+
+        let packet = open(
+            &self.receive_key,
+            counter,
+            &message[TRANSPORT_HEADER_LEN..],
+            &[],
+        )?;
+        self.replay.mark(counter);
+        self.confirmed = true;
+        Some(packet)
+    }
+}
+
+// A sliding window over the last 64 counters, which drops replayed messages
+// while still accepting ones reordered on the way.
+#[derive(Default)]
+struct ReplayWindow {
+    greatest: Option<u64>,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_new(&self, counter: u64) -> bool {
+        match self.greatest {
+            None => true,
+            Some(greatest) if counter > greatest => true,
+            Some(greatest) => {
+                let age = greatest - counter;
+                age < 64 && self.seen & (1 << age) == 0
+            }
+        }
+    }
+
+    fn mark(&mut self, counter: u64) {
+        match self.greatest {
+            Some(greatest) if counter <= greatest => self.seen |= 1 << (greatest - counter),
+            Some(greatest) => {
+                let shift = counter - greatest;
+                self.seen = if shift >= 64 { 0 } else { self.seen << shift };
+                self.seen |= 1;
+                self.greatest = Some(counter);
+            }
+            None => {
+                self.seen = 1;
+                self.greatest = Some(counter);
+            }
+        }
+    }
+}
+
+pub fn tai64n(time: SystemTime) -> [u8; TIMESTAMP_LEN] {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut timestamp = [0u8; TIMESTAMP_LEN];
+    timestamp[..8].copy_from_slice(&(TAI64_EPOCH + since_epoch.as_secs()).to_be_bytes());
+    timestamp[8..].copy_from_slice(&since_epoch.subsec_nanos().to_be_bytes());
+    timestamp
+}
+
+fn initial_state(responder: &[u8; KEY_LEN]) -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+    let chaining_key = hash(&[CONSTRUCTION]);
+    let h = hash(&[&chaining_key, IDENTIFIER]);
+    (chaining_key, hash(&[&h, responder]))
+}
+
+fn valid_mac1(mac1_key: &[u8; KEY_LEN], message: &[u8]) -> bool {
+    let offset = message.len() - 2 * MAC_LEN;
+    let expected = mac(mac1_key, &message[..offset]);
+    // not secret, but there is no reason to leak timing either
+    expected
+        .iter()
+        .zip(&message[offset..offset + MAC_LEN])
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+// Low order points give an all zero shared secret whatever the private key,
+// which would let a peer fix a contribution to the chaining key. Like the
+// kernel, handshakes with them are refused.
+fn dh(private_key: &StaticSecret, public_key: &[u8; KEY_LEN]) -> Option<Zeroizing<[u8; KEY_LEN]>> {
+    let shared = private_key.diffie_hellman(&DhPublic::from(*public_key));
+    if !shared.was_contributory() {
+        return None;
+    }
+    Some(Zeroizing::new(shared.to_bytes()))
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; KEY_LEN] {
+    let mut hasher = Blake2s256::new();
+    for part in parts {
+        Digest::update(&mut hasher, part);
+    }
+    hasher.finalize().into()
+}
+
+fn mac(key: &[u8], input: &[u8]) -> [u8; MAC_LEN] {
+    let mut mac = <Blake2sMac128 as KeyInit>::new_from_slice(key).expect("valid key length");
+    Update::update(&mut mac, input);
+    mac.finalize_fixed().into()
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; KEY_LEN] {
+    let mut hmac = <HmacBlake2s as KeyInit>::new_from_slice(key).expect("hmac takes any key");
+    for part in parts {
+        Mac::update(&mut hmac, part);
+    }
+    hmac.finalize().into_bytes().into()
+}
+
+fn kdf<const N: usize>(key: &[u8; KEY_LEN], input: impl AsRef<[u8]>) -> [[u8; KEY_LEN]; N] {
+    let mut secret = hmac(key, &[input.as_ref()]);
+    let mut outputs = [[0u8; KEY_LEN]; N];
+    let mut previous: &[u8] = &[];
+    for (i, output) in outputs.iter_mut().enumerate() {
+        *output = hmac(&secret, &[previous, &[i as u8 + 1]]);
+        previous = output;
+    }
+    secret.zeroize();
+    outputs
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce.into()
+}
+
+fn seal(key: &[u8; KEY_LEN], counter: u64, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(
+            &nonce(counter),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .expect("chacha20poly1305 encryption is infallible")
+}
+
+fn open(key: &[u8; KEY_LEN], counter: u64, ciphertext: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(
+            &nonce(counter),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    // The handshake below between fixed keys, checked against an independent
+    // implementation of the protocol.
+    const INITIATOR_INDEX: u32 = 0x0102_0304;
+    const RESPONDER_INDEX: u32 = 0x0a0b_0c0d;
+    const INITIATION: &str = "01000000040302017b0d47d93427f8311160781c7c733fd89f88970aef490d8aa0ee19a4cb8a1b\
+        1481e498317da959fba46669572516a5e6c021bfa620bb6c56ca0082e1feae14988c2c64d024c617734a263f76a0\
+        08df04d0f94bad8ee75ec8e699a440b4a50daa11f4b49e4391d702b229aa3b568b8ed0e3ceebefd4b11f97517007\
+        a600000000000000000000000000000000";
+    // the mac2 of the initiation with the cookie
+    const MAC2: &str = "f6c115df9294bec0653b001eed865943";
+    const RESPONSE: &str = "020000000d0c0b0a04030201ff2ee45601ec1b67310c7790404585ae697331eee1c1f8cf24\
+        19731c1fff3e6bda81a927fedf673417b5274250355a3fe12577315a06f95b40d38b9e825616930000000000000000\
+        0000000000000000";
+    // "hello" from the initiator, the first message of the session
+    const TRANSPORT: &str = "040000000d0c0b0a0000000000000000643526f2702defcdbd5d064c77112b4d1dc2a6303\
+        24ce847c671aacf5d5293da";
+    // the responder's reply to the initiation, carrying the cookie
+    const COOKIE_REPLY: &str = "03000000040302017777777777777777777777777777777777777777777777774\
+        71a164fe380c2686ed63ee1ec18da2e8447e531f0e24f579b9c78ba0a038336";
+    const COOKIE: Cookie = [0x66; MAC_LEN];
+    const PRESHARED_KEY: [u8; KEY_LEN] = [0x55; KEY_LEN];
+
+    fn decode(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn initiator() -> Identity {
+        Identity::new(&PrivateKey::from([0x11; KEY_LEN]))
+    }
+
+    fn responder() -> Identity {
+        Identity::new(&PrivateKey::from([0x22; KEY_LEN]))
+    }
+
+    fn timestamp() -> [u8; TIMESTAMP_LEN] {
+        tai64n(UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789))
+    }
+
+    fn initiate(cookie: Option<&Cookie>) -> ([u8; INITIATION_LEN], PendingHandshake) {
+        initiation(
+            &initiator(),
+            &responder().public_key(),
+            INITIATOR_INDEX,
+            StaticSecret::from([0x33; KEY_LEN]),
+            timestamp(),
+            cookie,
+        )
+        .unwrap()
+    }
+
+    fn respond(initiation: &ReceivedInitiation) -> ([u8; RESPONSE_LEN], Session) {
+        response(
+            initiation,
+            Some(&PRESHARED_KEY),
+            RESPONDER_INDEX,
+            StaticSecret::from([0x44; KEY_LEN]),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn derives_the_initial_state_from_the_protocol_name() {
+        let chaining_key = hash(&[CONSTRUCTION]);
+        assert_eq!(
+            chaining_key.to_vec(),
+            decode("60e26daef327efc02ec335e2a025d2d016eb4206f87277f52d38d1988b78cd36")
+        );
+        assert_eq!(
+            hash(&[&chaining_key, IDENTIFIER]).to_vec(),
+            decode("2211b361081ac566691243db458ad5322d9c6c662293e8b70ee19c65ba079ef3")
+        );
+    }
+
+    #[test]
+    fn computes_the_x25519_test_vector() {
+        // RFC 7748, section 5.2
+        let scalar: [u8; KEY_LEN] =
+            decode("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4")
+                .try_into()
+                .unwrap();
+        let point: [u8; KEY_LEN] =
+            decode("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c")
+                .try_into()
+                .unwrap();
+        assert_eq!(
+            dh(&StaticSecret::from(scalar), &point).unwrap().to_vec(),
+            decode("c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552")
+        );
+    }
+
+    #[test]
+    fn encodes_tai64n_timestamps() {
+        assert_eq!(timestamp().to_vec(), decode("400000006553f10a075bcd15"));
+    }
+
+    #[test]
+    fn creates_the_known_initiation() {
+        let (message, _) = initiate(None);
+        assert_eq!(message.to_vec(), decode(INITIATION));
+
+        let (message, _) = initiate(Some(&COOKIE));
+        assert_eq!(message[..132], decode(INITIATION)[..132]);
+        assert_eq!(message[132..].to_vec(), decode(MAC2));
+    }
+
+    #[test]
+    fn completes_the_known_handshake() {
+        let (message, pending) = initiate(None);
+
+        let initiation = consume_initiation(&responder(), &message).unwrap();
+        assert_eq!(initiation.peer, initiator().public_key());
+        assert_eq!(initiation.sender_index, INITIATOR_INDEX);
+        assert_eq!(initiation.timestamp, timestamp());
+
+        let (response, mut responder_session) = respond(&initiation);
+        assert_eq!(response.to_vec(), decode(RESPONSE));
+        assert!(!responder_session.confirmed);
+
+        let mut initiator_session =
+            consume_response(&initiator(), &pending, Some(&PRESHARED_KEY), &response).unwrap();
+        assert_eq!(initiator_session.local_index, INITIATOR_INDEX);
+        assert_eq!(initiator_session.remote_index, RESPONDER_INDEX);
+        assert!(initiator_session.confirmed);
+
+        let transport = initiator_session.encrypt(b"hello").unwrap();
+        assert_eq!(transport, decode(TRANSPORT));
+        let packet = responder_session.decrypt(&transport).unwrap();
+        assert_eq!(&packet[..5], b"hello");
+        assert!(packet[5..].iter().all(|byte| *byte == 0));
+        assert!(responder_session.confirmed);
+
+        let transport = responder_session.encrypt(&[]).unwrap();
+        assert_eq!(
+            initiator_session.decrypt(&transport).unwrap(),
+            Vec::<u8>::new()
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_handshakes() {
+        let (message, pending) = initiate(None);
+
+        let mut tampered = message;
+        tampered[50] ^= 1;
+        assert!(consume_initiation(&responder(), &tampered).is_none());
+        assert!(consume_initiation(&initiator(), &message).is_none());
+        assert!(consume_initiation(&responder(), &message[..100]).is_none());
+
+        let initiation = consume_initiation(&responder(), &message).unwrap();
+        let (response, _) = respond(&initiation);
+        // the preshared keys have to match
+        assert!(consume_response(&initiator(), &pending, None, &response).is_none());
+        let mut tampered = response;
+        tampered[8] ^= 1;
+        assert!(
+            consume_response(&initiator(), &pending, Some(&PRESHARED_KEY), &tampered).is_none()
+        );
+    }
+
+    #[test]
+    fn refuses_low_order_points() {
+        // the identity and a point of order 8
+        let low_order: [[u8; KEY_LEN]; 2] = [
+            [0; KEY_LEN],
+            decode("5f9c95bca3508c24b1d0b1559c83ef5b04445cc4581c8e86d8224eddd09f1157")
+                .try_into()
+                .unwrap(),
+        ];
+
+        for point in low_order {
+            assert!(dh(&StaticSecret::from([0x33; KEY_LEN]), &point).is_none());
+            assert!(create_initiation(&initiator(), &PublicKey::from(point), 1, None).is_none());
+
+            // an initiation with a low order ephemeral, correctly macced
+            let (mut message, pending) = initiate(None);
+            message[8..40].copy_from_slice(&point);
+            add_macs(&mut message, &responder().public_key(), None);
+            assert!(consume_initiation(&responder(), &message).is_none());
+
+            // and a response with one
+            let (message, _) = initiate(None);
+            let initiation = consume_initiation(&responder(), &message).unwrap();
+            let (mut response, _) = respond(&initiation);
+            response[12..44].copy_from_slice(&point);
+            add_macs(&mut response, &initiator().public_key(), None);
+            assert!(
+                consume_response(&initiator(), &pending, Some(&PRESHARED_KEY), &response).is_none()
+            );
+        }
+    }
+
+    #[test]
+    fn opens_the_known_cookie_reply() {
+        let (message, _) = initiate(None);
+        let reply = decode(COOKIE_REPLY);
+
+        assert_eq!(
+            consume_cookie_reply(&responder().public_key(), &mac1(&message), &reply),
+            Some(COOKIE)
+        );
+        // the reply is bound to the message it answers and the peer sending it
+        let mut other_mac1 = mac1(&message);
+        other_mac1[0] ^= 1;
+        assert!(consume_cookie_reply(&responder().public_key(), &other_mac1, &reply).is_none());
+        assert!(consume_cookie_reply(&initiator().public_key(), &mac1(&message), &reply).is_none());
+        assert!(
+            consume_cookie_reply(&responder().public_key(), &mac1(&message), &reply[..63])
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn drops_replayed_transport_messages() {
+        let (message, pending) = initiate(None);
+        let initiation = consume_initiation(&responder(), &message).unwrap();
+        let (response, mut responder_session) = respond(&initiation);
+        let mut initiator_session =
+            consume_response(&initiator(), &pending, Some(&PRESHARED_KEY), &response).unwrap();
+
+        let first = initiator_session.encrypt(b"first").unwrap();
+        let second = initiator_session.encrypt(b"second").unwrap();
+        assert!(responder_session.decrypt(&second).is_some());
+        assert!(responder_session.decrypt(&first).is_some());
+        assert!(responder_session.decrypt(&first).is_none());
+        assert!(responder_session.decrypt(&second).is_none());
+    }
+
+    #[test]
+    fn replay_window_accepts_new_and_reordered_counters() {
+        let mut window = ReplayWindow::default();
+        for counter in [0, 1, 2, 5, 4, 3] {
+            assert!(window.is_new(counter), "{}", counter);
+            window.mark(counter);
+        }
+        for counter in 0..=5 {
+            assert!(!window.is_new(counter), "{}", counter);
+        }
+        assert!(window.is_new(6));
+    }
+
+    #[test]
+    fn replay_window_drops_counters_older_than_the_window() {
+        let mut window = ReplayWindow::default();
+        window.mark(100);
+
+        assert!(window.is_new(37));
+        assert!(!window.is_new(36));
+        window.mark(37);
+        assert!(!window.is_new(37));
+
+        // a jump past the window forgets everything before it
+        window.mark(1000);
+        assert!(!window.is_new(100));
+        assert!(window.is_new(999));
+        assert!(window.is_new(937));
+        assert!(!window.is_new(936));
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    net::{Ipv4Addr, UdpSocket},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::fs::OpenOptionsExt,
+    },
+};
+
+const TUN_DEVICE: &str = "/dev/net/tun";
+// the default of the kernel module, which leaves room for the IPv6 overhead
+const DEFAULT_MTU: libc::c_int = 1420;
+
+// A TUN interface carrying bare IP packets without the packet information
+// header. It's persistent, so it outlives the handle until it's deleted or
+// its netns goes away, and a restarted agent can attach to it again.
+pub struct Tun {
+    file: File,
+}
+
+impl Tun {
+    pub fn create(name: &str) -> io::Result<Self> {
+        let tun = Tun::open(name)?;
+        // SAFETY: the fd is open, the argument is a plain integer
+        if unsafe { libc::ioctl(tun.file.as_raw_fd(), libc::TUNSETPERSIST, 1) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // interface ioctls take any socket
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        let mut request = ifreq(name);
+        request.ifr_ifru.ifru_mtu = DEFAULT_MTU;
+        // SAFETY: the fd is open and request outlives the call
+        if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFMTU, &request) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(tun)
+    }
+
+    // Opens an interface created earlier, keeping its configuration.
+    pub fn attach(name: &str) -> io::Result<Self> {
+        Tun::open(name)
+    }
+
+    fn open(name: &str) -> io::Result<Self> {
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("interface name {} is too long", name),
+            ));
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open(TUN_DEVICE)?;
+
+        let mut request = ifreq(name);
+        request.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+
+        // SAFETY: the fd is open and request outlives the call
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &request) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Tun { file })
+    }
+
+    pub fn recv(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buffer)
+    }
+
+    pub fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.file.write_all(packet)
+    }
+}
+
+fn ifreq(name: &str) -> libc::ifreq {
+    // SAFETY: ifreq is plain data, zeroed is a valid value
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in request.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    request
+}
+
+impl AsRawFd for Tun {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
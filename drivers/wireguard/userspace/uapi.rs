@@ -0,0 +1,579 @@
+use crate::{
+    system::netlink::Address,
+    wireguard::{
+        device::{Device, DeviceUpdate, Peer, PeerUpdate},
+        key::{KEY_LEN, PresharedKey, PrivateKey, PublicKey},
+    },
+};
+
+use std::{
+    ffi::CString,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::IpAddr,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, anyhow, bail};
+use zeroize::Zeroizing;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A handle on the control socket of a userspace device. It speaks the
+// configuration protocol of the cross platform implementations: key=value
+// lines terminated by an empty line, with keys in hex.
+// see https://www.wireguard.com/xplatform/
+pub struct Uapi {
+    path: PathBuf,
+}
+
+impl Uapi {
+    pub fn open(path: PathBuf) -> Self {
+        Uapi { path }
+    }
+
+    pub fn get_device(&mut self, name: &str) -> anyhow::Result<Device> {
+        let response = self.request("get=1\n\n")?;
+        let mut device = parse_device(&response)?;
+        device.name = name.to_string();
+        let name = CString::new(name).context("invalid interface name")?;
+        // SAFETY: name is a valid nul terminated string
+        device.index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        Ok(device)
+    }
+
+    pub fn set_device(&mut self, _name: &str, update: &DeviceUpdate) -> anyhow::Result<()> {
+        let mut request = Zeroizing::new("set=1\n".to_string());
+        request.push_str(&format_update(update));
+        request.push('\n');
+        self.request(&request)?;
+        Ok(())
+    }
+
+    fn request(&mut self, request: &str) -> anyhow::Result<Zeroizing<String>> {
+        let mut stream = UnixStream::connect(&self.path).with_context(|| {
+            format!(
+                "failed to connect to the userspace wireguard device at {}",
+                self.path.display()
+            )
+        })?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.write_all(request.as_bytes())?;
+
+        let response = read_message(&mut BufReader::new(stream))?
+            .context("userspace wireguard device closed the connection")?;
+        let (body, status) = match response.trim_end().rsplit_once('\n') {
+            Some((body, status)) => (body, status),
+            None => ("", response.trim_end()),
+        };
+        match status.strip_prefix("errno=") {
+            Some("0") => Ok(Zeroizing::new(body.to_string())),
+            Some(errno) => {
+                bail!("userspace wireguard device failed the request with errno {errno}")
+            }
+            None => bail!("userspace wireguard device sent an invalid response"),
+        }
+    }
+}
+
+// Reads up to the empty line ending a request or response, None when the
+// connection was closed before anything was sent.
+pub fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Zeroizing<String>>> {
+    let mut message = Zeroizing::new(String::new());
+    loop {
+        let mut line = Zeroizing::new(String::new());
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(if message.is_empty() {
+                None
+            } else {
+                Some(message)
+            });
+        }
+        if line.trim_end().is_empty() {
+            return Ok(Some(message));
+        }
+        message.push_str(&line);
+    }
+}
+
+pub fn format_device(device: &Device) -> Zeroizing<String> {
+    let mut text = Zeroizing::new(String::new());
+    if let Some(private_key) = &device.private_key {
+        let _ = writeln!(
+            text,
+            "private_key={}",
+            encode_key(private_key.as_bytes()).as_str()
+        );
+    }
+    let _ = writeln!(text, "listen_port={}", device.listen_port);
+    if device.fwmark != 0 {
+        let _ = writeln!(text, "fwmark={}", device.fwmark);
+    }
+    for peer in &device.peers {
+        let _ = writeln!(
+            text,
+            "public_key={}",
+            encode_key(peer.public_key.as_bytes()).as_str()
+        );
+        if let Some(preshared_key) = &peer.preshared_key {
+            let _ = writeln!(
+                text,
+                "preshared_key={}",
+                encode_key(preshared_key.as_bytes()).as_str()
+            );
+        }
+        if let Some(endpoint) = peer.endpoint {
+            let _ = writeln!(text, "endpoint={}", endpoint);
+        }
+        let _ = writeln!(
+            text,
+            "persistent_keepalive_interval={}",
+            peer.persistent_keepalive
+        );
+        let last_handshake = peer
+            .last_handshake
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        let _ = writeln!(text, "last_handshake_time_sec={}", last_handshake.as_secs());
+        let _ = writeln!(
+            text,
+            "last_handshake_time_nsec={}",
+            last_handshake.subsec_nanos()
+        );
+        let _ = writeln!(text, "rx_bytes={}", peer.rx_bytes);
+        let _ = writeln!(text, "tx_bytes={}", peer.tx_bytes);
+        for allowed_ip in &peer.allowed_ips {
+            let _ = writeln!(
+                text,
+                "allowed_ip={}/{}",
+                allowed_ip.address, allowed_ip.prefix
+            );
+        }
+    }
+    text
+}
+
+pub fn parse_device(text: &str) -> anyhow::Result<Device> {
+    let mut device = Device::default();
+    for (key, value) in lines(text)? {
+        if key == "public_key" {
+            device.peers.push(Peer {
+                public_key: PublicKey::from(*decode_key(value)?),
+                preshared_key: None,
+                endpoint: None,
+                allowed_ips: vec![],
+                persistent_keepalive: 0,
+                last_handshake: None,
+                rx_bytes: 0,
+                tx_bytes: 0,
+            });
+            continue;
+        }
+
+        let Some(peer) = device.peers.last_mut() else {
+            match key {
+                "private_key" => {
+                    let private_key = PrivateKey::from(*decode_key(value)?);
+                    device.public_key = Some(private_key.public_key());
+                    device.private_key = Some(private_key);
+                }
+                "listen_port" => device.listen_port = parse(key, value)?,
+                "fwmark" => device.fwmark = parse(key, value)?,
+                _ => {}
+            }
+            continue;
+        };
+        // the handshake time is split over two lines
+        let last_handshake = peer
+            .last_handshake
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        match key {
+            "preshared_key" => {
+                peer.preshared_key =
+                    Some(PresharedKey::from(*decode_key(value)?)).filter(|key| !key.is_zero())
+            }
+            "endpoint" => peer.endpoint = Some(parse(key, value)?),
+            "persistent_keepalive_interval" => peer.persistent_keepalive = parse(key, value)?,
+            "last_handshake_time_sec" => {
+                peer.last_handshake =
+                    handshake_time(parse(key, value)?, last_handshake.subsec_nanos())
+            }
+            "last_handshake_time_nsec" => {
+                peer.last_handshake = handshake_time(last_handshake.as_secs(), parse(key, value)?)
+            }
+            "rx_bytes" => peer.rx_bytes = parse(key, value)?,
+            "tx_bytes" => peer.tx_bytes = parse(key, value)?,
+            "allowed_ip" => peer.allowed_ips.push(parse_address(value)?),
+            _ => {}
+        }
+    }
+    Ok(device)
+}
+
+// peers which never completed a handshake report zero
+fn handshake_time(seconds: u64, nanoseconds: u32) -> Option<SystemTime> {
+    if seconds == 0 && nanoseconds == 0 {
+        return None;
+    }
+    SystemTime::UNIX_EPOCH.checked_add(Duration::new(seconds, nanoseconds))
+}
+
+pub fn format_update(update: &DeviceUpdate) -> Zeroizing<String> {
+    let mut text = Zeroizing::new(String::new());
+    if let Some(private_key) = &update.private_key {
+        let _ = writeln!(
+            text,
+            "private_key={}",
+            encode_key(private_key.as_bytes()).as_str()
+        );
+    }
+    if let Some(listen_port) = update.listen_port {
+        let _ = writeln!(text, "listen_port={}", listen_port);
+    }
+    if let Some(fwmark) = update.fwmark {
+        let _ = writeln!(text, "fwmark={}", fwmark);
+    }
+    if update.replace_peers {
+        let _ = writeln!(text, "replace_peers=true");
+    }
+    for peer in &update.peers {
+        let _ = writeln!(
+            text,
+            "public_key={}",
+            encode_key(peer.public_key.as_bytes()).as_str()
+        );
+        if peer.remove {
+            let _ = writeln!(text, "remove=true");
+            continue;
+        }
+        if peer.update_only {
+            let _ = writeln!(text, "update_only=true");
+        }
+        if let Some(preshared_key) = &peer.preshared_key {
+            let _ = writeln!(
+                text,
+                "preshared_key={}",
+                encode_key(preshared_key.as_bytes()).as_str()
+            );
+        }
+        if let Some(endpoint) = peer.endpoint {
+            let _ = writeln!(text, "endpoint={}", endpoint);
+        }
+        if let Some(persistent_keepalive) = peer.persistent_keepalive {
+            let _ = writeln!(
+                text,
+                "persistent_keepalive_interval={}",
+                persistent_keepalive
+            );
+        }
+        if peer.replace_allowed_ips {
+            let _ = writeln!(text, "replace_allowed_ips=true");
+        }
+        for allowed_ip in &peer.allowed_ips {
+            let _ = writeln!(
+                text,
+                "allowed_ip={}/{}",
+                allowed_ip.address, allowed_ip.prefix
+            );
+        }
+    }
+    text
+}
+
+pub fn parse_update(text: &str) -> anyhow::Result<DeviceUpdate> {
+    let mut update = DeviceUpdate::default();
+    for (key, value) in lines(text)? {
+        if key == "public_key" {
+            update
+                .peers
+                .push(PeerUpdate::new(PublicKey::from(*decode_key(value)?)));
+            continue;
+        }
+
+        let Some(peer) = update.peers.last_mut() else {
+            match key {
+                "private_key" => update.private_key = Some(PrivateKey::from(*decode_key(value)?)),
+                "listen_port" => update.listen_port = Some(parse(key, value)?),
+                "fwmark" => update.fwmark = Some(parse(key, value)?),
+                "replace_peers" => update.replace_peers = parse(key, value)?,
+                _ => bail!("unsupported device key {}", key),
+            }
+            continue;
+        };
+        match key {
+            "remove" => peer.remove = parse(key, value)?,
+            "update_only" => peer.update_only = parse(key, value)?,
+            "preshared_key" => peer.preshared_key = Some(PresharedKey::from(*decode_key(value)?)),
+            "endpoint" => peer.endpoint = Some(parse(key, value)?),
+            "persistent_keepalive_interval" => peer.persistent_keepalive = Some(parse(key, value)?),
+            "replace_allowed_ips" => peer.replace_allowed_ips = parse(key, value)?,
+            "allowed_ip" => peer.allowed_ips.push(parse_address(value)?),
+            _ => bail!("unsupported peer key {}", key),
+        }
+    }
+    Ok(update)
+}
+
+fn lines(text: &str) -> anyhow::Result<Vec<(&str, &str)>> {
+    text.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            line.split_once('=')
+                .ok_or_else(|| anyhow!("invalid line {:?}", line))
+        })
+        .collect()
+}
+
+fn parse<T: std::str::FromStr>(key: &str, value: &str) -> anyhow::Result<T> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("invalid value {:?} for {}", value, key))
+}
+
+fn parse_address(value: &str) -> anyhow::Result<Address> {
+    let (address, prefix) = value
+        .split_once('/')
+        .with_context(|| format!("invalid allowed ip {}", value))?;
+    let address: IpAddr = parse("allowed_ip", address)?;
+    let prefix = parse("allowed_ip", prefix)?;
+    Ok(Address { address, prefix })
+}
+
+fn encode_key(key: &[u8; KEY_LEN]) -> Zeroizing<String> {
+    let mut encoded = Zeroizing::new(String::with_capacity(2 * KEY_LEN));
+    for byte in key {
+        let _ = write!(encoded, "{:02x}", byte);
+    }
+    encoded
+}
+
+fn decode_key(value: &str) -> anyhow::Result<Zeroizing<[u8; KEY_LEN]>> {
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    if value.len() != 2 * KEY_LEN || !value.is_ascii() {
+        bail!("key is not {} hex digits", 2 * KEY_LEN);
+    }
+    for (byte, digits) in key.iter_mut().zip(value.as_bytes().chunks(2)) {
+        let digits = str::from_utf8(digits).expect("checked to be ascii");
+        *byte = u8::from_str_radix(digits, 16).context("key is not hex")?;
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        io::Cursor,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+        os::unix::net::UnixListener,
+        thread,
+    };
+
+    fn address(address: IpAddr, prefix: u8) -> Address {
+        Address { address, prefix }
+    }
+
+    fn public_key(seed: u8) -> PublicKey {
+        PrivateKey::from([seed; KEY_LEN]).public_key()
+    }
+
+    fn device() -> Device {
+        let private_key = PrivateKey::from([1; KEY_LEN]);
+        Device {
+            public_key: Some(private_key.public_key()),
+            private_key: Some(private_key),
+            listen_port: 51820,
+            fwmark: 921481285,
+            peers: vec![
+                Peer {
+                    public_key: public_key(2),
+                    preshared_key: Some(PresharedKey::from([9; KEY_LEN])),
+                    endpoint: Some(SocketAddr::from((Ipv4Addr::new(192, 168, 1, 2), 51820))),
+                    allowed_ips: vec![
+                        address(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 32),
+                        address(IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0)), 64),
+                    ],
+                    persistent_keepalive: 25,
+                    last_handshake: Some(SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 5)),
+                    rx_bytes: 1024,
+                    tx_bytes: 2048,
+                },
+                Peer {
+                    public_key: public_key(3),
+                    preshared_key: None,
+                    endpoint: None,
+                    allowed_ips: vec![],
+                    persistent_keepalive: 0,
+                    last_handshake: None,
+                    rx_bytes: 0,
+                    tx_bytes: 0,
+                },
+            ],
+            ..Device::default()
+        }
+    }
+
+    fn update() -> DeviceUpdate {
+        let mut added = PeerUpdate::new(public_key(2));
+        added.preshared_key = Some(PresharedKey::from([9; KEY_LEN]));
+        added.endpoint = Some(SocketAddr::from((Ipv4Addr::new(192, 168, 1, 2), 51820)));
+        added.persistent_keepalive = Some(25);
+        added.replace_allowed_ips = true;
+        added.allowed_ips = vec![address(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 32)];
+        let mut updated = PeerUpdate::new(public_key(3));
+        updated.update_only = true;
+        updated.persistent_keepalive = Some(0);
+
+        DeviceUpdate {
+            private_key: Some(PrivateKey::from([1; KEY_LEN])),
+            listen_port: Some(51820),
+            fwmark: Some(921481285),
+            replace_peers: true,
+            peers: vec![added, updated, PeerUpdate::remove(public_key(4))],
+        }
+    }
+
+    #[test]
+    fn devices_round_trip() {
+        let device = device();
+
+        let text = format_device(&device);
+        let parsed = parse_device(&text).unwrap();
+
+        assert_eq!(parsed.public_key, device.public_key);
+        assert_eq!(parsed.listen_port, device.listen_port);
+        assert_eq!(parsed.fwmark, device.fwmark);
+        assert_eq!(parsed.peers, device.peers);
+        assert_eq!(format_device(&parsed).as_str(), text.as_str());
+    }
+
+    #[test]
+    fn updates_round_trip() {
+        let update = update();
+
+        let text = format_update(&update);
+        let parsed = parse_update(&text).unwrap();
+
+        assert_eq!(format_update(&parsed).as_str(), text.as_str());
+        assert!(parsed.replace_peers);
+        assert_eq!(parsed.peers.len(), 3);
+        assert!(parsed.peers[0].replace_allowed_ips);
+        assert!(parsed.peers[1].update_only);
+        assert!(parsed.peers[2].remove);
+    }
+
+    #[test]
+    fn formats_keys_in_hex() {
+        let text = format_update(&DeviceUpdate {
+            private_key: Some(PrivateKey::from([0xab; KEY_LEN])),
+            ..DeviceUpdate::default()
+        });
+
+        assert_eq!(
+            text.as_str(),
+            format!("private_key={}\n", "ab".repeat(KEY_LEN))
+        );
+    }
+
+    #[test]
+    fn zero_preshared_keys_read_as_none() {
+        let text = format!(
+            "public_key={}\npreshared_key={}\n",
+            encode_key(public_key(2).as_bytes()).as_str(),
+            "00".repeat(KEY_LEN)
+        );
+
+        let device = parse_device(&text).unwrap();
+
+        assert_eq!(device.peers[0].preshared_key, None);
+    }
+
+    #[test]
+    fn rejects_invalid_updates() {
+        let peer = format!(
+            "public_key={}\n",
+            encode_key(public_key(2).as_bytes()).as_str()
+        );
+        for text in [
+            "listen_port=port\n".to_string(),
+            "unknown=1\n".to_string(),
+            "private_key=abcd\n".to_string(),
+            format!("private_key={}\n", "zz".repeat(KEY_LEN)),
+            "listen_port\n".to_string(),
+            format!("{}allowed_ip=10.0.0.2\n", peer),
+            format!("{}unknown=1\n", peer),
+        ] {
+            assert!(parse_update(&text).is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn reads_messages_up_to_the_empty_line() {
+        let mut reader = Cursor::new("get=1\n\nset=1\nlisten_port=1\n\nunterminated=1\n");
+
+        assert_eq!(
+            read_message(&mut reader).unwrap().unwrap().as_str(),
+            "get=1\n"
+        );
+        assert_eq!(
+            read_message(&mut reader).unwrap().unwrap().as_str(),
+            "set=1\nlisten_port=1\n"
+        );
+        assert_eq!(
+            read_message(&mut reader).unwrap().unwrap().as_str(),
+            "unterminated=1\n"
+        );
+        assert!(read_message(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn requests_round_trip_over_the_socket() {
+        let path = std::env::temp_dir().join(format!("podtunnel-uapi-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let device = device();
+        let response = format_device(&device);
+
+        let server = thread::spawn(move || {
+            let mut requests = vec![];
+            for response in [
+                format!("{}errno=0\n\n", response.as_str()),
+                "errno=0\n\n".to_string(),
+                format!("errno={}\n\n", libc::EINVAL),
+            ] {
+                let (stream, _) = listener.accept().unwrap();
+                let request = read_message(&mut BufReader::new(&stream)).unwrap().unwrap();
+                requests.push(request.to_string());
+                (&stream).write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+
+        let mut uapi = Uapi::open(path.clone());
+        let got = uapi.get_device("lo").unwrap();
+        assert_eq!(got.name, "lo");
+        assert_eq!(got.index, 1);
+        assert_eq!(got.peers, device.peers);
+        uapi.set_device("lo", &update()).unwrap();
+        let err = uapi.set_device("lo", &update()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "userspace wireguard device failed the request with errno {}",
+                libc::EINVAL
+            )
+        );
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0], "get=1\n");
+        assert_eq!(
+            requests[1],
+            format!("set=1\n{}", format_update(&update()).as_str())
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+}
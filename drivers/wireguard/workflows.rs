@@ -1,224 +1,1206 @@
 // FIXME: 🐉!here be dragons!🐉
 use crate::{
-    info,
-    system::linux::{run, run_with_stdin},
+    driver::{ConfiguredInterface, PeerStats, Tunnel, TunnelStats},
+    executor::{Executor, SystemExecutor},
+    kube::{INITIAL_READINESS_BACKOFF, MAX_READINESS_BACKOFF, NotReady, kube_client},
+    settings::Settings,
+    system::{
+        netlink::{
+            Address, Link, LinkKind, MAIN_TABLE, NetlinkError, Route, Rule, ignore_not_found,
+        },
+        netns::run_in_netns,
+    },
+    transaction::{StepFailed, Transaction, Undo},
+    wireguard::{
+        device::{DeviceUpdate, Peer, PeerUpdate, WireguardControl},
+        key::{KEY_LEN, PresharedKey, PrivateKey, PublicKey},
+        userspace,
+    },
+};
+use api::{
+    ObjectReference,
+    wireguard::{
+        DEFAULT_WIREGUARD_LISTEN_PORT, WireguardConfig, WireguardConfigStatus, WireguardInterface,
+        WireguardMode, WireguardPeerConfig, WireguardRoutingMode,
+    },
 };
-use api::wireguard::{WireguardConfig, WireguardConfigStatus, WireguardPeerConfig};
 
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::net::Ipv4Addr;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
 
 use anyhow::{Context, anyhow};
-use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::{api::core::v1::Secret, serde_json::json};
 use kube::{
     Api, Client as KubeClient, Error as KubeError,
-    api::{ListParams, ObjectList, Patch, PatchParams},
+    api::{Patch, PatchParams},
 };
-use nix::sched::{CloneFlags, setns};
+use serde::{Deserialize, Serialize};
+use tokio::time::{Instant, sleep};
+use tracing::info;
+
+const WIREGUARD_TRAFFIC_PRIORITY: u32 = 1;
+const TUNNEL_TRAFFIC_PRIORITY: u32 = 2;
+const SUPPRESS_PRIORITY: u32 = 3;
 
-const SECRET_LABEL: &str = "operator.podtunnel.com/wireguard_config";
-const DEFAULT_WIREGUARD_INTERFACE_NAME: &str = "wg0";
+// the outer IP and UDP headers and the WireGuard data message header
+const WIREGUARD_IPV4_OVERHEAD: u32 = 60;
+const WIREGUARD_IPV6_OVERHEAD: u32 = 80;
+// IPv4 and TCP headers without options
+const TCP_IPV4_OVERHEAD: u32 = 40;
+
+// The keys behind the preshared_key references of peers, read from their
+// Secrets just before they're applied.
+type PresharedKeys = HashMap<ObjectReference, PresharedKey>;
+
+// What the wireguard driver configured besides the interface itself.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WireguardTunnel {
+    pub listen_port: u16,
+    pub public_key: Option<String>,
+    pub peers: Vec<WireguardPeerConfig>,
+    // results cached before routing modes existed were full tunnels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub routing_mode: Option<WireguardRoutingMode>,
+    #[serde(default)]
+    pub mss_clamping: bool,
+}
 
 pub async fn configure_wireguard_for_pod(
     name: &str,
     namespace: &str,
     netns: &str,
     pod_ip: &Ipv4Addr,
-) -> anyhow::Result<Option<(String, Ipv4Addr, u8)>> {
-    unsafe {
-        std::env::set_var("KUBECONFIG", "/etc/kubernetes/admin.conf");
-    };
-    let kube_client = KubeClient::try_default().await?;
+    settings: &Settings,
+) -> anyhow::Result<Option<ConfiguredInterface>> {
+    let kube_client = kube_client(settings).await?;
 
     info!("finding WireguardConfig for Pod {}", name);
-    let wireguard_config = match get_wg_config(&kube_client, namespace, name, pod_ip).await? {
+    let wireguard_config = match get_wg_config(
+        &kube_client,
+        namespace,
+        name,
+        pod_ip,
+        settings.readiness_timeout,
+    )
+    .await?
+    {
         Some(wireguard_config) => wireguard_config,
         None => return Ok(None),
     };
 
     info!("getting private_key for Pod{}", name);
-    let private_key = get_privkey(&kube_client, name, namespace).await?;
+    let private_key = get_privkey(&kube_client, &wireguard_config, namespace).await?;
 
-    let (tunnel_address, tunnel_address_prefix, listen_port) = getnet(&wireguard_config);
-    Ok(configure_wireguard_interface(
+    let (tunnel_address, tunnel_address_prefix, listen_port) = getnet(&wireguard_config)?;
+    let interface = wireguard_config.spec.interface;
+    let status = wireguard_config
+        .status
+        .context("WireguardConfig has no status")?;
+    let preshared_keys = get_preshared_keys(&kube_client, namespace, &status.peers).await?;
+    let (configured_interface, mode) = configure_wireguard_interface(
         netns,
         &tunnel_address,
         tunnel_address_prefix,
         &private_key,
         listen_port,
-        wireguard_config.status.unwrap().peers,
-    )
-    .await?)
+        status,
+        &preshared_keys,
+        &interface,
+        settings,
+    )?;
+
+    // the tunnel works either way, so failing to report it doesn't fail ADD
+    info!(
+        "reporting {:?} mode and mtu {:?} for WireguardConfig {}",
+        mode, configured_interface.mtu, name
+    );
+    let wireguard_configs: Api<WireguardConfig> = Api::namespaced(kube_client, namespace);
+    let patch = json!({
+        "status": {
+            "mode": mode,
+            "mtu": configured_interface.mtu,
+        }
+    });
+    if let Err(err) = wireguard_configs
+        .patch_status(name, &PatchParams::default(), &Patch::Merge(&patch))
+        .await
+    {
+        info!(
+            "failed to report the interface status for {}: {}",
+            name, err
+        );
+    }
+
+    Ok(Some(configured_interface))
 }
 
-async fn configure_wireguard_interface(
+#[allow(clippy::too_many_arguments)]
+fn configure_wireguard_interface(
     netns: &str,
     tunnel_address: &Ipv4Addr,
     tunnel_address_prefix: u8,
-    private_key: &str,
+    private_key: &PrivateKey,
     listen_port: u16,
-    peers: Vec<WireguardPeerConfig>,
-) -> anyhow::Result<Option<(String, Ipv4Addr, u8)>> {
-    let original_netns_file = File::open("/proc/self/ns/net")?;
-    let container_netns_file = File::open(&netns)?;
+    status: WireguardConfigStatus,
+    preshared_keys: &PresharedKeys,
+    interface: &WireguardInterface,
+    settings: &Settings,
+) -> anyhow::Result<(ConfiguredInterface, WireguardMode)> {
+    let peers = status.peers;
+    let container_netns_file = File::open(netns)?;
+    let (link, mode) = run_in_netns(&container_netns_file, || {
+        apply_wireguard_state(
+            &mut SystemExecutor::open()?,
+            netns,
+            tunnel_address,
+            tunnel_address_prefix,
+            private_key,
+            listen_port,
+            &peers,
+            preshared_keys,
+            interface,
+            settings,
+        )
+    })?;
 
-    info!("pod netns: {:?}", &container_netns_file);
-    setns(&container_netns_file, CloneFlags::CLONE_NEWNET)?;
+    let mut routes: Vec<String> = vec![];
+    if interface.routing_mode != WireguardRoutingMode::None {
+        for allowed_ip in peers.iter().flat_map(|peer| &peer.allowed_ips) {
+            if !routes.contains(allowed_ip) {
+                routes.push(allowed_ip.clone());
+            }
+        }
+    }
 
-    info!("adding wireguard interface");
-    run(
-        "ip",
-        vec![
-            "link",
-            "add",
-            DEFAULT_WIREGUARD_INTERFACE_NAME,
-            "type",
-            "wireguard",
-        ],
-    )?;
+    let configured_interface = ConfiguredInterface {
+        name: settings.interface_name.clone(),
+        address: *tunnel_address,
+        prefix: tunnel_address_prefix,
+        mtu: link.mtu.map(|mtu| mtu as usize),
+        mac: link.mac,
+        routes,
+        dns: interface.dns.clone().unwrap_or_default(),
+        tunnel: Tunnel::Wireguard(WireguardTunnel {
+            listen_port,
+            public_key: status.public_key,
+            peers,
+            routing_mode: Some(interface.routing_mode),
+            mss_clamping: interface.mss_clamping,
+        }),
+    };
+    Ok((configured_interface, mode))
+}
 
-    info!("adding address {} to wireguard interface", &tunnel_address);
-    run(
-        "ip",
-        vec![
-            "addr",
-            "add",
-            &format!("{}/{}", tunnel_address, tunnel_address_prefix),
-            "dev",
-            DEFAULT_WIREGUARD_INTERFACE_NAME,
-        ],
-    )?;
+// Either the whole configuration is applied, or the steps which succeeded
+// are undone again before the error is returned.
+#[allow(clippy::too_many_arguments)]
+fn apply_wireguard_state(
+    executor: &mut impl Executor,
+    netns: &str,
+    tunnel_address: &Ipv4Addr,
+    tunnel_address_prefix: u8,
+    private_key: &PrivateKey,
+    listen_port: u16,
+    peers: &[WireguardPeerConfig],
+    preshared_keys: &PresharedKeys,
+    interface: &WireguardInterface,
+    settings: &Settings,
+) -> anyhow::Result<(Link, WireguardMode)> {
+    let mut transaction = Transaction::default();
 
-    info!("configuring private key for wireguard interface");
-    run_with_stdin(
-        "wg",
-        vec![
-            "set",
-            DEFAULT_WIREGUARD_INTERFACE_NAME,
-            "private-key",
-            "/dev/stdin",
-        ],
-        &private_key,
-    )?;
+    let result = apply_wireguard_steps(
+        executor,
+        &mut transaction,
+        netns,
+        tunnel_address,
+        tunnel_address_prefix,
+        private_key,
+        listen_port,
+        peers,
+        preshared_keys,
+        interface,
+        settings,
+    );
+    if result.is_err() {
+        transaction.rollback(executor);
+    }
+    Ok(result?)
+}
 
-    info!("setting the wireguard listen-port {}", listen_port);
-    run(
-        "wg",
-        vec![
-            "set",
-            DEFAULT_WIREGUARD_INTERFACE_NAME,
-            "listen-port",
-            &listen_port.to_string(),
-        ],
-    )?;
+#[allow(clippy::too_many_arguments)]
+fn apply_wireguard_steps(
+    executor: &mut impl Executor,
+    transaction: &mut Transaction,
+    netns: &str,
+    tunnel_address: &Ipv4Addr,
+    tunnel_address_prefix: u8,
+    private_key: &PrivateKey,
+    listen_port: u16,
+    peers: &[WireguardPeerConfig],
+    preshared_keys: &PresharedKeys,
+    interface: &WireguardInterface,
+    settings: &Settings,
+) -> Result<(Link, WireguardMode), StepFailed> {
+    let interface_name = settings.interface_name.as_str();
+
+    let peer_updates = transaction.apply("parse the peer configuration", None, || {
+        peers
+            .iter()
+            .map(|peer| peer_update(peer, preshared_keys))
+            .collect::<anyhow::Result<Vec<_>>>()
+    })?;
 
-    info!("bringing the wireguard interface up");
-    run(
-        "ip",
-        vec!["link", "set", DEFAULT_WIREGUARD_INTERFACE_NAME, "up"],
+    // deleting the interface also stops a userspace device
+    let (mut link, mode) = transaction.apply(
+        "add the wireguard interface",
+        Some(Undo::DelLink(interface_name.to_string())),
+        || -> anyhow::Result<(Link, WireguardMode)> {
+            let mode = match executor.add_link(interface_name, LinkKind::Wireguard) {
+                Ok(()) => WireguardMode::Kernel,
+                Err(NetlinkError::Kernel(err))
+                    if err.raw_os_error() == Some(libc::EOPNOTSUPP)
+                        && settings.userspace_fallback =>
+                {
+                    info!("the kernel does not support wireguard, falling back to userspace");
+                    executor.spawn_userspace_device(netns, interface_name)?;
+                    WireguardMode::Userspace
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let link = executor
+                .link(interface_name)?
+                .context("wireguard interface vanished after it was added")?;
+            Ok((link, mode))
+        },
     )?;
 
-    info!("setting the fwmark");
-    let fwmark = "921481285";
-    run(
-        "wg",
-        vec!["set", DEFAULT_WIREGUARD_INTERFACE_NAME, "fwmark", &fwmark],
+    let address = Address {
+        address: IpAddr::V4(*tunnel_address),
+        prefix: tunnel_address_prefix,
+    };
+    transaction.apply(
+        format!("add address {}/{}", tunnel_address, tunnel_address_prefix),
+        Some(Undo::DelAddress(link.index, address)),
+        || executor.add_address(link.index, address),
     )?;
 
-    info!("adding a custom routing table");
-    let routing_table = "129518285";
-    run(
-        "ip",
-        vec![
-            "route",
-            "add",
-            "default",
-            "dev",
-            DEFAULT_WIREGUARD_INTERFACE_NAME,
-            "table",
-            &routing_table,
-        ],
+    if let Some(mtu) = tunnel_mtu(interface, peers, settings).filter(|mtu| link.mtu != Some(*mtu)) {
+        transaction.apply(format!("set the mtu to {}", mtu), None, || {
+            executor.set_link_mtu(link.index, mtu)
+        })?;
+        link.mtu = Some(mtu);
+    }
+
+    transaction.apply(
+        format!(
+            "configure the wireguard device with listen-port {} and {} peers",
+            listen_port,
+            peer_updates.len()
+        ),
+        None,
+        || {
+            executor.set_device(
+                interface_name,
+                &DeviceUpdate {
+                    private_key: Some(private_key.clone()),
+                    listen_port: Some(listen_port),
+                    fwmark: Some(settings.fwmark),
+                    replace_peers: true,
+                    peers: peer_updates,
+                },
+            )
+        },
     )?;
 
-    info!("configuring peers");
-    for peer in peers {
-        info!("configuring peer {}", &peer.public_key);
-        run(
-            "wg",
-            vec![
-                "set",
-                DEFAULT_WIREGUARD_INTERFACE_NAME,
-                "peer",
-                &peer.public_key,
-                "allowed-ips",
-                &peer.allowed_ips.join(","),
-                "endpoint",
-                &peer.endpoint(),
-            ],
-        )?;
+    transaction.apply("bring the wireguard interface up", None, || {
+        executor.set_link_up(link.index)
+    })?;
 
-        info!("routing wireguard traffic to the main routing table");
-        run(
-            "ip",
-            vec![
-                "rule",
-                "add",
-                "from",
-                "all",
-                "to",
-                &peer.endpoint_address.to_string(),
-                "fwmark",
-                &fwmark,
-                "lookup",
-                "main",
-                "priority",
-                "1",
-            ],
+    let routing_mode = interface.routing_mode;
+    let advmss = advmss(interface.mss_clamping, link.mtu);
+    let (routes, rules) = transaction.apply("plan the routing", None, || {
+        routing(routing_mode, link.index, advmss, peers, settings)
+    })?;
+    for route in routes {
+        transaction.apply(
+            format!("add the {}", route_name(&route)),
+            Some(Undo::DelRoute(route.clone())),
+            || executor.add_route(&route),
         )?;
-
-        info!("routing regular traffic over the wireguard tunnel");
-        run(
-            "ip",
-            vec![
-                "rule",
-                "add",
-                "from",
-                "all",
-                "to",
-                &peer.endpoint_address.to_string(),
-                "fwmark",
-                "0",
-                "lookup",
-                &routing_table,
-                "priority",
-                "2",
-            ],
+    }
+    for rule in rules {
+        transaction.apply(
+            format!("add the {}", rule_name(&rule)),
+            Some(Undo::DelRule(rule.clone())),
+            || executor.add_rule(&rule),
         )?;
     }
+    if routing_mode != WireguardRoutingMode::Full {
+        return Ok((link, mode));
+    }
 
-    info!("ensure most specific routing rules match");
-    run(
-        "ip",
-        vec![
-            "rule",
-            "add",
-            "table",
-            "main",
-            "suppress_prefixlength",
-            "0",
-            "priority",
-            "3",
-        ],
-    )?;
+    // the rule is shared with other podtunnel instances in the same netns,
+    // so it's only ours to undo when it didn't exist yet
+    let step = "add the suppress_prefixlength rule";
+    let added = transaction.apply(step, None, || match executor.add_rule(&suppress_rule()) {
+        Ok(()) => Ok(true),
+        Err(NetlinkError::Exists) => Ok(false),
+        Err(err) => Err(err),
+    })?;
+    if added {
+        transaction.record(step, Undo::DelRule(suppress_rule()));
+    }
 
-    info!("returning back to original netns");
-    setns(original_netns_file, CloneFlags::CLONE_NEWNET)?;
+    Ok((link, mode))
+}
 
-    Ok(Some((
-        DEFAULT_WIREGUARD_INTERFACE_NAME.to_string(),
-        *tunnel_address,
-        tunnel_address_prefix,
-    )))
+pub async fn teardown_wireguard_for_pod(
+    name: &str,
+    namespace: &str,
+    netns: Option<&str>,
+    pod_address: Option<Ipv4Addr>,
+    settings: &Settings,
+) -> anyhow::Result<()> {
+    match netns {
+        Some(netns) => teardown_wireguard_interface(netns, settings)?,
+        None => info!(
+            "no netns provided for Pod {}, skipping interface teardown",
+            name
+        ),
+    }
+
+    let kube_client = kube_client(settings).await?;
+
+    info!("clearing pod_address for WireguardConfig {}", name);
+    clear_pod_address(&kube_client, namespace, name, pod_address).await
+}
+
+pub fn teardown_wireguard_interface(netns: &str, settings: &Settings) -> anyhow::Result<()> {
+    let container_netns_file = match File::open(netns) {
+        Ok(container_netns_file) => container_netns_file,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            info!("pod netns {} is already gone, skipping", netns);
+            return Ok(());
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    run_in_netns(&container_netns_file, || {
+        remove_wireguard_state(&mut SystemExecutor::open()?, settings)?;
+        remove_userspace_socket(&settings.interface_name)
+    })
+}
+
+fn remove_wireguard_state(executor: &mut impl Executor, settings: &Settings) -> anyhow::Result<()> {
+    let interface_name = settings.interface_name.as_str();
+
+    info!("removing routing rules");
+    let rules = executor.rules()?;
+    for rule in rules.iter().filter(|rule| is_own_rule(rule, settings)) {
+        ignore_not_found(executor.del_rule(rule))?;
+    }
+
+    if !suppress_rule_is_shared(&rules, settings) {
+        ignore_not_found(executor.del_rule(&suppress_rule()))?;
+    }
+
+    info!("removing the wireguard interface");
+    if let Some(link) = executor.link(interface_name)? {
+        ignore_not_found(executor.del_link(link.index))?;
+    }
+
+    info!("flushing the custom routing table");
+    for route in executor.routes()? {
+        if route.table == settings.routing_table {
+            ignore_not_found(executor.del_route(&route))?;
+        }
+    }
+
+    Ok(())
+}
+
+// A userspace device removes its socket and saved state as it exits, which
+// may lag behind the interface, or not happen at all if the agent was down.
+fn remove_userspace_socket(interface_name: &str) -> anyhow::Result<()> {
+    userspace::remove(interface_name)
+}
+
+// The suppress_prefixlength rule is only removed once no other tunnel
+// relies on it.
+fn suppress_rule_is_shared(rules: &[Rule], settings: &Settings) -> bool {
+    rules
+        .iter()
+        .any(|rule| rule.priority == TUNNEL_TRAFFIC_PRIORITY && !is_own_rule(rule, settings))
+}
+
+fn is_own_rule(rule: &Rule, settings: &Settings) -> bool {
+    match rule.priority {
+        WIREGUARD_TRAFFIC_PRIORITY => rule.fwmark == settings.fwmark && rule.table == MAIN_TABLE,
+        TUNNEL_TRAFFIC_PRIORITY => rule.table == settings.routing_table,
+        _ => false,
+    }
+}
+
+fn default_route(interface: u32, advmss: Option<u32>, settings: &Settings) -> Route {
+    Route {
+        destination: None,
+        interface: Some(interface),
+        table: settings.routing_table,
+        advmss,
+    }
+}
+
+fn wireguard_traffic_rule(endpoint_address: Ipv4Addr, settings: &Settings) -> Rule {
+    Rule {
+        priority: WIREGUARD_TRAFFIC_PRIORITY,
+        destination: Some(host_address(endpoint_address)),
+        fwmark: settings.fwmark,
+        table: MAIN_TABLE,
+        suppress_prefixlength: None,
+    }
+}
+
+fn tunnel_traffic_rule(endpoint_address: Ipv4Addr, settings: &Settings) -> Rule {
+    Rule {
+        priority: TUNNEL_TRAFFIC_PRIORITY,
+        destination: Some(host_address(endpoint_address)),
+        fwmark: 0,
+        table: settings.routing_table,
+        suppress_prefixlength: None,
+    }
+}
+
+// everything else is looked up in the table, which only has routes for the
+// allowed ips of a split tunnel
+fn split_traffic_rule(settings: &Settings) -> Rule {
+    Rule {
+        priority: TUNNEL_TRAFFIC_PRIORITY,
+        destination: None,
+        fwmark: 0,
+        table: settings.routing_table,
+        suppress_prefixlength: None,
+    }
+}
+
+// The routes in the custom table and the rules a routing mode needs, apart
+// from the suppress_prefixlength rule which is shared between full tunnels.
+// The wireguard traffic rules keep the tunnel's own packets to an endpoint
+// from being routed into the tunnel.
+fn routing(
+    routing_mode: WireguardRoutingMode,
+    interface: u32,
+    advmss: Option<u32>,
+    peers: &[WireguardPeerConfig],
+    settings: &Settings,
+) -> anyhow::Result<(Vec<Route>, Vec<Rule>)> {
+    let endpoint_addresses = endpoint_addresses(peers);
+    match routing_mode {
+        WireguardRoutingMode::Full => Ok((
+            vec![default_route(interface, advmss, settings)],
+            endpoint_addresses
+                .into_iter()
+                .flat_map(|endpoint_address| {
+                    [
+                        wireguard_traffic_rule(endpoint_address, settings),
+                        tunnel_traffic_rule(endpoint_address, settings),
+                    ]
+                })
+                .collect(),
+        )),
+        WireguardRoutingMode::Split => {
+            let mut routes = vec![];
+            // the custom table is IPv4 only, like the tunnel addresses
+            for allowed_ip in peers.iter().flat_map(|peer| &peer.allowed_ips) {
+                let allowed_ip = parse_allowed_ip(allowed_ip)?;
+                let route = Route {
+                    destination: Some(allowed_ip),
+                    interface: Some(interface),
+                    table: settings.routing_table,
+                    advmss,
+                };
+                if allowed_ip.address.is_ipv4() && !routes.contains(&route) {
+                    routes.push(route);
+                }
+            }
+            let mut rules: Vec<Rule> = endpoint_addresses
+                .into_iter()
+                .map(|endpoint_address| wireguard_traffic_rule(endpoint_address, settings))
+                .collect();
+            rules.push(split_traffic_rule(settings));
+            Ok((routes, rules))
+        }
+        WireguardRoutingMode::None => Ok((vec![], vec![])),
+    }
+}
+
+// The interface MTU asked for, or the MTU of the Pod's interface less the
+// WireGuard overhead. None leaves the MTU the interface was created with.
+fn tunnel_mtu(
+    interface: &WireguardInterface,
+    peers: &[WireguardPeerConfig],
+    settings: &Settings,
+) -> Option<u32> {
+    if interface.mtu.is_some() {
+        return interface.mtu;
+    }
+    // without peers there's no telling, so leave room for IPv6
+    let overhead = peers
+        .iter()
+        .map(|peer| match peer_endpoint(peer) {
+            SocketAddr::V4(_) => WIREGUARD_IPV4_OVERHEAD,
+            SocketAddr::V6(_) => WIREGUARD_IPV6_OVERHEAD,
+        })
+        .max()
+        .unwrap_or(WIREGUARD_IPV6_OVERHEAD);
+    settings
+        .underlay_mtu
+        .map(|underlay_mtu| underlay_mtu.saturating_sub(overhead))
+}
+
+// the tunnel only carries IPv4, so the MSS fits a segment with IPv4 headers
+pub(crate) fn advmss(mss_clamping: bool, mtu: Option<u32>) -> Option<u32> {
+    mtu.filter(|_| mss_clamping)
+        .map(|mtu| mtu.saturating_sub(TCP_IPV4_OVERHEAD))
+}
+
+fn route_name(route: &Route) -> String {
+    match route.destination {
+        Some(destination) => format!(
+            "route to {}/{} in table {}",
+            destination.address, destination.prefix, route.table
+        ),
+        None => format!("default route to table {}", route.table),
+    }
+}
+
+fn rule_name(rule: &Rule) -> String {
+    let kind = match rule.priority {
+        WIREGUARD_TRAFFIC_PRIORITY => "wireguard traffic",
+        TUNNEL_TRAFFIC_PRIORITY => "tunnel traffic",
+        _ => "routing",
+    };
+    match rule.destination {
+        Some(destination) => format!("{} rule for {}", kind, destination.address),
+        None => format!("{} rule", kind),
+    }
+}
+
+fn suppress_rule() -> Rule {
+    Rule {
+        priority: SUPPRESS_PRIORITY,
+        destination: None,
+        fwmark: 0,
+        table: MAIN_TABLE,
+        suppress_prefixlength: Some(0),
+    }
+}
+
+fn host_address(address: Ipv4Addr) -> Address {
+    Address {
+        address: IpAddr::V4(address),
+        prefix: 32,
+    }
+}
+
+// Brings a running tunnel in line with the current WireguardConfig status and
+// returns what had to change, which is nothing if it was already up to date.
+pub async fn reconcile_wireguard_for_pod(
+    name: &str,
+    namespace: &str,
+    netns: &str,
+    settings: &Settings,
+) -> anyhow::Result<Vec<String>> {
+    let kube_client = kube_client(settings).await?;
+
+    info!("finding WireguardConfig for Pod {}", name);
+    let wireguard_configs: Api<WireguardConfig> = Api::namespaced(kube_client.clone(), namespace);
+    let wireguard_config = wireguard_configs.get(name).await?;
+    let interface = wireguard_config.spec.interface;
+    let status = wireguard_config
+        .status
+        .context("WireguardConfig has no status")?;
+    let preshared_keys = get_preshared_keys(&kube_client, namespace, &status.peers).await?;
+
+    let changes =
+        reconcile_wireguard_interface(netns, &status, &interface, &preshared_keys, settings)?;
+
+    // like on ADD, a stale status doesn't fail the reconcile
+    let mtu = tunnel_mtu(&interface, &status.peers, settings);
+    if mtu.is_some() && mtu != status.mtu {
+        info!("reporting mtu {:?} for WireguardConfig {}", mtu, name);
+        let patch = json!({
+            "status": {
+                "mtu": mtu,
+            }
+        });
+        if let Err(err) = wireguard_configs
+            .patch_status(name, &PatchParams::default(), &Patch::Merge(&patch))
+            .await
+        {
+            info!(
+                "failed to report the interface status for {}: {}",
+                name, err
+            );
+        }
+    }
+
+    Ok(changes)
+}
+
+fn reconcile_wireguard_interface(
+    netns: &str,
+    desired: &WireguardConfigStatus,
+    interface: &WireguardInterface,
+    preshared_keys: &PresharedKeys,
+    settings: &Settings,
+) -> anyhow::Result<Vec<String>> {
+    let container_netns_file = File::open(netns)?;
+    run_in_netns(&container_netns_file, || {
+        reconcile_wireguard_state(
+            &mut SystemExecutor::open()?,
+            desired,
+            interface,
+            preshared_keys,
+            settings,
+        )
+    })
+}
+
+// Peers which are kept are updated in place rather than re-added, so their
+// sessions survive. Keys and the listen port are left alone, they only
+// change together with the Pod.
+fn reconcile_wireguard_state(
+    executor: &mut impl Executor,
+    desired: &WireguardConfigStatus,
+    interface: &WireguardInterface,
+    preshared_keys: &PresharedKeys,
+    settings: &Settings,
+) -> anyhow::Result<Vec<String>> {
+    let interface_name = settings.interface_name.as_str();
+    let mut changes = vec![];
+
+    let mut link = executor
+        .link(interface_name)?
+        .with_context(|| format!("interface {} does not exist", interface_name))?;
+
+    info!("reconciling the wireguard interface address");
+    let address = Address {
+        address: IpAddr::V4(
+            desired
+                .tunnel_address
+                .context("WireguardConfig has no tunnel_address")?,
+        ),
+        prefix: desired
+            .tunnel_address_prefix
+            .context("WireguardConfig has no tunnel_address_prefix")?,
+    };
+    let addresses = executor.addresses(link.index)?;
+    if !addresses.contains(&address) {
+        executor.add_address(link.index, address)?;
+        changes.push(format!(
+            "added address {}/{}",
+            address.address, address.prefix
+        ));
+    }
+    for stale in addresses
+        .into_iter()
+        .filter(|stale| stale.address.is_ipv4() && *stale != address)
+    {
+        ignore_not_found(executor.del_address(link.index, stale))?;
+        changes.push(format!(
+            "removed address {}/{}",
+            stale.address, stale.prefix
+        ));
+    }
+
+    if let Some(mtu) =
+        tunnel_mtu(interface, &desired.peers, settings).filter(|mtu| link.mtu != Some(*mtu))
+    {
+        executor.set_link_mtu(link.index, mtu)?;
+        link.mtu = Some(mtu);
+        changes.push(format!("set mtu {}", mtu));
+    }
+
+    if !link.up {
+        executor.set_link_up(link.index)?;
+        changes.push(format!("brought interface {} up", interface_name));
+    }
+
+    info!("reconciling the wireguard device");
+    let device = executor.get_device(interface_name)?;
+    let mut update = DeviceUpdate::default();
+
+    if device.fwmark != settings.fwmark {
+        update.fwmark = Some(settings.fwmark);
+        changes.push(format!("set fwmark {}", settings.fwmark));
+    }
+
+    let mut live_peers: HashMap<PublicKey, Peer> = device
+        .peers
+        .into_iter()
+        .map(|peer| (peer.public_key, peer))
+        .collect();
+    for peer in &desired.peers {
+        let mut desired_peer = peer_update(peer, preshared_keys)?;
+        match live_peers.remove(&desired_peer.public_key) {
+            Some(live_peer)
+                if live_peer.endpoint == desired_peer.endpoint
+                    && Some(live_peer.persistent_keepalive)
+                        == desired_peer.persistent_keepalive
+                    && live_peer.preshared_key == desired_peer.preshared_key
+                    && live_peer.allowed_ips.iter().collect::<HashSet<_>>()
+                        == desired_peer.allowed_ips.iter().collect::<HashSet<_>>() => {}
+            Some(live_peer) => {
+                if live_peer.preshared_key.is_some() && desired_peer.preshared_key.is_none() {
+                    desired_peer.preshared_key = Some(PresharedKey::from([0; KEY_LEN]));
+                }
+                changes.push(format!("updated peer {}", peer.public_key));
+                update.peers.push(desired_peer);
+            }
+            None => {
+                changes.push(format!("added peer {}", peer.public_key));
+                update.peers.push(desired_peer);
+            }
+        }
+    }
+    for public_key in live_peers.into_keys() {
+        changes.push(format!("removed peer {}", public_key));
+        update.peers.push(PeerUpdate::remove(public_key));
+    }
+
+    if update.fwmark.is_some() || !update.peers.is_empty() {
+        executor.set_device(interface_name, &update)?;
+    }
+
+    let routing_mode = interface.routing_mode;
+    let advmss = advmss(interface.mss_clamping, link.mtu);
+    let (desired_routes, desired_rules) =
+        routing(routing_mode, link.index, advmss, &desired.peers, settings)?;
+
+    info!("reconciling routing rules");
+    let rules = executor.rules()?;
+    for rule in desired_rules.iter().filter(|rule| !rules.contains(rule)) {
+        executor.add_rule(rule)?;
+        changes.push(format!("added rule {:?}", rule));
+    }
+    for rule in rules
+        .iter()
+        .filter(|rule| is_own_rule(rule, settings) && !desired_rules.contains(rule))
+    {
+        ignore_not_found(executor.del_rule(rule))?;
+        changes.push(format!("removed rule {:?}", rule));
+    }
+    let has_suppress_rule = rules.contains(&suppress_rule());
+    if routing_mode == WireguardRoutingMode::Full && !has_suppress_rule {
+        match executor.add_rule(&suppress_rule()) {
+            Ok(()) | Err(NetlinkError::Exists) => {}
+            Err(err) => return Err(err.into()),
+        }
+        changes.push("added suppress_prefixlength rule".to_string());
+    } else if routing_mode != WireguardRoutingMode::Full
+        && has_suppress_rule
+        && !suppress_rule_is_shared(&rules, settings)
+    {
+        ignore_not_found(executor.del_rule(&suppress_rule()))?;
+        changes.push("removed suppress_prefixlength rule".to_string());
+    }
+
+    info!("reconciling the custom routing table");
+    let routes: Vec<Route> = executor
+        .routes()?
+        .into_iter()
+        .filter(|route| route.table == settings.routing_table)
+        .collect();
+    for route in desired_routes
+        .iter()
+        .filter(|route| !routes.contains(route))
+    {
+        executor.add_route(route)?;
+        changes.push(format!("added {}", route_name(route)));
+    }
+    for route in routes
+        .iter()
+        .filter(|route| !desired_routes.contains(route))
+    {
+        ignore_not_found(executor.del_route(route))?;
+        changes.push(format!("removed {}", route_name(route)));
+    }
+
+    Ok(changes)
+}
+
+pub async fn verify_wireguard_for_pod(
+    name: &str,
+    namespace: &str,
+    netns: &str,
+    settings: &Settings,
+) -> anyhow::Result<Vec<String>> {
+    let kube_client = kube_client(settings).await?;
+
+    info!("finding WireguardConfig for Pod {}", name);
+    let wireguard_configs: Api<WireguardConfig> = Api::namespaced(kube_client, namespace);
+    let wireguard_config = match wireguard_configs.get(name).await {
+        Ok(wireguard_config) => wireguard_config,
+        Err(KubeError::Api(api_err)) if api_err.code == 404 => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+
+    let (tunnel_address, tunnel_address_prefix, listen_port) = getnet(&wireguard_config)?;
+    let interface = wireguard_config.spec.interface;
+    let status = wireguard_config
+        .status
+        .context("WireguardConfig has no status")?;
+    let mtu = tunnel_mtu(&interface, &status.peers, settings);
+
+    let expected = ConfiguredInterface {
+        name: settings.interface_name.clone(),
+        address: tunnel_address,
+        prefix: tunnel_address_prefix,
+        mtu: mtu.map(|mtu| mtu as usize),
+        mac: None,
+        routes: vec![],
+        dns: vec![],
+        tunnel: Tunnel::Wireguard(WireguardTunnel {
+            listen_port,
+            public_key: status.public_key,
+            peers: status.peers,
+            routing_mode: Some(interface.routing_mode),
+            mss_clamping: interface.mss_clamping,
+        }),
+    };
+
+    verify_wireguard_interface(netns, &expected, settings)
+}
+
+pub fn verify_wireguard_interface(
+    netns: &str,
+    expected: &ConfiguredInterface,
+    settings: &Settings,
+) -> anyhow::Result<Vec<String>> {
+    let container_netns_file = File::open(netns)?;
+    run_in_netns(&container_netns_file, || {
+        inspect_wireguard_state(&mut SystemExecutor::open()?, expected, settings)
+    })
+}
+
+fn inspect_wireguard_state(
+    executor: &mut impl Executor,
+    expected: &ConfiguredInterface,
+    settings: &Settings,
+) -> anyhow::Result<Vec<String>> {
+    let Tunnel::Wireguard(tunnel) = &expected.tunnel else {
+        return Err(anyhow!(
+            "interface {} was not configured by the wireguard driver",
+            expected.name
+        ));
+    };
+    let interface_name = settings.interface_name.as_str();
+    let mut drift = vec![];
+
+    info!("inspecting the wireguard interface");
+    let link = match executor.link(interface_name)? {
+        Some(link) => link,
+        None => {
+            drift.push(format!("interface {} does not exist", interface_name));
+            return Ok(drift);
+        }
+    };
+    if !link.up {
+        drift.push(format!("interface {} is not up", interface_name));
+    }
+    let expected_mtu = expected.mtu.and_then(|mtu| u32::try_from(mtu).ok());
+    if expected_mtu.is_some() && link.mtu != expected_mtu {
+        drift.push(format!(
+            "interface {} mtu is {} instead of {}",
+            interface_name,
+            link.mtu.map_or("(none)".to_string(), |mtu| mtu.to_string()),
+            expected_mtu.unwrap_or_default()
+        ));
+    }
+
+    info!("inspecting the wireguard interface address");
+    let address = Address {
+        address: IpAddr::V4(expected.address),
+        prefix: expected.prefix,
+    };
+    if !executor.addresses(link.index)?.contains(&address) {
+        drift.push(format!(
+            "address {}/{} is not assigned",
+            expected.address, expected.prefix
+        ));
+    }
+
+    info!("inspecting the wireguard device");
+    let device = executor.get_device(interface_name)?;
+
+    let public_key = device.public_key.map(|public_key| public_key.to_string());
+    if tunnel.public_key != public_key {
+        drift.push("private key does not match the configured key".to_string());
+    }
+
+    if device.listen_port != tunnel.listen_port {
+        drift.push(format!(
+            "listen port is {} instead of {}",
+            device.listen_port, tunnel.listen_port
+        ));
+    }
+
+    if device.fwmark != settings.fwmark {
+        drift.push(format!(
+            "fwmark is {} instead of {}",
+            device.fwmark, settings.fwmark
+        ));
+    }
+
+    let mut live_peers: HashMap<String, Peer> = device
+        .peers
+        .into_iter()
+        .map(|peer| (peer.public_key.to_string(), peer))
+        .collect();
+
+    for peer in &tunnel.peers {
+        match live_peers.remove(&peer.public_key) {
+            Some(Peer {
+                endpoint,
+                allowed_ips,
+                persistent_keepalive,
+                preshared_key,
+                ..
+            }) => {
+                if endpoint != Some(peer_endpoint(peer)) {
+                    drift.push(format!(
+                        "peer {} endpoint is {} instead of {}",
+                        peer.public_key,
+                        endpoint.map_or("(none)".to_string(), |endpoint| endpoint.to_string()),
+                        peer.endpoint()
+                    ));
+                }
+                let expected_allowed_ips = peer
+                    .allowed_ips
+                    .iter()
+                    .map(|allowed_ip| parse_allowed_ip(allowed_ip))
+                    .collect::<anyhow::Result<HashSet<_>>>()?;
+                if allowed_ips.into_iter().collect::<HashSet<_>>() != expected_allowed_ips {
+                    drift.push(format!("peer {} allowed ips do not match", peer.public_key));
+                }
+                let expected_keepalive = persistent_keepalive_interval(peer)?;
+                if persistent_keepalive != expected_keepalive {
+                    drift.push(format!(
+                        "peer {} persistent keepalive is {} instead of {}",
+                        peer.public_key, persistent_keepalive, expected_keepalive
+                    ));
+                }
+                // the cached configuration only has the Secret, not the key
+                match (&peer.preshared_key, preshared_key) {
+                    (Some(_), None) => drift.push(format!(
+                        "peer {} has no preshared key configured",
+                        peer.public_key
+                    )),
+                    (None, Some(_)) => drift.push(format!(
+                        "peer {} has an unexpected preshared key",
+                        peer.public_key
+                    )),
+                    _ => {}
+                }
+            }
+            None => drift.push(format!("peer {} is not configured", peer.public_key)),
+        }
+    }
+    for public_key in live_peers.keys() {
+        drift.push(format!("unexpected peer {} is configured", public_key));
+    }
+
+    let routing_mode = tunnel.routing_mode.unwrap_or_default();
+    let advmss = advmss(tunnel.mss_clamping, expected_mtu.or(link.mtu));
+    let (expected_routes, expected_rules) =
+        routing(routing_mode, link.index, advmss, &tunnel.peers, settings)?;
+
+    info!("inspecting routing rules");
+    let rules = executor.rules()?;
+    for rule in expected_rules.iter().filter(|rule| !rules.contains(rule)) {
+        drift.push(format!("{} is missing", rule_name(rule)));
+    }
+    if routing_mode == WireguardRoutingMode::Full && !rules.contains(&suppress_rule()) {
+        drift.push("suppress_prefixlength rule is missing".to_string());
+    }
+
+    info!("inspecting the custom routing table");
+    let routes = executor.routes()?;
+    for route in expected_routes
+        .iter()
+        .filter(|route| !routes.contains(route))
+    {
+        drift.push(format!("{} is missing", route_name(route)));
+    }
+
+    Ok(drift)
+}
+
+pub fn wireguard_stats(netns: &str, settings: &Settings) -> anyhow::Result<TunnelStats> {
+    let container_netns_file = File::open(netns)?;
+    let device = run_in_netns(&container_netns_file, || {
+        WireguardControl::open(&settings.interface_name)?.get_device(&settings.interface_name)
+    })?;
+
+    let peers: Vec<PeerStats> = device
+        .peers
+        .into_iter()
+        .map(|peer| PeerStats {
+            peer: peer.public_key.to_string(),
+            endpoint: peer.endpoint,
+            last_handshake: peer.last_handshake,
+            rx_bytes: peer.rx_bytes,
+            tx_bytes: peer.tx_bytes,
+        })
+        .collect();
+
+    Ok(TunnelStats {
+        interface: device.name,
+        rx_bytes: peers.iter().map(|peer| peer.rx_bytes).sum(),
+        tx_bytes: peers.iter().map(|peer| peer.tx_bytes).sum(),
+        peers,
+    })
+}
+
+fn peer_update(
+    peer: &WireguardPeerConfig,
+    preshared_keys: &PresharedKeys,
+) -> anyhow::Result<PeerUpdate> {
+    let public_key: PublicKey = peer
+        .public_key
+        .parse()
+        .with_context(|| format!("invalid public key for peer {}", peer.public_key))?;
+    let allowed_ips = peer
+        .allowed_ips
+        .iter()
+        .map(|allowed_ip| parse_allowed_ip(allowed_ip))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let preshared_key = match &peer.preshared_key {
+        Some(secret_ref) => Some(
+            preshared_keys
+                .get(secret_ref)
+                .cloned()
+                .with_context(|| format!("missing preshared key {}", secret_ref))?,
+        ),
+        None => None,
+    };
+
+    // everything is set, so that an update also takes back what was dropped
+    // from the peer, down to an empty list of allowed ips
+    Ok(PeerUpdate {
+        preshared_key,
+        endpoint: Some(peer_endpoint(peer)),
+        persistent_keepalive: Some(persistent_keepalive_interval(peer)?),
+        replace_allowed_ips: true,
+        allowed_ips,
+        ..PeerUpdate::new(public_key)
+    })
+}
+
+fn peer_endpoint(peer: &WireguardPeerConfig) -> SocketAddr {
+    SocketAddr::V4(SocketAddrV4::new(
+        peer.endpoint_address,
+        peer.endpoint_port.unwrap_or(DEFAULT_WIREGUARD_LISTEN_PORT),
+    ))
+}
+
+// unset means no keepalives, which is what WireGuard defaults to
+fn persistent_keepalive_interval(peer: &WireguardPeerConfig) -> anyhow::Result<u16> {
+    let persistent_keepalive = peer.persistent_keepalive.unwrap_or_default();
+    u16::try_from(persistent_keepalive).map_err(|_| {
+        anyhow!(
+            "invalid persistent_keepalive {} for peer {}",
+            persistent_keepalive,
+            peer.public_key
+        )
+    })
+}
+
+// allowed ips are CIDRs, a bare address stands for the host itself
+fn parse_allowed_ip(allowed_ip: &str) -> anyhow::Result<Address> {
+    let (address, prefix) = match allowed_ip.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (allowed_ip, None),
+    };
+    let address: IpAddr = address
+        .parse()
+        .with_context(|| format!("invalid allowed ip {}", allowed_ip))?;
+    let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix
+            .parse()
+            .ok()
+            .filter(|prefix| *prefix <= max_prefix)
+            .with_context(|| format!("invalid allowed ip prefix {}", allowed_ip))?,
+        None => max_prefix,
+    };
+    // the kernel drops host bits, compare against what it will report back
+    let address = match address {
+        IpAddr::V4(address) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(address.to_bits() & mask))
+        }
+        IpAddr::V6(address) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(address.to_bits() & mask))
+        }
+    };
+    Ok(Address { address, prefix })
+}
+
+fn endpoint_addresses(peers: &[WireguardPeerConfig]) -> Vec<Ipv4Addr> {
+    let mut endpoint_addresses: Vec<Ipv4Addr> = vec![];
+    for peer in peers {
+        if !endpoint_addresses.contains(&peer.endpoint_address) {
+            endpoint_addresses.push(peer.endpoint_address);
+        }
+    }
+    endpoint_addresses
+}
+
+// When the address the attachment was created for is known, the status is
+// only cleared if it still refers to that address, so a replacement Pod with
+// the same name keeps its own.
+async fn clear_pod_address(
+    kube_client: &KubeClient,
+    namespace: &str,
+    name: &str,
+    pod_address: Option<Ipv4Addr>,
+) -> anyhow::Result<()> {
+    let wireguard_configs: Api<WireguardConfig> = Api::namespaced(kube_client.clone(), namespace);
+    if let Some(pod_address) = pod_address {
+        let current_pod_address = match wireguard_configs.get(name).await {
+            Ok(wireguard_config) => wireguard_config
+                .status
+                .and_then(|status| status.pod_address),
+            Err(KubeError::Api(api_err)) if api_err.code == 404 => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        if current_pod_address != Some(pod_address) {
+            info!("pod_address for {} was already replaced, skipping", name);
+            return Ok(());
+        }
+    }
+
+    let patch = json!({
+        "status": {
+            "pod_address": null,
+            "mode": null,
+        }
+    });
+
+    match wireguard_configs
+        .patch_status(name, &PatchParams::default(), &Patch::Merge(&patch))
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(KubeError::Api(api_err)) if api_err.code == 404 => Ok(()),
+        Err(err) => Err(err.into()),
+    }
 }
 
 async fn get_wg_config(
@@ -226,85 +1208,856 @@ async fn get_wg_config(
     namespace: &str,
     name: &str,
     pod_ip: &Ipv4Addr,
+    readiness_timeout: Duration,
 ) -> anyhow::Result<Option<WireguardConfig>> {
-    let wireguard_configs: Api<WireguardConfig> = Api::namespaced(kube_client.clone(), &namespace);
-    let wireguard_config = 'wait_for_readiness: loop {
-        let mut wireguard_config = match wireguard_configs.get(&name).await {
-            Ok(wireguard_config) => wireguard_config,
+    let wireguard_configs: Api<WireguardConfig> = Api::namespaced(kube_client.clone(), namespace);
+    let deadline = Instant::now() + readiness_timeout;
+    let mut backoff = INITIAL_READINESS_BACKOFF;
+    let mut pod_address_reported = false;
+
+    loop {
+        let wireguard_config = match wireguard_configs.get(name).await {
+            Ok(wireguard_config) => Some(wireguard_config),
             Err(KubeError::Api(api_err)) if api_err.code == 404 => return Ok(None),
-            Err(err) => return Err(err.into()),
+            Err(err) => {
+                info!("failed to get WireguardConfig {}: {}", name, err);
+                None
+            }
         };
 
-        let status = wireguard_config.status.get_or_insert_default();
-        status.pod_address = Some(*pod_ip);
+        if let Some(wireguard_config) = wireguard_config {
+            if !pod_address_reported {
+                let patch = json!({
+                    "status": {
+                        "pod_address": pod_ip,
+                    }
+                });
+                match wireguard_configs
+                    .patch_status(name, &PatchParams::default(), &Patch::Merge(&patch))
+                    .await
+                {
+                    Ok(_) => pod_address_reported = true,
+                    Err(err) => info!("failed to report pod_address for {}: {}", name, err),
+                }
+            }
 
-        match wireguard_configs
-            .patch_status(
-                name,
-                &PatchParams::default(),
-                &Patch::Merge(&wireguard_config),
-            )
-            .await
-        {
-            Ok(_) => {}
-            Err(_) => continue 'wait_for_readiness,
+            if let WireguardConfig {
+                status:
+                    Some(WireguardConfigStatus {
+                        interface_ready,
+                        ref peers,
+                        ..
+                    }),
+                ..
+            } = wireguard_config
+                && interface_ready
+                && !peers.is_empty()
+                && pod_address_reported
+            {
+                return Ok(Some(wireguard_config));
+            }
         }
 
-        if let WireguardConfig {
-            status:
-                Some(WireguardConfigStatus {
-                    interface_ready,
-                    ref peers,
-                    ..
-                }),
-            ..
-        } = wireguard_config
-        {
-            if interface_ready && peers.len() > 0 {
-                break wireguard_config;
+        if Instant::now() + backoff > deadline {
+            return Err(NotReady {
+                kind: "WireguardConfig",
+                name: name.to_string(),
+                timeout: readiness_timeout,
             }
+            .into());
         }
-    };
 
-    Ok(Some(wireguard_config))
+        info!(
+            "WireguardConfig {} not ready, retrying in {:?}",
+            name, backoff
+        );
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_READINESS_BACKOFF);
+    }
 }
 
+// The Secret is read by the name in the status rather than looked up by its
+// label, so the plugin only needs get on the Secrets it's granted.
 async fn get_privkey(
     kube_client: &KubeClient,
-    name: &str,
+    wireguard_config: &WireguardConfig,
     namespace: &str,
-) -> anyhow::Result<String> {
-    let secrets: Api<Secret> = Api::namespaced(kube_client.clone(), namespace);
-    let private_key_secret_label_selector = format!("{}={}", SECRET_LABEL, name);
-    let private_key_secret = match secrets
-        .list(&ListParams::default().labels(&private_key_secret_label_selector))
-        .await?
-    {
-        object_list if object_list.items.len() == 1 => object_list.items[0].clone(),
-        ObjectList { items, .. } => return Err(anyhow!("expected 1 secret found {}", items.len())),
-    };
+) -> anyhow::Result<PrivateKey> {
+    let secret_ref = wireguard_config
+        .status
+        .as_ref()
+        .and_then(|status| status.private_key.as_ref())
+        .context("WireguardConfig has no private_key")?;
+    let secret_namespace = secret_ref.namespace.as_deref().unwrap_or(namespace);
+    let secrets: Api<Secret> = Api::namespaced(kube_client.clone(), secret_namespace);
+    let private_key_secret = secrets
+        .get(&secret_ref.name)
+        .await
+        .with_context(|| format!("failed to get private key secret {}", secret_ref))?;
 
     let data = private_key_secret.data.context("missing secret data")?;
-    Ok(String::from_utf8_lossy(&data.get("private_key").context("missing private_key")?.0).into())
+    let private_key = data.get("private_key").context("missing private_key")?;
+    let private_key = str::from_utf8(&private_key.0).context("private_key is not utf-8")?;
+    Ok(private_key.parse()?)
 }
 
-fn getnet(wireguard_config: &WireguardConfig) -> (Ipv4Addr, u8, u16) {
-    let tunnel_address = wireguard_config
-        .status
-        .as_ref()
-        .unwrap()
-        .tunnel_address
-        .unwrap();
-    let prefix = wireguard_config
+async fn get_preshared_keys(
+    kube_client: &KubeClient,
+    namespace: &str,
+    peers: &[WireguardPeerConfig],
+) -> anyhow::Result<PresharedKeys> {
+    let mut preshared_keys = PresharedKeys::new();
+    for secret_ref in peers.iter().filter_map(|peer| peer.preshared_key.as_ref()) {
+        if preshared_keys.contains_key(secret_ref) {
+            continue;
+        }
+        let secret_namespace = secret_ref.namespace.as_deref().unwrap_or(namespace);
+        let secrets: Api<Secret> = Api::namespaced(kube_client.clone(), secret_namespace);
+        let secret = secrets
+            .get(&secret_ref.name)
+            .await
+            .with_context(|| format!("failed to get preshared key secret {}", secret_ref))?;
+
+        let data = secret.data.context("missing secret data")?;
+        let preshared_key = data
+            .get("preshared_key")
+            .with_context(|| format!("missing preshared_key in secret {}", secret_ref))?;
+        let preshared_key = str::from_utf8(&preshared_key.0)
+            .with_context(|| format!("preshared_key in secret {} is not utf-8", secret_ref))?;
+        let preshared_key = preshared_key
+            .parse()
+            .with_context(|| format!("invalid preshared_key in secret {}", secret_ref))?;
+        preshared_keys.insert(secret_ref.clone(), preshared_key);
+    }
+    Ok(preshared_keys)
+}
+
+fn getnet(wireguard_config: &WireguardConfig) -> anyhow::Result<(Ipv4Addr, u8, u16)> {
+    let status = wireguard_config
         .status
         .as_ref()
-        .unwrap()
-        .tunnel_address_prefix
-        .unwrap();
+        .context("WireguardConfig has no status")?;
     let listen_port = wireguard_config
         .spec
         .interface
         .listen_port
         .unwrap_or_default();
-    (tunnel_address, prefix, listen_port)
+    match (status.tunnel_address, status.tunnel_address_prefix) {
+        (Some(tunnel_address), Some(prefix)) => Ok((tunnel_address, prefix, listen_port)),
+        _ => Err(anyhow!("WireguardConfig has no tunnel address assigned")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::fake::RecordingExecutor;
+
+    const NETNS: &str = "/var/run/netns/test";
+    const LISTEN_PORT: u16 = 51820;
+
+    fn private_key() -> PrivateKey {
+        PrivateKey::from([1; 32])
+    }
+
+    fn peer_public_key(seed: u8) -> String {
+        PrivateKey::from([seed; 32]).public_key().to_string()
+    }
+
+    fn peer(seed: u8, endpoint_address: [u8; 4], allowed_ips: &[&str]) -> WireguardPeerConfig {
+        WireguardPeerConfig {
+            public_key: peer_public_key(seed),
+            endpoint_address: Ipv4Addr::from(endpoint_address),
+            endpoint_port: Some(LISTEN_PORT),
+            tunnel_address: None,
+            tunnel_address_prefix: None,
+            allowed_ips: allowed_ips.iter().map(|ip| ip.to_string()).collect(),
+            persistent_keepalive: Some(25),
+            preshared_key: None,
+        }
+    }
+
+    fn preshared_key_ref() -> ObjectReference {
+        ObjectReference {
+            name: "a-b-psk".to_string(),
+            namespace: Some("default".to_string()),
+        }
+    }
+
+    fn preshared_keys() -> PresharedKeys {
+        PresharedKeys::from([(preshared_key_ref(), PresharedKey::from([9; 32]))])
+    }
+
+    fn apply(
+        executor: &mut RecordingExecutor,
+        peers: &[WireguardPeerConfig],
+    ) -> anyhow::Result<(Link, WireguardMode)> {
+        apply_with(
+            executor,
+            peers,
+            &preshared_keys(),
+            &interface(WireguardRoutingMode::Full),
+            &Settings::default(),
+        )
+    }
+
+    fn apply_with(
+        executor: &mut RecordingExecutor,
+        peers: &[WireguardPeerConfig],
+        preshared_keys: &PresharedKeys,
+        interface: &WireguardInterface,
+        settings: &Settings,
+    ) -> anyhow::Result<(Link, WireguardMode)> {
+        apply_wireguard_state(
+            executor,
+            NETNS,
+            &Ipv4Addr::new(10, 0, 0, 1),
+            24,
+            &private_key(),
+            LISTEN_PORT,
+            peers,
+            preshared_keys,
+            interface,
+            settings,
+        )
+    }
+
+    fn interface(routing_mode: WireguardRoutingMode) -> WireguardInterface {
+        WireguardInterface {
+            routing_mode,
+            ..WireguardInterface::default()
+        }
+    }
+
+    fn status(peers: Vec<WireguardPeerConfig>) -> WireguardConfigStatus {
+        WireguardConfigStatus {
+            tunnel_address: Some(Ipv4Addr::new(10, 0, 0, 1)),
+            tunnel_address_prefix: Some(24),
+            peers,
+            ..WireguardConfigStatus::default()
+        }
+    }
+
+    fn expected(peers: Vec<WireguardPeerConfig>) -> ConfiguredInterface {
+        expected_with(peers, &interface(WireguardRoutingMode::Full))
+    }
+
+    fn expected_with(
+        peers: Vec<WireguardPeerConfig>,
+        interface: &WireguardInterface,
+    ) -> ConfiguredInterface {
+        ConfiguredInterface {
+            name: Settings::default().interface_name,
+            address: Ipv4Addr::new(10, 0, 0, 1),
+            prefix: 24,
+            mtu: interface.mtu.map(|mtu| mtu as usize),
+            mac: None,
+            routes: vec![],
+            dns: vec![],
+            tunnel: Tunnel::Wireguard(WireguardTunnel {
+                listen_port: LISTEN_PORT,
+                public_key: Some(private_key().public_key().to_string()),
+                peers,
+                routing_mode: Some(interface.routing_mode),
+                mss_clamping: interface.mss_clamping,
+            }),
+        }
+    }
+
+    fn applied_operations(peer_key: &str) -> Vec<String> {
+        vec![
+            "add_link wg0 Wireguard".to_string(),
+            "add_address 2 10.0.0.1/24".to_string(),
+            format!(
+                "set_device wg0 private_key listen_port=51820 fwmark=921481285 replace_peers \
+                 peer={} endpoint=192.168.1.2:51820 persistent_keepalive=25 replace_allowed_ips allowed_ips=10.0.0.2/32",
+                peer_key
+            ),
+            "set_link_up 2".to_string(),
+            "add_route default dev 2 table 129518285".to_string(),
+            "add_rule priority 1 to 192.168.1.2/32 fwmark 921481285 table 254".to_string(),
+            "add_rule priority 2 to 192.168.1.2/32 table 129518285".to_string(),
+            "add_rule priority 3 table 254 suppress_prefixlength 0".to_string(),
+        ]
+    }
+
+    #[test]
+    fn apply_configures_kernel_device() {
+        let mut executor = RecordingExecutor::default();
+        let peers = [peer(2, [192, 168, 1, 2], &["10.0.0.2"])];
+
+        let (link, mode) = apply(&mut executor, &peers).unwrap();
+
+        assert_eq!(link.index, 2);
+        assert_eq!(mode, WireguardMode::Kernel);
+        assert_eq!(
+            executor.take_operations(),
+            applied_operations(&peer_public_key(2))
+        );
+    }
+
+    #[test]
+    fn apply_falls_back_to_userspace() {
+        let mut executor = RecordingExecutor::default();
+        executor.without_wireguard_module = true;
+        let peers = [peer(2, [192, 168, 1, 2], &["10.0.0.2"])];
+        let settings = Settings {
+            userspace_fallback: true,
+            ..Settings::default()
+        };
+
+        let (_, mode) = apply_with(
+            &mut executor,
+            &peers,
+            &preshared_keys(),
+            &interface(WireguardRoutingMode::Full),
+            &settings,
+        )
+        .unwrap();
+
+        assert_eq!(mode, WireguardMode::Userspace);
+        let mut operations = applied_operations(&peer_public_key(2));
+        operations.insert(1, "spawn_userspace_device wg0".to_string());
+        assert_eq!(executor.take_operations(), operations);
+    }
+
+    #[test]
+    fn apply_needs_the_module_unless_falling_back_is_allowed() {
+        let mut executor = RecordingExecutor::default();
+        executor.without_wireguard_module = true;
+        let peers = [peer(2, [192, 168, 1, 2], &["10.0.0.2"])];
+
+        assert!(apply(&mut executor, &peers).is_err());
+        assert!(
+            !executor
+                .take_operations()
+                .iter()
+                .any(|operation| operation.starts_with("spawn_userspace_device"))
+        );
+    }
+
+    #[test]
+    fn apply_split_routes_only_allowed_ips() {
+        let mut executor = RecordingExecutor::default();
+        let peers = [peer(2, [192, 168, 1, 2], &["10.0.0.2", "10.1.0.0/16"])];
+
+        apply_with(
+            &mut executor,
+            &peers,
+            &preshared_keys(),
+            &interface(WireguardRoutingMode::Split),
+            &Settings::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            executor.take_operations()[4..],
+            [
+                "add_route 10.0.0.2/32 dev 2 table 129518285",
+                "add_route 10.1.0.0/16 dev 2 table 129518285",
+                "add_rule priority 1 to 192.168.1.2/32 fwmark 921481285 table 254",
+                "add_rule priority 2 table 129518285",
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_without_routing_only_configures_device() {
+        let mut executor = RecordingExecutor::default();
+        let peers = [peer(2, [192, 168, 1, 2], &["10.0.0.2"])];
+
+        apply_with(
+            &mut executor,
+            &peers,
+            &preshared_keys(),
+            &interface(WireguardRoutingMode::None),
+            &Settings::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            executor.take_operations(),
+            applied_operations(&peer_public_key(2))[..4]
+        );
+        assert!(executor.routes.is_empty());
+        assert!(executor.rules.is_empty());
+    }
+
+    #[test]
+    fn apply_derives_mtu_and_clamps_mss() {
+        let mut executor = RecordingExecutor::default();
+        let peers = [peer(2, [192, 168, 1, 2], &["10.0.0.2"])];
+        let mut interface = interface(WireguardRoutingMode::Full);
+        interface.mss_clamping = true;
+        let settings = Settings {
+            underlay_mtu: Some(1500),
+            ..Settings::default()
+        };
+
+        let (link, _) = apply_with(
+            &mut executor,
+            &peers,
+            &preshared_keys(),
+            &interface,
+            &settings,
+        )
+        .unwrap();
+
+        assert_eq!(link.mtu, Some(1440));
+        let operations = executor.take_operations();
+        assert_eq!(operations[2], "set_link_mtu 2 1440");
+        assert_eq!(
+            operations[5],
+            "add_route default dev 2 table 129518285 advmss 1400"
+        );
+    }
+
+    #[test]
+    fn apply_prefers_configured_mtu() {
+        let mut executor = RecordingExecutor::default();
+        let peers = [peer(2, [192, 168, 1, 2], &["10.0.0.2"])];
+        let mut interface = interface(WireguardRoutingMode::Full);
+        interface.mtu = Some(1280);
+        let settings = Settings {
+            underlay_mtu: Some(1500),
+            ..Settings::default()
+        };
+
+        apply_with(
+            &mut executor,
+            &peers,
+            &preshared_keys(),
+            &interface,
+            &settings,
+        )
+        .unwrap();
+
+        assert_eq!(executor.take_operations()[2], "set_link_mtu 2 1280");
+    }
+
+    #[test]
+    fn reconcile_applies_changed_mtu() {
+        let mut executor = RecordingExecutor::default();
+        let peers = vec![peer(2, [192, 168, 1, 2], &["10.0.0.2"])];
+        apply(&mut executor, &peers).unwrap();
+        executor.take_operations();
+        let mut interface = interface(WireguardRoutingMode::Full);
+        interface.mtu = Some(1380);
+        interface.mss_clamping = true;
+
+        let changes = reconcile_wireguard_state(
+            &mut executor,
+            &status(peers.clone()),
+            &interface,
+            &preshared_keys(),
+            &Settings::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            changes,
+            [
+                "set mtu 1380",
+                "added default route to table 129518285",
+                "removed default route to table 129518285",
+            ]
+        );
+        assert_eq!(
+            executor.routes,
+            [Route {
+                destination: None,
+                interface: Some(2),
+                table: 129518285,
+                advmss: Some(1340),
+            }]
+        );
+
+        let mut expected = expected_with(peers, &interface);
+        assert!(
+            inspect_wireguard_state(&mut executor, &expected, &Settings::default())
+                .unwrap()
+                .is_empty()
+        );
+        expected.mtu = Some(1420);
+        let drift =
+            inspect_wireguard_state(&mut executor, &expected, &Settings::default()).unwrap();
+        assert_eq!(drift[0], "interface wg0 mtu is 1380 instead of 1420");
+    }
+
+    #[test]
+    fn reconcile_switches_routing_mode() {
+        let mut executor = RecordingExecutor::default();
+        let peers = vec![peer(2, [192, 168, 1, 2], &["10.0.0.2"])];
+        apply(&mut executor, &peers).unwrap();
+        executor.take_operations();
+
+        let changes = reconcile_wireguard_state(
+            &mut executor,
+            &status(peers.clone()),
+            &interface(WireguardRoutingMode::Split),
+            &preshared_keys(),
+            &Settings::default(),
+        )
+        .unwrap();
+
+        assert_eq!(changes.len(), 5, "{:?}", changes);
+        let mut operations = executor.take_operations();
+        operations.sort();
+        assert_eq!(
+            operations,
+            [
+                "add_route 10.0.0.2/32 dev 2 table 129518285",
+                "add_rule priority 2 table 129518285",
+                "del_route default dev 2 table 129518285",
+                "del_rule priority 2 to 192.168.1.2/32 table 129518285",
+                "del_rule priority 3 table 254 suppress_prefixlength 0",
+            ]
+        );
+
+        let expected = expected_with(peers.clone(), &interface(WireguardRoutingMode::Split));
+        assert!(
+            inspect_wireguard_state(&mut executor, &expected, &Settings::default())
+                .unwrap()
+                .is_empty()
+        );
+
+        reconcile_wireguard_state(
+            &mut executor,
+            &status(peers),
+            &interface(WireguardRoutingMode::None),
+            &preshared_keys(),
+            &Settings::default(),
+        )
+        .unwrap();
+        assert!(executor.routes.is_empty());
+        assert!(executor.rules.is_empty());
+    }
+
+    #[test]
+    fn apply_rolls_back_when_device_configuration_fails() {
+        let mut executor = RecordingExecutor::default();
+        executor.fail("set_device");
+        let peers = [peer(2, [192, 168, 1, 2], &["10.0.0.2"])];
+
+        let err = apply(&mut executor, &peers).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "failed to configure the wireguard device with listen-port 51820 and 1 peers"
+        );
+        let operations = executor.take_operations();
+        assert_eq!(operations[3..], ["del_address 2 10.0.0.1/24", "del_link 2"]);
+        assert!(executor.links.is_empty());
+    }
+
+    #[test]
+    fn apply_rolls_back_in_reverse_order() {
+        let mut executor = RecordingExecutor::default();
+        executor.fail("add_rule priority 3");
+        let peers = [peer(2, [192, 168, 1, 2], &["10.0.0.2"])];
+
+        let err = apply(&mut executor, &peers).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "failed to add the suppress_prefixlength rule"
+        );
+        let operations = executor.take_operations();
+        assert_eq!(
+            operations[8..],
+            [
+                "del_rule priority 2 to 192.168.1.2/32 table 129518285",
+                "del_rule priority 1 to 192.168.1.2/32 fwmark 921481285 table 254",
+                "del_route default dev 2 table 129518285",
+                "del_address 2 10.0.0.1/24",
+                "del_link 2",
+            ]
+        );
+        assert!(executor.rules.is_empty());
+        assert!(executor.routes.is_empty());
+    }
+
+    #[test]
+    fn apply_rejects_invalid_peer_without_changes() {
+        let mut executor = RecordingExecutor::default();
+        let mut invalid = peer(2, [192, 168, 1, 2], &["10.0.0.2"]);
+        invalid.public_key = "not a key".to_string();
+
+        let err = apply(&mut executor, &[invalid]).unwrap_err();
+
+        assert_eq!(err.to_string(), "failed to parse the peer configuration");
+        assert!(executor.take_operations().is_empty());
+    }
+
+    #[test]
+    fn reconcile_after_apply_changes_nothing() {
+        let mut executor = RecordingExecutor::default();
+        let peers = vec![peer(2, [192, 168, 1, 2], &["10.0.0.2"])];
+        apply(&mut executor, &peers).unwrap();
+        executor.take_operations();
+
+        let changes = reconcile_wireguard_state(
+            &mut executor,
+            &status(peers),
+            &interface(WireguardRoutingMode::Full),
+            &preshared_keys(),
+            &Settings::default(),
+        )
+        .unwrap();
+
+        assert!(changes.is_empty(), "{:?}", changes);
+        assert!(executor.take_operations().is_empty());
+    }
+
+    #[test]
+    fn reconcile_replaces_changed_peers() {
+        let mut executor = RecordingExecutor::default();
+        apply(&mut executor, &[peer(2, [192, 168, 1, 2], &["10.0.0.2"])]).unwrap();
+        executor.take_operations();
+
+        let peers = vec![peer(3, [192, 168, 1, 3], &["10.0.0.3/32"])];
+        let changes = reconcile_wireguard_state(
+            &mut executor,
+            &status(peers),
+            &interface(WireguardRoutingMode::Full),
+            &preshared_keys(),
+            &Settings::default(),
+        )
+        .unwrap();
+
+        assert_eq!(changes.len(), 6, "{:?}", changes);
+        assert_eq!(
+            executor.take_operations(),
+            [
+                format!(
+                    "set_device wg0 peer={} endpoint=192.168.1.3:51820 persistent_keepalive=25 replace_allowed_ips \
+                     allowed_ips=10.0.0.3/32 peer={} remove",
+                    peer_public_key(3),
+                    peer_public_key(2)
+                ),
+                "add_rule priority 1 to 192.168.1.3/32 fwmark 921481285 table 254".to_string(),
+                "add_rule priority 2 to 192.168.1.3/32 table 129518285".to_string(),
+                "del_rule priority 1 to 192.168.1.2/32 fwmark 921481285 table 254".to_string(),
+                "del_rule priority 2 to 192.168.1.2/32 table 129518285".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn inspect_reports_drift() {
+        let mut executor = RecordingExecutor::default();
+        let peers = vec![peer(2, [192, 168, 1, 2], &["10.0.0.2"])];
+        apply(&mut executor, &peers).unwrap();
+        let settings = Settings::default();
+
+        assert!(
+            inspect_wireguard_state(&mut executor, &expected(peers.clone()), &settings)
+                .unwrap()
+                .is_empty()
+        );
+
+        executor
+            .rules
+            .retain(|rule| rule.priority != SUPPRESS_PRIORITY);
+        let mut peers = peers;
+        peers[0].endpoint_port = Some(51821);
+        assert_eq!(
+            inspect_wireguard_state(&mut executor, &expected(peers), &settings).unwrap(),
+            [
+                format!(
+                    "peer {} endpoint is 192.168.1.2:51820 instead of 192.168.1.2:51821",
+                    peer_public_key(2)
+                ),
+                "suppress_prefixlength rule is missing".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn remove_cleans_up_own_state() {
+        let mut executor = RecordingExecutor::default();
+        apply(&mut executor, &[peer(2, [192, 168, 1, 2], &["10.0.0.2"])]).unwrap();
+        executor.take_operations();
+
+        remove_wireguard_state(&mut executor, &Settings::default()).unwrap();
+
+        assert_eq!(
+            executor.take_operations(),
+            [
+                "del_rule priority 1 to 192.168.1.2/32 fwmark 921481285 table 254",
+                "del_rule priority 2 to 192.168.1.2/32 table 129518285",
+                "del_rule priority 3 table 254 suppress_prefixlength 0",
+                "del_link 2",
+            ]
+        );
+        assert!(executor.links.is_empty());
+        assert!(executor.rules.is_empty());
+        assert!(executor.routes.is_empty());
+    }
+
+    #[test]
+    fn preshared_keys_are_applied_and_removed() {
+        let mut executor = RecordingExecutor::default();
+        let mut peers = vec![peer(2, [192, 168, 1, 2], &["10.0.0.2"])];
+        peers[0].preshared_key = Some(preshared_key_ref());
+
+        apply(&mut executor, &peers).unwrap();
+
+        let operations = executor.take_operations();
+        assert_eq!(
+            operations[2],
+            format!(
+                "set_device wg0 private_key listen_port=51820 fwmark=921481285 replace_peers \
+                 peer={} preshared_key endpoint=192.168.1.2:51820 persistent_keepalive=25 replace_allowed_ips \
+                 allowed_ips=10.0.0.2/32",
+                peer_public_key(2)
+            )
+        );
+        assert_eq!(
+            executor.devices["wg0"].peers[0].preshared_key,
+            Some(PresharedKey::from([9; 32]))
+        );
+        let settings = Settings::default();
+        assert!(
+            inspect_wireguard_state(&mut executor, &expected(peers.clone()), &settings)
+                .unwrap()
+                .is_empty()
+        );
+
+        peers[0].preshared_key = None;
+        assert_eq!(
+            inspect_wireguard_state(&mut executor, &expected(peers.clone()), &settings).unwrap(),
+            [format!(
+                "peer {} has an unexpected preshared key",
+                peer_public_key(2)
+            )]
+        );
+        let changes = reconcile_wireguard_state(
+            &mut executor,
+            &status(peers),
+            &interface(WireguardRoutingMode::Full),
+            &preshared_keys(),
+            &settings,
+        )
+        .unwrap();
+
+        assert_eq!(changes, [format!("updated peer {}", peer_public_key(2))]);
+        assert_eq!(
+            executor.take_operations(),
+            [format!(
+                "set_device wg0 peer={} preshared_key=none endpoint=192.168.1.2:51820 persistent_keepalive=25 \
+                 replace_allowed_ips allowed_ips=10.0.0.2/32",
+                peer_public_key(2)
+            )]
+        );
+        assert_eq!(executor.devices["wg0"].peers[0].preshared_key, None);
+    }
+
+    #[test]
+    fn apply_fails_without_preshared_key_secret() {
+        let mut executor = RecordingExecutor::default();
+        let mut peers = vec![peer(2, [192, 168, 1, 2], &["10.0.0.2"])];
+        peers[0].preshared_key = Some(preshared_key_ref());
+
+        let err = apply_with(
+            &mut executor,
+            &peers,
+            &PresharedKeys::new(),
+            &interface(WireguardRoutingMode::Full),
+            &Settings::default(),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            format!("{:#}", err),
+            "failed to parse the peer configuration: missing preshared key default/a-b-psk"
+        );
+        assert!(executor.take_operations().is_empty());
+    }
+
+    #[test]
+    fn reconcile_applies_peer_options() {
+        let mut executor = RecordingExecutor::default();
+        let mut peers = vec![peer(2, [192, 168, 1, 2], &["10.0.0.2"])];
+        apply(&mut executor, &peers).unwrap();
+        executor.take_operations();
+
+        peers[0].persistent_keepalive = None;
+        peers[0].endpoint_port = None;
+        peers[0].allowed_ips = vec![];
+        let settings = Settings::default();
+        assert_eq!(
+            inspect_wireguard_state(&mut executor, &expected(peers.clone()), &settings).unwrap(),
+            [
+                format!("peer {} allowed ips do not match", peer_public_key(2)),
+                format!(
+                    "peer {} persistent keepalive is 25 instead of 0",
+                    peer_public_key(2)
+                ),
+            ]
+        );
+        reconcile_wireguard_state(
+            &mut executor,
+            &status(peers),
+            &interface(WireguardRoutingMode::Full),
+            &preshared_keys(),
+            &settings,
+        )
+        .unwrap();
+
+        assert_eq!(
+            executor.take_operations(),
+            [format!(
+                "set_device wg0 peer={} endpoint=192.168.1.2:51820 persistent_keepalive=0 \
+                 replace_allowed_ips",
+                peer_public_key(2)
+            )]
+        );
+        let live_peer = &executor.devices["wg0"].peers[0];
+        assert_eq!(live_peer.persistent_keepalive, 0);
+        assert!(live_peer.allowed_ips.is_empty());
+    }
+
+    #[test]
+    fn apply_rejects_out_of_range_keepalive() {
+        let mut executor = RecordingExecutor::default();
+        let mut peers = vec![peer(2, [192, 168, 1, 2], &["10.0.0.2"])];
+        peers[0].persistent_keepalive = Some(-1);
+
+        let err = apply(&mut executor, &peers).unwrap_err();
+
+        assert_eq!(
+            format!("{:#}", err),
+            format!(
+                "failed to parse the peer configuration: invalid persistent_keepalive -1 for \
+                 peer {}",
+                peer_public_key(2)
+            )
+        );
+        assert!(executor.take_operations().is_empty());
+    }
+
+    #[test]
+    fn getnet_requires_an_assigned_tunnel_address() {
+        let mut wireguard_config = WireguardConfig::default();
+        assert_eq!(
+            getnet(&wireguard_config).unwrap_err().to_string(),
+            "WireguardConfig has no status"
+        );
+
+        wireguard_config.status = Some(WireguardConfigStatus::default());
+        assert_eq!(
+            getnet(&wireguard_config).unwrap_err().to_string(),
+            "WireguardConfig has no tunnel address assigned"
+        );
+
+        let status = wireguard_config.status.as_mut().unwrap();
+        status.tunnel_address = Some(Ipv4Addr::new(10, 0, 0, 1));
+        status.tunnel_address_prefix = Some(24);
+        wireguard_config.spec.interface.listen_port = Some(LISTEN_PORT);
+        assert_eq!(
+            getnet(&wireguard_config).unwrap(),
+            (Ipv4Addr::new(10, 0, 0, 1), 24, LISTEN_PORT)
+        );
+    }
 }
@@ -0,0 +1,155 @@
+use crate::controllers::errors::{Error, Result};
+
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::rbac::v1::{PolicyRule, Role, RoleBinding, RoleRef, Subject};
+use kube::{Api, Client, Error as KubeError, api::PostParams};
+use tracing::*;
+
+// The CNI plugin's service account, see config/rbac.
+const CNI_SERVICE_ACCOUNT: &str = "podtunnel-cni";
+const CNI_SERVICE_ACCOUNT_NAMESPACE: &str = "kube-system";
+// The Role and RoleBinding the operator manages in every namespace with
+// tunnels.
+const CNI_ROLE: &str = "podtunnel-cni";
+const MANAGED_BY_LABEL: (&str, &str) = ("app.kubernetes.io/managed-by", "podtunnel-operator");
+
+// Lets the CNI plugin get the given Secret, and no other, by adding it to the
+// resourceNames of the namespace's Role. Names are never removed: the Secrets
+// are named after the WireguardConfigs which own them, a config recreated
+// under the same name gets the same Secret name.
+pub async fn grant_secret(client: &Client, namespace: &str, secret_name: &str) -> Result<()> {
+    let roles: Api<Role> = Api::namespaced(client.clone(), namespace);
+    let role = roles.get_opt(CNI_ROLE).await.map_err(Error::KubeError)?;
+    let exists = role.is_some();
+    // a concurrent grant makes the create or replace conflict, the
+    // reconcile is then retried against the updated Role
+    if let Some(role) = with_secret(role, namespace, secret_name) {
+        info!("granting the CNI plugin access to secret {}", secret_name);
+        if exists {
+            roles
+                .replace(CNI_ROLE, &PostParams::default(), &role)
+                .await
+                .map_err(Error::KubeError)?;
+        } else {
+            roles
+                .create(&PostParams::default(), &role)
+                .await
+                .map_err(Error::KubeError)?;
+        }
+    }
+
+    let role_bindings: Api<RoleBinding> = Api::namespaced(client.clone(), namespace);
+    match role_bindings
+        .create(&PostParams::default(), &role_binding(namespace))
+        .await
+    {
+        Ok(_) => info!("bound the CNI plugin to role {}/{}", namespace, CNI_ROLE),
+        Err(KubeError::Api(api_err)) if api_err.code == 409 => {}
+        Err(err) => return Err(Error::KubeError(err)),
+    }
+    Ok(())
+}
+
+// The Role with the Secret added, or None when it's already granted.
+fn with_secret(role: Option<Role>, namespace: &str, secret_name: &str) -> Option<Role> {
+    let mut role = role.unwrap_or_else(|| {
+        let mut role = Role::default();
+        role.metadata.name = Some(CNI_ROLE.to_string());
+        role.metadata.namespace = Some(namespace.to_string());
+        role.metadata.labels = Some(managed_by());
+        role
+    });
+
+    let rules = role.rules.get_or_insert_with(Vec::new);
+    let rule = match rules.iter().position(is_secret_rule) {
+        Some(index) => &mut rules[index],
+        None => {
+            rules.push(PolicyRule {
+                api_groups: Some(vec!["".to_string()]),
+                resources: Some(vec!["secrets".to_string()]),
+                verbs: vec!["get".to_string()],
+                resource_names: Some(vec![]),
+                ..Default::default()
+            });
+            rules.last_mut()?
+        }
+    };
+
+    let resource_names = rule.resource_names.get_or_insert_with(Vec::new);
+    if resource_names.iter().any(|name| name == secret_name) {
+        return None;
+    }
+    resource_names.push(secret_name.to_string());
+    Some(role)
+}
+
+fn is_secret_rule(rule: &PolicyRule) -> bool {
+    rule.resources.as_deref() == Some(&["secrets".to_string()])
+        && rule.verbs == ["get"]
+        && rule.resource_names.is_some()
+}
+
+fn role_binding(namespace: &str) -> RoleBinding {
+    let mut role_binding = RoleBinding {
+        role_ref: RoleRef {
+            api_group: "rbac.authorization.k8s.io".to_string(),
+            kind: "Role".to_string(),
+            name: CNI_ROLE.to_string(),
+        },
+        subjects: Some(vec![Subject {
+            kind: "ServiceAccount".to_string(),
+            name: CNI_SERVICE_ACCOUNT.to_string(),
+            namespace: Some(CNI_SERVICE_ACCOUNT_NAMESPACE.to_string()),
+            ..Default::default()
+        }]),
+        ..Default::default()
+    };
+    role_binding.metadata.name = Some(CNI_ROLE.to_string());
+    role_binding.metadata.namespace = Some(namespace.to_string());
+    role_binding.metadata.labels = Some(managed_by());
+    role_binding
+}
+
+fn managed_by() -> BTreeMap<String, String> {
+    let (key, value) = MANAGED_BY_LABEL;
+    BTreeMap::from([(key.to_string(), value.to_string())])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resource_names(role: &Role) -> Vec<String> {
+        let rules = role.rules.as_ref().unwrap();
+        assert_eq!(rules.len(), 1);
+        rules[0].resource_names.clone().unwrap()
+    }
+
+    #[test]
+    fn creates_a_role_for_the_first_secret() {
+        let role = with_secret(None, "default", "pod-a").unwrap();
+
+        assert_eq!(role.metadata.name.as_deref(), Some(CNI_ROLE));
+        assert_eq!(role.metadata.namespace.as_deref(), Some("default"));
+        let rule = &role.rules.as_ref().unwrap()[0];
+        assert_eq!(rule.api_groups.as_deref(), Some(&["".to_string()][..]));
+        assert_eq!(rule.verbs, ["get"]);
+        assert_eq!(resource_names(&role), ["pod-a"]);
+    }
+
+    #[test]
+    fn adds_secrets_to_the_existing_role() {
+        let role = with_secret(None, "default", "pod-a").unwrap();
+        let role = with_secret(Some(role), "default", "pod-a-pod-b-psk").unwrap();
+
+        assert_eq!(resource_names(&role), ["pod-a", "pod-a-pod-b-psk"]);
+    }
+
+    #[test]
+    fn leaves_granted_secrets_alone() {
+        let role = with_secret(None, "default", "pod-a").unwrap();
+
+        assert!(with_secret(Some(role), "default", "pod-a").is_none());
+    }
+}
@@ -4,6 +4,8 @@ use super::errors::{Error, Result};
 use api::wireguard::WireguardConfig;
 use reconciler::{Context, reconcile};
 
+pub(crate) use reconciler::assign_pool_address;
+
 use std::{sync::Arc, time::Duration};
 
 use futures::StreamExt;
@@ -27,11 +29,7 @@ pub async fn run() -> Result<(), std::io::Error> {
     Controller::new(wireguard_configs, watcher::Config::default().any_semantic())
         .with_config(Config::default())
         .shutdown_on_signal()
-        .run(
-            reconcile,
-            error_policy,
-            Arc::new(Context { client: client }),
-        )
+        .run(reconcile, error_policy, Arc::new(Context { client }))
         .for_each(|res| async move {
             match res {
                 Ok(o) => debug!("reconciled {:?}", o),
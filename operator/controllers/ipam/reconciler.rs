@@ -11,7 +11,7 @@ use std::{net::Ipv4Addr, sync::Arc};
 
 use k8s_openapi::serde_json::json;
 use kube::{
-    Api, Client, ResourceExt,
+    Api, Client, Resource, ResourceExt,
     api::{Patch, PatchParams},
     runtime::controller::Action,
 };
@@ -26,11 +26,11 @@ pub async fn reconcile(
     wireguard_config: Arc<WireguardConfig>,
     ctx: Arc<Context>,
 ) -> Result<Action> {
-    if let Some(WireguardConfigStatus { tunnel_address, .. }) = &wireguard_config.status {
-        if tunnel_address.is_some() {
-            debug!("address already assigned, skipping");
-            return Ok(Action::await_change());
-        }
+    if let Some(WireguardConfigStatus { tunnel_address, .. }) = &wireguard_config.status
+        && tunnel_address.is_some()
+    {
+        debug!("address already assigned, skipping");
+        return Ok(Action::await_change());
     }
 
     let name = wireguard_config.name_any();
@@ -54,6 +54,7 @@ pub async fn reconcile(
                 &ctx.client,
                 &pool.name,
                 &namespace,
+                &WireguardConfig::kind(&()),
                 ObjectReference {
                     name: name.clone(),
                     namespace: Some(namespace.clone()),
@@ -85,21 +86,22 @@ pub async fn reconcile(
     Ok(Action::await_change())
 }
 
-async fn assign_pool_address(
+pub(crate) async fn assign_pool_address(
     client: &Client,
     pool_name: &str,
     pool_namespace: &str,
+    config_kind: &str,
     config_ref: ObjectReference,
 ) -> Result<(Ipv4Addr, u8)> {
-    let address_pools: Api<WireguardAddressPool> = Api::namespaced(client.clone(), &pool_namespace);
+    let address_pools: Api<WireguardAddressPool> = Api::namespaced(client.clone(), pool_namespace);
 
     let mut address_pool = address_pools
-        .get(&pool_name)
+        .get(pool_name)
         .await
         .map_err(Error::KubeError)?;
 
     let (assigned_address, prefix) = address_pool
-        .assign_ipv4(config_ref)
+        .assign_ipv4(config_kind, config_ref)
         .await
         .map_err(Error::ControllerError)?;
 
@@ -114,3 +116,56 @@ async fn assign_pool_address(
 
     Ok((assigned_address, prefix))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use api::overlay::OverlayConfig;
+
+    fn config_ref(name: &str) -> ObjectReference {
+        ObjectReference {
+            name: name.to_string(),
+            namespace: Some("default".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn configs_of_different_kinds_get_different_addresses() {
+        let mut pool = WireguardAddressPool::new("pool", Default::default());
+
+        let wireguard = pool
+            .assign_ipv4(&WireguardConfig::kind(&()), config_ref("pod"))
+            .await
+            .unwrap();
+        let overlay = pool
+            .assign_ipv4(&OverlayConfig::kind(&()), config_ref("pod"))
+            .await
+            .unwrap();
+
+        assert_ne!(wireguard.0, overlay.0);
+        assert_eq!(
+            pool.assign_ipv4(&OverlayConfig::kind(&()), config_ref("pod"))
+                .await
+                .unwrap(),
+            overlay
+        );
+    }
+
+    #[tokio::test]
+    async fn wireguard_configs_keep_their_existing_leases() {
+        let mut pool = WireguardAddressPool::new("pool", Default::default());
+        pool.status
+            .get_or_insert_default()
+            .allocation
+            .insert("default/pod".to_string(), Ipv4Addr::new(10, 0, 100, 7));
+
+        let (address, prefix) = pool
+            .assign_ipv4(&WireguardConfig::kind(&()), config_ref("pod"))
+            .await
+            .unwrap();
+
+        assert_eq!(address, Ipv4Addr::new(10, 0, 100, 7));
+        assert_eq!(prefix, 24);
+    }
+}
@@ -27,11 +27,7 @@ pub async fn run() -> Result<(), std::io::Error> {
     Controller::new(wireguard_configs, watcher::Config::default().any_semantic())
         .with_config(Config::default())
         .shutdown_on_signal()
-        .run(
-            reconcile,
-            error_policy,
-            Arc::new(Context { client: client }),
-        )
+        .run(reconcile, error_policy, Arc::new(Context { client }))
         .for_each(|res| async move {
             match res {
                 Ok(o) => debug!("reconciled {:?}", o),
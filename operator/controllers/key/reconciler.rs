@@ -1,4 +1,5 @@
 use crate::controllers::{
+    access::grant_secret,
     errors::{Error, Result},
     labels::SECRET_LABEL,
 };
@@ -30,19 +31,24 @@ pub async fn reconcile(
     wireguard_config: Arc<WireguardConfig>,
     ctx: Arc<Context>,
 ) -> Result<Action> {
-    if let Some(WireguardConfigStatus { private_key, .. }) = &wireguard_config.status {
-        if private_key.is_some() {
-            debug!("already has a private_key, skipping");
-            return Ok(Action::await_change());
-        }
-    }
-
     let name = wireguard_config.name_any();
     let namespace = wireguard_config.namespace().unwrap_or_default();
 
+    // configs keyed before the plugin's access was granted per Secret get it
+    // here
+    if let Some(WireguardConfigStatus {
+        private_key: Some(secret_ref),
+        ..
+    }) = &wireguard_config.status
+    {
+        debug!("already has a private_key, skipping");
+        let secret_namespace = secret_ref.namespace.as_deref().unwrap_or(&namespace);
+        grant_secret(&ctx.client, secret_namespace, &secret_ref.name).await?;
+        return Ok(Action::await_change());
+    }
+
     info!("generating private key");
-    let (private_key, public_key) =
-        drivers::wireguard::key::generate().map_err(Error::ControllerError)?;
+    let (private_key, public_key) = drivers::wireguard::key::generate();
 
     let (secret_ref, _private_key, public_key) = generate_secret_ref(
         &ctx.client,
@@ -53,11 +59,12 @@ pub async fn reconcile(
         &public_key,
     )
     .await?;
+    grant_secret(&ctx.client, &namespace, &secret_ref.name).await?;
 
     let patch = json!({
         "status": {
             "private_key": secret_ref,
-            "public_key": public_key,
+            "public_key": public_key.to_string(),
         }
     });
 
@@ -75,14 +82,20 @@ async fn generate_secret_ref(
     name: &str,
     namespace: &str,
     uid: &str,
-    private_key: &str,
-    public_key: &str,
+    private_key: &PrivateKey,
+    public_key: &PublicKey,
 ) -> Result<(ObjectReference, PrivateKey, PublicKey)> {
-    let secrets: Api<Secret> = Api::namespaced(client.clone(), &namespace);
+    let secrets: Api<Secret> = Api::namespaced(client.clone(), namespace);
 
     let map = BTreeMap::from([
-        ("private_key".into(), ByteString(private_key.into())),
-        ("public_key".into(), ByteString(public_key.into())),
+        (
+            "private_key".into(),
+            ByteString(private_key.to_base64().as_bytes().to_vec()),
+        ),
+        (
+            "public_key".into(),
+            ByteString(public_key.to_string().into_bytes()),
+        ),
     ]);
 
     let mut secret = Secret::default();
@@ -105,7 +118,7 @@ async fn generate_secret_ref(
 
     info!("generating private_key secret");
     let (private_key, public_key) = match secrets.create(&PostParams::default(), &secret).await {
-        Ok(_secret) => (private_key.to_string(), public_key.to_string()),
+        Ok(_secret) => (private_key.clone(), *public_key),
         Err(KubeError::Api(api_err)) if api_err.code == 409 => {
             info!("private key secret for config {} already exists", &name);
             get_keys_from_secret(&secrets, name).await?
@@ -154,8 +167,26 @@ async fn get_keys_from_secret(
                 &name
             )))?;
 
-    let private_key = String::from_utf8_lossy(&private_key_bytestring.0);
-    let public_key = String::from_utf8_lossy(&public_key_bytestring.0);
+    let private_key = String::from_utf8_lossy(&private_key_bytestring.0)
+        .parse()
+        .map_err(|err| {
+            Error::ControllerError(anyhow::anyhow!(
+                "invalid private_key in secret {}/{}: {}",
+                &namespace,
+                &name,
+                err
+            ))
+        })?;
+    let public_key = String::from_utf8_lossy(&public_key_bytestring.0)
+        .parse()
+        .map_err(|err| {
+            Error::ControllerError(anyhow::anyhow!(
+                "invalid public_key in secret {}/{}: {}",
+                &namespace,
+                &name,
+                err
+            ))
+        })?;
 
-    Ok((private_key.into_owned(), public_key.into_owned()))
+    Ok((private_key, public_key))
 }
@@ -1,6 +1,8 @@
+pub mod access;
 pub mod errors;
 pub mod interface;
 pub mod ipam;
 pub mod key;
 pub mod labels;
+pub mod overlay;
 pub mod peer;
@@ -0,0 +1,130 @@
+use crate::controllers::{
+    errors::{Error, Result},
+    ipam::assign_pool_address,
+};
+use api::{
+    ObjectReference,
+    overlay::{OverlayConfig, OverlayConfigStatus, OverlayPeer, OverlayPeerConfig},
+    wireguard::{WireguardAddress, WireguardNetwork},
+};
+use k8s_openapi::serde_json::json;
+
+use std::sync::Arc;
+
+use kube::{
+    Api, Client, Resource, ResourceExt,
+    api::{Patch, PatchParams},
+    runtime::controller::Action,
+};
+use tracing::*;
+
+#[derive(Clone)]
+pub struct Context {
+    pub client: Client,
+}
+
+// OverlayConfigs have no keys, so addressing, readiness and peers are all
+// handled by this one controller.
+pub async fn reconcile(overlay_config: Arc<OverlayConfig>, ctx: Arc<Context>) -> Result<Action> {
+    let name = overlay_config.name_any();
+    let namespace = overlay_config.namespace().unwrap_or_default();
+    let client = &ctx.client;
+    let overlay_configs: Api<OverlayConfig> = Api::namespaced(client.clone(), &namespace);
+
+    let is_addressed = matches!(
+        &overlay_config.status,
+        Some(OverlayConfigStatus {
+            tunnel_address: Some(_),
+            tunnel_address_prefix: Some(_),
+            ..
+        })
+    );
+    // the interface is ready as soon as it's addressed, peers of other Pods
+    // only resolve once those are ready and running, which may in turn wait
+    // for this one.
+    if !is_addressed {
+        let (tunnel_address, tunnel_address_prefix) = match &overlay_config.spec.interface.address {
+            WireguardAddress::NetworkAddress(WireguardNetwork { address, prefix }) => {
+                info!("address configured manually");
+                (*address, *prefix)
+            }
+            WireguardAddress::PoolAddress(pool) => {
+                info!("assigning address from pool");
+                assign_pool_address(
+                    client,
+                    &pool.name,
+                    &namespace,
+                    &OverlayConfig::kind(&()),
+                    ObjectReference {
+                        name: name.clone(),
+                        namespace: Some(namespace.clone()),
+                    },
+                )
+                .await?
+            }
+        };
+        info!(
+            "address assigned: {}/{}",
+            &tunnel_address, tunnel_address_prefix
+        );
+
+        let patch = json!({
+            "status": {
+                "interface_ready": true,
+                "tunnel_address": tunnel_address,
+                "tunnel_address_prefix": tunnel_address_prefix,
+            }
+        });
+        overlay_configs
+            .patch_status(&name, &PatchParams::default(), &Patch::Merge(&patch))
+            .await
+            .map_err(Error::KubeError)?;
+    }
+
+    debug!("compiling peers");
+    let mut compiled_peers = vec![];
+    for peer in overlay_config.spec.peers.iter() {
+        let peer_config = match peer {
+            OverlayPeer::Config(config) => config.clone(),
+            OverlayPeer::Pod(object_ref) => {
+                get_peer_config(&overlay_configs, &object_ref.name).await?
+            }
+        };
+        compiled_peers.push(peer_config);
+    }
+
+    let patch = json!({
+        "status": {
+            "peers": compiled_peers,
+        }
+    });
+    overlay_configs
+        .patch_status(&name, &PatchParams::default(), &Patch::Merge(&patch))
+        .await
+        .map_err(Error::KubeError)?;
+
+    Ok(Action::await_change())
+}
+
+async fn get_peer_config(
+    overlay_configs: &Api<OverlayConfig>,
+    name: &str,
+) -> Result<OverlayPeerConfig> {
+    match overlay_configs.get(name).await {
+        Ok(OverlayConfig {
+            status:
+                Some(OverlayConfigStatus {
+                    interface_ready,
+                    pod_address: Some(pod_address),
+                    tunnel_address,
+                    ..
+                }),
+            ..
+        }) if interface_ready => Ok(OverlayPeerConfig {
+            endpoint_address: pod_address,
+            tunnel_address,
+        }),
+        Ok(_) => Err(Error::ControllerError(anyhow::anyhow!("peer not ready"))),
+        Err(err) => Err(Error::KubeError(err)),
+    }
+}
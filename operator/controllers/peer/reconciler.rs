@@ -1,19 +1,37 @@
-use crate::controllers::errors::{Error, Result};
-use api::wireguard::{
-    WireguardConfig, WireguardConfigSpec, WireguardConfigStatus, WireguardInterface, WireguardPeer,
-    WireguardPeerConfig,
+use crate::controllers::{
+    access::grant_secret,
+    errors::{Error, Result},
 };
-use k8s_openapi::serde_json::json;
+use api::{
+    ObjectReference,
+    wireguard::{
+        DEFAULT_PERSISTENT_KEEPALIVE, WireguardConfig, WireguardConfigSpec, WireguardConfigStatus,
+        WireguardInterface, WireguardPeer, WireguardPeerConfig,
+    },
+};
+use drivers::wireguard::key::PresharedKey;
 
-use std::sync::Arc;
+use std::{collections::BTreeMap, fmt::Write, sync::Arc};
 
+use anyhow::anyhow;
+use blake2::{Blake2s256, Digest};
+use k8s_openapi::{
+    ByteString, api::core::v1::Secret, apimachinery::pkg::apis::meta::v1::OwnerReference,
+    serde_json::json,
+};
 use kube::{
-    Api, Client, ResourceExt,
-    api::{Patch, PatchParams},
+    Api, Client, Error as KubeError, Resource, ResourceExt,
+    api::{Patch, PatchParams, PostParams},
     runtime::controller::Action,
 };
 use tracing::*;
 
+// Secret names are DNS subdomains
+const MAX_SECRET_NAME_LEN: usize = 253;
+const PRESHARED_KEY_SUFFIX: &str = "-psk";
+// hex digits of the hash which keeps truncated names unique
+const NAME_HASH_LEN: usize = 16;
+
 #[derive(Clone)]
 pub struct Context {
     pub client: Client,
@@ -33,12 +51,38 @@ pub async fn reconcile(
     let mut compiled_peers = vec![];
     for peer in specified_peers {
         let peer_config = match peer {
-            WireguardPeer::Config(config) => config,
-            WireguardPeer::Pod(object_ref) => {
-                &get_peer_config(&wireguard_configs, &object_ref.name).await?
+            WireguardPeer::Config(config) => {
+                // Secrets in other namespaces are left to the cluster admin
+                // to grant, configs must not hand out access beyond their own
+                if let Some(secret_ref) = &config.preshared_key
+                    && secret_ref
+                        .namespace
+                        .as_deref()
+                        .is_none_or(|secret_namespace| secret_namespace == namespace)
+                {
+                    grant_secret(client, &namespace, &secret_ref.name).await?;
+                }
+                config.clone()
+            }
+            WireguardPeer::Pod(pod_peer) => {
+                let peer_wireguard_config = wireguard_configs
+                    .get(&pod_peer.pod.name)
+                    .await
+                    .map_err(Error::KubeError)?;
+                let mut peer_config = get_peer_config(peer_wireguard_config.clone())?;
+                peer_config.persistent_keepalive = Some(
+                    pod_peer
+                        .persistent_keepalive
+                        .unwrap_or(DEFAULT_PERSISTENT_KEEPALIVE),
+                );
+                peer_config.preshared_key = Some(
+                    generate_preshared_key_ref(client, &wireguard_config, &peer_wireguard_config)
+                        .await?,
+                );
+                peer_config
             }
         };
-        compiled_peers.push(peer_config.clone());
+        compiled_peers.push(peer_config);
     }
 
     let patch = json!({
@@ -55,12 +99,9 @@ pub async fn reconcile(
     Ok(Action::await_change())
 }
 
-async fn get_peer_config(
-    wireguard_configs: &Api<WireguardConfig>,
-    name: &str,
-) -> Result<WireguardPeerConfig> {
-    match wireguard_configs.get(name).await {
-        Ok(WireguardConfig {
+fn get_peer_config(wireguard_config: WireguardConfig) -> Result<WireguardPeerConfig> {
+    match wireguard_config {
+        WireguardConfig {
             spec:
                 WireguardConfigSpec {
                     interface: WireguardInterface { listen_port, .. },
@@ -77,19 +118,155 @@ async fn get_peer_config(
                     ..
                 }),
             ..
-        }) if interface_ready => Ok(WireguardPeerConfig {
-            public_key: public_key,
+        } if interface_ready => Ok(WireguardPeerConfig {
+            public_key,
             endpoint_address: pod_address,
             endpoint_port: listen_port,
             tunnel_address: Some(tunnel_address),
-            tunnel_address_prefix: tunnel_address_prefix,
+            tunnel_address_prefix,
             allowed_ips: vec![
                 format!("{}/32", &tunnel_address),
                 format!("{}/32", &pod_address),
             ],
-            persistent_keepalive: Some(25),
+            persistent_keepalive: None,
+            preshared_key: None,
         }),
-        Ok(_) => Err(Error::ControllerError(anyhow::anyhow!("peer not ready"))),
-        Err(err) => Err(Error::KubeError(err)),
+        _ => Err(Error::ControllerError(anyhow::anyhow!("peer not ready"))),
+    }
+}
+
+// Both ends of a Pod to Pod tunnel need the same preshared key, so the Secret
+// is named after the pair and whichever side is reconciled first creates it.
+// It's owned by both WireguardConfigs and goes away with the last of them.
+async fn generate_preshared_key_ref(
+    client: &Client,
+    wireguard_config: &WireguardConfig,
+    peer_wireguard_config: &WireguardConfig,
+) -> Result<ObjectReference> {
+    let namespace = wireguard_config.namespace().unwrap_or_default();
+    let secrets: Api<Secret> = Api::namespaced(client.clone(), &namespace);
+
+    let name = preshared_key_secret_name(
+        &wireguard_config.name_any(),
+        &peer_wireguard_config.name_any(),
+    );
+
+    let owner_references = [wireguard_config, peer_wireguard_config]
+        .into_iter()
+        .map(|owner| {
+            let uid = owner.uid().ok_or_else(|| {
+                Error::ControllerError(anyhow!("WireguardConfig {} has no uid", owner.name_any()))
+            })?;
+            Ok(OwnerReference {
+                api_version: WireguardConfig::api_version(&()).into(),
+                kind: WireguardConfig::kind(&()).into(),
+                name: owner.name_any(),
+                uid,
+                ..Default::default()
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut secret = Secret::default();
+    secret.metadata.name = Some(name.clone());
+    secret.metadata.namespace = Some(namespace.clone());
+    secret.metadata.owner_references = Some(owner_references.clone());
+    secret.data = Some(BTreeMap::from([(
+        "preshared_key".into(),
+        ByteString(PresharedKey::generate().to_base64().as_bytes().to_vec()),
+    )]));
+
+    match secrets.create(&PostParams::default(), &secret).await {
+        Ok(_secret) => info!("generated preshared key secret {}", &name),
+        // a WireguardConfig recreated under the same name has a new uid, the
+        // strategic merge adds it to the owners of the existing Secret
+        Err(KubeError::Api(api_err)) if api_err.code == 409 => {
+            debug!("preshared key secret {} already exists", &name);
+            let patch = json!({
+                "metadata": {
+                    "ownerReferences": owner_references,
+                }
+            });
+            secrets
+                .patch(&name, &PatchParams::default(), &Patch::Strategic(&patch))
+                .await
+                .map_err(Error::KubeError)?;
+        }
+        Err(err) => return Err(Error::KubeError(err)),
+    }
+    grant_secret(client, &namespace, &name).await?;
+
+    Ok(ObjectReference {
+        name,
+        namespace: Some(namespace),
+    })
+}
+
+// The pair's names sorted, so both ends agree on it. Names which would be too
+// long for a Secret are truncated and made unique with a hash of the pair.
+fn preshared_key_secret_name(name: &str, peer_name: &str) -> String {
+    let mut names = [name, peer_name];
+    names.sort();
+    let name = format!("{}-{}{}", names[0], names[1], PRESHARED_KEY_SUFFIX);
+    if name.len() <= MAX_SECRET_NAME_LEN {
+        return name;
+    }
+
+    let mut hasher = Blake2s256::new();
+    hasher.update(names[0]);
+    hasher.update([0]);
+    hasher.update(names[1]);
+    let hash = hasher
+        .finalize()
+        .iter()
+        .fold(String::new(), |mut hash, byte| {
+            let _ = write!(hash, "{:02x}", byte);
+            hash
+        });
+
+    // names are ASCII, and a name has to end alphanumeric before the hash
+    let prefix_len = MAX_SECRET_NAME_LEN - NAME_HASH_LEN - PRESHARED_KEY_SUFFIX.len() - 1;
+    let prefix = name[..prefix_len].trim_end_matches(['-', '.']);
+    format!(
+        "{}-{}{}",
+        prefix,
+        &hash[..NAME_HASH_LEN],
+        PRESHARED_KEY_SUFFIX
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_preshared_key_secrets_after_the_sorted_pair() {
+        assert_eq!(preshared_key_secret_name("b", "a"), "a-b-psk");
+        assert_eq!(preshared_key_secret_name("a", "b"), "a-b-psk");
+    }
+
+    #[test]
+    fn truncates_long_preshared_key_secret_names() {
+        let long = "a".repeat(200);
+        let name = preshared_key_secret_name(&long, &format!("{}b", long));
+        assert_eq!(name.len(), MAX_SECRET_NAME_LEN);
+        assert!(name.starts_with(&long));
+        assert!(name.ends_with(PRESHARED_KEY_SUFFIX));
+        assert_eq!(
+            name,
+            preshared_key_secret_name(&format!("{}b", long), &long)
+        );
+
+        let other = preshared_key_secret_name(&long, &format!("{}c", long));
+        assert_ne!(name, other);
+    }
+
+    #[test]
+    fn truncated_preshared_key_secret_names_end_alphanumeric_before_the_hash() {
+        let name = format!("{}-{}", "a".repeat(231), "b".repeat(30));
+        let name = preshared_key_secret_name(&name, "c");
+        let (prefix, _) = name.rsplit_once('-').unwrap();
+        let (prefix, _) = prefix.rsplit_once('-').unwrap();
+        assert_eq!(prefix, "a".repeat(231));
     }
 }
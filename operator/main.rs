@@ -1,9 +1,11 @@
 mod controllers;
 
-use controllers::{interface, ipam, key, peer};
+use controllers::{interface, ipam, key, overlay, peer};
 
 use tracing::*;
 
+// Every driver's controllers run, each only acts on the resources of its
+// driver, so the driver is chosen by the config a Pod has.
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
     tracing_subscriber::fmt().init();
@@ -20,17 +22,22 @@ async fn main() -> Result<(), std::io::Error> {
     info!("starting interface controller");
     let interface_controller = interface::run();
 
+    info!("starting overlay controller");
+    let overlay_controller = overlay::run();
+
     let results = tokio::join!(
         ipam_controller,
         key_controller,
         peer_controller,
-        interface_controller
+        interface_controller,
+        overlay_controller
     );
 
     results.0?;
     results.1?;
     results.2?;
     results.3?;
+    results.4?;
 
     Ok(())
 }
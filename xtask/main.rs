@@ -1,4 +1,7 @@
-use api::wireguard::{WireguardAddressPool, WireguardConfig};
+use api::{
+    overlay::OverlayConfig,
+    wireguard::{WireguardAddressPool, WireguardConfig},
+};
 
 use std::{env, fs};
 
@@ -14,6 +17,8 @@ const CNI_TYPE: &str = "podtunnel-cni";
 const CNI_VERSION: &str = "0.3.1";
 const CRD_KUSTOMIZE_DIR: &str = "config/crds";
 
+// variants map directly onto the subcommand names
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Parser)]
 enum Command {
     GenerateCniConfig,
@@ -35,15 +40,19 @@ async fn main() -> anyhow::Result<()> {
             let cni_config_json = serde_json::to_string_pretty(
                 &(json!({
                     "name":  env::var("CNI_NAME").unwrap_or(CNI_NAME.to_string()),
-                    "cniVersion":  env::var("CNI_TYPE").unwrap_or(CNI_TYPE.to_string()),
-                    "type": env::var("CNI_VERSION").unwrap_or(CNI_VERSION.to_string()),
+                    "cniVersion":  env::var("CNI_VERSION").unwrap_or(CNI_VERSION.to_string()),
+                    "type": env::var("CNI_TYPE").unwrap_or(CNI_TYPE.to_string()),
                 })),
             )?;
             println!("{}", cni_config_json);
             Ok(())
         }
         GenerateCrds => {
-            let crds = vec![WireguardAddressPool::crd(), WireguardConfig::crd()];
+            let crds = vec![
+                OverlayConfig::crd(),
+                WireguardAddressPool::crd(),
+                WireguardConfig::crd(),
+            ];
             let crd_file_names = create_crd_files(crds)?;
             create_kustomization_file(crd_file_names)?;
             Ok(())
@@ -84,7 +93,7 @@ resources:"#
         new_file_contents.push_str("\n- ");
         new_file_contents.push_str(&file_name);
     }
-    new_file_contents.push_str("\n");
+    new_file_contents.push('\n');
 
     if current_file_contents == new_file_contents {
         println!("{} already up-to-date", &kustomize_file_path);